@@ -0,0 +1,366 @@
+use std::io::Read;
+use std::time::Instant;
+
+use cascade::{Cascade, LevelCounts};
+use optimizer::{sample_csv_records, sample_records, Optimizer, Plan};
+use plan_cache::{PlanCache, PlanKey};
+use raw_filter::RawFilter;
+use stats::ScanStats;
+use stream::ChunkReader;
+use utils::error::Result;
+
+/// Settings for re-calibrating a cascade while a stream is scanned.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AdaptiveConfig {
+    /// Number of records sampled from a chunk when (re-)calibrating.
+    pub sample_size: usize,
+    /// Relative deviation between the observed and the estimated cost per byte of a
+    /// chunk above which the cascade is re-optimized.
+    pub threshold: f64,
+    /// Re-calibrate every this many chunks even if the cost looks right, to pick up
+    /// filters outside the current cascade that have become selective. The observed
+    /// cost only covers the filters in the cascade, so without resampling a cascade
+    /// that is still as cheap as estimated (e.g. an empty one) is never replaced.
+    pub resample_interval: Option<usize>,
+}
+
+impl Default for AdaptiveConfig {
+    fn default() -> Self {
+        AdaptiveConfig {
+            sample_size: 1000,
+            threshold: 0.5,
+            resample_interval: Some(8),
+        }
+    }
+}
+
+/// AdaptiveScanner scans a stream like `StreamScanner`, but chooses the cascade
+/// itself and keeps it up to date as the data changes.
+///
+/// The cascade is calibrated on a sample of the first chunk. While scanning, the
+/// bytes passing each cascade level are counted and priced with the optimizer's
+/// cost model; when the observed cost of a chunk deviates from the plan's estimate
+/// by more than `threshold`, or every `resample_interval` chunks, the next chunk is
+/// sampled and the optimizer is run again. Cascades are only swapped between
+/// chunks, so every record is filtered by exactly one cascade.
+pub struct AdaptiveScanner<R> {
+    chunks: ChunkReader<R>,
+    candidates: Vec<RawFilter>,
+    optimizer: Optimizer,
+    config: AdaptiveConfig,
+    plan: Option<Plan>,
+    recalibrations: usize,
+    stats: ScanStats,
+    cache: Option<(PlanCache, PlanKey)>,
+}
+
+impl<R: Read> AdaptiveScanner<R> {
+    /// Creates a scanner choosing cascades from `candidates`, which must all be
+    /// necessary conditions of the query.
+    pub fn new(
+        chunks: ChunkReader<R>,
+        candidates: Vec<RawFilter>,
+        optimizer: Optimizer,
+        config: AdaptiveConfig,
+    ) -> Self {
+        AdaptiveScanner {
+            chunks,
+            candidates,
+            optimizer,
+            config,
+            plan: None,
+            recalibrations: 0,
+            stats: ScanStats::new(),
+            cache: None,
+        }
+    }
+
+    /// Takes the initial plan from `cache` under `key`, and stores it there if the
+    /// cached one does not fit. Later recalibrations bypass the cache.
+    pub fn with_plan_cache(mut self, cache: PlanCache, key: PlanKey) -> Self {
+        self.cache = Some((cache, key));
+        self
+    }
+
+    /// Returns the plan cache set with `with_plan_cache`, e.g. to save it.
+    pub fn take_plan_cache(&mut self) -> Option<PlanCache> {
+        self.cache.take().map(|(cache, _)| cache)
+    }
+
+    /// Returns the plan currently in use.
+    pub fn plan(&self) -> Option<&Plan> {
+        self.plan.as_ref()
+    }
+
+    /// Returns how often the optimizer was run after the initial calibration.
+    pub fn recalibrations(&self) -> usize {
+        self.recalibrations
+    }
+
+    /// Returns the statistics of all searches so far.
+    pub fn stats(&self) -> &ScanStats {
+        &self.stats
+    }
+
+    /// Reads the stream to its end and calls `verifier` with the stream offset and
+    /// bytes of every record passing the current cascade. Returns the number of
+    /// records for which `verifier` returned true.
+    pub fn search<F: FnMut(u64, &[u8]) -> bool>(&mut self, mut verifier: F) -> Result<u64> {
+        let delimiter = self.chunks.delimiter();
+        let csv = self.chunks.csv_format();
+        let mut passed = 0;
+        let mut stale = self.plan.is_none();
+        let mut chunks_since_calibration = 0;
+        loop {
+            let read_start = Instant::now();
+            let chunk = self.chunks.next_chunk()?;
+            self.stats.read_time += read_start.elapsed();
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            if stale {
+                let calibration_start = Instant::now();
+                let max = self.config.sample_size;
+                let sample = match csv {
+                    Some(ref format) => sample_csv_records(format, chunk.data, max),
+                    None => sample_records(chunk.data, delimiter, max),
+                };
+                let plan = match self.cache {
+                    Some((ref mut cache, ref key)) if self.plan.is_none() => {
+                        cache.plan(key, &self.optimizer, &sample, &self.candidates)?
+                    }
+                    _ => self.optimizer.optimize(&sample, &self.candidates)?,
+                };
+                if self.plan.is_some() {
+                    self.recalibrations += 1;
+                }
+                self.plan = Some(plan);
+                chunks_since_calibration = 0;
+                self.stats.calibration_time += calibration_start.elapsed();
+            }
+            let plan = self.plan.as_ref().unwrap();
+
+            let base = chunk.offset;
+            let mut counts = LevelCounts::new(plan.cascade.len());
+            passed += self.stats.scan(
+                &plan.cascade,
+                chunk.data,
+                delimiter,
+                csv.as_ref(),
+                &mut counts,
+                |offset, record| verifier(base + offset as u64, record),
+            );
+
+            chunks_since_calibration += 1;
+            let observed = observed_cost(&self.optimizer, &plan.cascade, &counts, chunk.data.len());
+            stale = deviation(observed, plan.estimated_cost) > self.config.threshold
+                || self
+                    .config
+                    .resample_interval
+                    .is_some_and(|n| chunks_since_calibration >= n);
+        }
+        Ok(passed)
+    }
+}
+
+/// Prices the per-level counts of a scanned chunk with the optimizer's cost model.
+fn observed_cost(
+    optimizer: &Optimizer,
+    cascade: &Cascade,
+    counts: &LevelCounts,
+    len: usize,
+) -> f64 {
+    let len = len.max(1) as f64;
+    let passed: Vec<f64> = counts.bytes.iter().map(|&b| b as f64 / len).collect();
+    optimizer.cost_model().filter_cascade_cost(cascade, &passed)
+}
+
+/// Returns the deviation of `observed` from `estimated` relative to `estimated`,
+/// which may be zero with a calibrated cost model.
+fn deviation(observed: f64, estimated: f64) -> f64 {
+    let difference = (observed - estimated).abs();
+    if difference == 0.0 {
+        0.0
+    } else if estimated > 0.0 {
+        difference / estimated
+    } else {
+        f64::INFINITY
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::time::Duration;
+
+    use super::*;
+    use cascade::for_each_record;
+    use formats::csv::CsvFormat;
+    use utils::memory::AllocationMode;
+
+    /// In the first half of the stream "rust" is rare and "#trending" is everywhere;
+    /// in the second half "#trending" is rare and "rust" is everywhere.
+    fn drifting_stream() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..4000 {
+            let (text, tag) = if i < 2000 {
+                (if i % 50 == 0 { "rust" } else { "golang" }, "#trending")
+            } else {
+                ("rust", if i % 50 == 0 { "#trending" } else { "#news" })
+            };
+            data.extend_from_slice(
+                format!("{{\"id\": {}, \"text\": \"{} {}\"}}\n", i, text, tag).as_bytes(),
+            );
+        }
+        data
+    }
+
+    fn scanner(data: &[u8], config: AdaptiveConfig) -> AdaptiveScanner<Cursor<&[u8]>> {
+        let chunks =
+            ChunkReader::with_chunk_size(Cursor::new(data), b'\n', 8192, AllocationMode::Default)
+                .unwrap();
+        let candidates = vec![
+            RawFilter::substring("rust"),
+            RawFilter::substring("#trending"),
+        ];
+        AdaptiveScanner::new(chunks, candidates, Optimizer::default(), config)
+    }
+
+    #[test]
+    fn test_recalibrates_on_drift_without_losing_records() {
+        let data = drifting_stream();
+        let mut expected = Vec::new();
+        for_each_record(&data, b'\n', |offset, record| {
+            let text = String::from_utf8_lossy(record);
+            if text.contains("rust") && text.contains("#trending") {
+                expected.push(offset as u64);
+            }
+        });
+
+        let mut scanner = scanner(&data, AdaptiveConfig::default());
+        let mut found = Vec::new();
+        let passed = scanner
+            .search(|offset, record| {
+                let text = String::from_utf8_lossy(record);
+                if text.contains("rust") && text.contains("#trending") {
+                    found.push(offset);
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap();
+
+        assert_eq!(expected, found);
+        assert_eq!(expected.len() as u64, passed);
+        assert!(scanner.recalibrations() >= 1);
+        assert!(!found.is_empty());
+        let stats = scanner.stats();
+        assert_eq!(4000, stats.records);
+        assert_eq!(data.len() as u64, stats.bytes_scanned);
+        assert_eq!(passed, stats.matches());
+        assert!(stats.calibration_time > Duration::default());
+        let plan = scanner.plan().unwrap();
+        assert_eq!(RawFilter::substring("#trending"), plan.cascade.filters()[0]);
+    }
+
+    #[test]
+    fn test_samples_csv_records() {
+        // every record mentions "rust", on the line after a quoted newline
+        let mut data = Vec::new();
+        for i in 0..100 {
+            data.extend_from_slice(format!("{},\"golang\nrust\"\n", i).as_bytes());
+        }
+        let chunks =
+            ChunkReader::new(Cursor::new(&data[..]), b'\n').with_csv_records(CsvFormat::csv());
+        let candidates = vec![RawFilter::substring("rust")];
+        let mut scanner = AdaptiveScanner::new(
+            chunks,
+            candidates,
+            Optimizer::default(),
+            AdaptiveConfig::default(),
+        );
+        assert_eq!(100, scanner.search(|_, _| true).unwrap());
+        let explain = &scanner.plan().unwrap().explain;
+        assert_eq!(100, explain.sample_records);
+        assert_eq!(1.0, explain.candidates[0].passed);
+    }
+
+    #[test]
+    fn test_plan_cache() {
+        let data = drifting_stream();
+        let key = PlanKey::new(&"text ~ /rust.*#trending/", "drifting");
+        let mut cache = PlanCache::new();
+        for _ in 0..2 {
+            let mut scanner =
+                scanner(&data, AdaptiveConfig::default()).with_plan_cache(cache, key.clone());
+            scanner.search(|_, _| true).unwrap();
+            cache = scanner.take_plan_cache().unwrap();
+            assert!(scanner.take_plan_cache().is_none());
+        }
+        assert_eq!((1, 1), (cache.hits(), cache.misses()));
+        // the cache holds the plan for the start of the stream, not the last one
+        let cached = &cache.get(&key).unwrap().cascade;
+        assert_eq!(RawFilter::substring("rust"), cached.filters()[0]);
+    }
+
+    #[test]
+    fn test_default_config_picks_up_filters_outside_the_cascade() {
+        // "rust" is in every record at first, so the initial cascade is empty and its
+        // observed cost always matches the estimate
+        let mut data = Vec::new();
+        for i in 0..4000 {
+            let text = if i < 1000 || i % 50 == 0 {
+                "rust"
+            } else {
+                "golang"
+            };
+            data.extend_from_slice(
+                format!("{{\"id\": {}, \"text\": \"{}\"}}\n", i, text).as_bytes(),
+            );
+        }
+        let chunks = ChunkReader::with_chunk_size(
+            Cursor::new(&data[..]),
+            b'\n',
+            8192,
+            AllocationMode::Default,
+        )
+        .unwrap();
+        let candidates = vec![RawFilter::substring("rust")];
+        let mut scanner = AdaptiveScanner::new(
+            chunks,
+            candidates,
+            Optimizer::default(),
+            AdaptiveConfig::default(),
+        );
+        let passed = scanner
+            .search(|_, record| String::from_utf8_lossy(record).contains("rust"))
+            .unwrap();
+        assert_eq!(1000 + 3000 / 50, passed);
+        assert!(scanner.recalibrations() >= 1);
+        let plan = scanner.plan().unwrap();
+        assert_eq!(&[RawFilter::substring("rust")], plan.cascade.filters());
+    }
+
+    #[test]
+    fn test_deviation() {
+        assert_eq!(0.5, deviation(3.0, 2.0));
+        assert_eq!(0.0, deviation(0.0, 0.0));
+        assert_eq!(f64::INFINITY, deviation(1.0, 0.0));
+    }
+
+    #[test]
+    fn test_resample_interval() {
+        let data = drifting_stream();
+        let config = AdaptiveConfig {
+            threshold: f64::INFINITY,
+            resample_interval: Some(1),
+            ..AdaptiveConfig::default()
+        };
+        let mut scanner = scanner(&data, config);
+        scanner.search(|_, _| true).unwrap();
+        let chunks = data.len().div_ceil(8192);
+        assert!(scanner.recalibrations() >= chunks - 2);
+    }
+}
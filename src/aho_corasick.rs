@@ -0,0 +1,225 @@
+//! An Aho-Corasick automaton for finding any of a large set of literals.
+//!
+//! The automaton is compiled to a DFA over byte classes: every byte occurring in a
+//! literal has a class of its own and all other bytes share one, so the transition
+//! table has one row per trie node and one column per class. A scan takes one table
+//! lookup per input byte whatever the number of literals, which makes it the better
+//! choice over a `Fingerprint` once there are hundreds of them.
+
+use std::collections::VecDeque;
+use std::hash::{Hash, Hasher};
+use std::iter;
+use std::ops::Range;
+
+/// Marks a trie edge that does not exist while the automaton is built.
+const NO_STATE: u32 = u32::MAX;
+
+/// A DFA finding occurrences of any of a set of literals.
+///
+/// Literals must not contain the record delimiter of the data they are searched in.
+#[derive(Debug, Clone)]
+pub struct Automaton {
+    /// Sorted and deduplicated.
+    literals: Vec<Vec<u8>>,
+    /// Byte classes; there are up to 257, as bytes in no literal share a class.
+    classes: Vec<u16>,
+    /// Number of byte classes, i.e. the length of a row of `transitions`.
+    stride: usize,
+    /// `transitions[state * stride + class]` is the state after reading a byte of
+    /// `class` in `state`. State 0 is the start.
+    transitions: Vec<u32>,
+    /// Length of the longest literal ending in each state, or zero if none does.
+    matches: Vec<u32>,
+}
+
+impl Automaton {
+    /// Creates the automaton of `literals`, ignoring empty ones.
+    pub fn new<T: AsRef<[u8]>>(literals: &[T]) -> Self {
+        let mut literals: Vec<Vec<u8>> = literals
+            .iter()
+            .map(|l| l.as_ref().to_vec())
+            .filter(|l| !l.is_empty())
+            .collect();
+        literals.sort();
+        literals.dedup();
+
+        let mut classes = vec![0u16; 256];
+        let mut stride = 1;
+        for &b in literals.iter().flatten() {
+            if classes[b as usize] == 0 {
+                classes[b as usize] = stride as u16;
+                stride += 1;
+            }
+        }
+
+        // the trie, with missing edges marked
+        let mut transitions = vec![NO_STATE; stride];
+        let mut matches = vec![0];
+        for literal in &literals {
+            let mut state = 0;
+            for &b in literal {
+                let edge = state * stride + classes[b as usize] as usize;
+                if transitions[edge] == NO_STATE {
+                    transitions[edge] = matches.len() as u32;
+                    transitions.extend(iter::repeat_n(NO_STATE, stride));
+                    matches.push(0);
+                }
+                state = transitions[edge] as usize;
+            }
+            matches[state] = literal.len() as u32;
+        }
+
+        // Breadth-first, every missing edge of a state is the edge of its failure
+        // state, the longest proper suffix in the trie, which is complete already.
+        let mut queue = VecDeque::new();
+        for edge in transitions.iter_mut().take(stride) {
+            match *edge {
+                NO_STATE => *edge = 0,
+                next => queue.push_back((next as usize, 0)),
+            }
+        }
+        while let Some((state, fail)) = queue.pop_front() {
+            if matches[state] == 0 {
+                matches[state] = matches[fail];
+            }
+            for class in 0..stride {
+                let fail_next = transitions[fail * stride + class];
+                match transitions[state * stride + class] {
+                    NO_STATE => transitions[state * stride + class] = fail_next,
+                    next => queue.push_back((next as usize, fail_next as usize)),
+                }
+            }
+        }
+
+        Automaton {
+            literals,
+            classes,
+            stride,
+            transitions,
+            matches,
+        }
+    }
+
+    /// Returns the literals, sorted.
+    pub fn literals(&self) -> &[Vec<u8>] {
+        &self.literals
+    }
+
+    /// Returns the number of states of the automaton.
+    pub fn states(&self) -> usize {
+        self.matches.len()
+    }
+
+    /// Returns the byte range of the first literal to end in `data`; of the literals
+    /// ending there, the longest.
+    pub fn find(&self, data: &[u8]) -> Option<Range<usize>> {
+        self.find_iter(data).next()
+    }
+
+    /// Returns the byte ranges of the literals in `data`, one for every position a
+    /// literal ends at, as `find` reports them.
+    pub fn find_iter<'a>(&'a self, data: &'a [u8]) -> AutomatonIter<'a> {
+        AutomatonIter {
+            automaton: self,
+            data,
+            pos: 0,
+            state: 0,
+        }
+    }
+}
+
+pub struct AutomatonIter<'a> {
+    automaton: &'a Automaton,
+    data: &'a [u8],
+    pos: usize,
+    state: usize,
+}
+
+impl<'a> Iterator for AutomatonIter<'a> {
+    type Item = Range<usize>;
+
+    fn next(&mut self) -> Option<Range<usize>> {
+        let automaton = self.automaton;
+        while self.pos < self.data.len() {
+            let class = automaton.classes[self.data[self.pos] as usize] as usize;
+            self.state = automaton.transitions[self.state * automaton.stride + class] as usize;
+            self.pos += 1;
+            let len = automaton.matches[self.state] as usize;
+            if len != 0 {
+                return Some(self.pos - len..self.pos);
+            }
+        }
+        None
+    }
+}
+
+impl PartialEq for Automaton {
+    fn eq(&self, other: &Automaton) -> bool {
+        self.literals == other.literals
+    }
+}
+
+impl Eq for Automaton {}
+
+/// The tables are derived from the literals, so they alone identify an automaton.
+impl Hash for Automaton {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.literals.hash(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    #[test]
+    fn test_find() {
+        let automaton = Automaton::new(&["he", "she", "his", "hers", ""]);
+        assert_eq!(4, automaton.literals().len());
+        assert_eq!(Some(1..4), automaton.find(b"ushers"));
+        assert_eq!(
+            vec![1..4, 2..6],
+            automaton.find_iter(b"ushers").collect::<Vec<_>>()
+        );
+        assert_eq!(Some(0..3), automaton.find(b"his"));
+        assert_eq!(None, automaton.find(b"hi s"));
+        assert_eq!(None, Automaton::new::<&str>(&[]).find(b"anything"));
+        assert_eq!(1, Automaton::new::<&str>(&[]).states());
+    }
+
+    #[test]
+    fn test_matches_naive_search() {
+        let mut rng = StdRng::seed_from_u64(47);
+        // a two letter alphabet makes literals overlap and share suffixes, which
+        // exercises the failure links
+        let alphabet = b"ab";
+        for _ in 0..50 {
+            let count = rng.gen_range(1, 20);
+            let literals: Vec<Vec<u8>> = (0..count)
+                .map(|_| {
+                    let len = rng.gen_range(1, 8);
+                    (0..len).map(|_| *rng.choose(alphabet).unwrap()).collect()
+                })
+                .collect();
+            let automaton = Automaton::new(&literals);
+            let data: Vec<u8> = (0..rng.gen_range(0, 200))
+                .map(|_| *rng.choose(alphabet).unwrap())
+                .collect();
+            // for every end position, the longest literal ending there
+            let expected: Vec<Range<usize>> = (1..data.len() + 1)
+                .filter_map(|end| {
+                    literals
+                        .iter()
+                        .filter(|l| data[..end].ends_with(l))
+                        .map(|l| end - l.len()..end)
+                        .min_by_key(|r| r.start)
+                })
+                .collect();
+            assert_eq!(expected, automaton.find_iter(&data).collect::<Vec<_>>());
+            assert_eq!(expected.first().cloned(), automaton.find(&data));
+        }
+    }
+}
@@ -0,0 +1,842 @@
+//! `sparser grep`: prints the records of files or stdin that match a filter
+//! expression, filtering with an optimized raw-filter cascade before parsing.
+
+extern crate sparser_rs;
+
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Cursor, ErrorKind, Read, Write};
+use std::path::Path;
+use std::process;
+use std::time::Instant;
+
+use sparser_rs::adaptive::{AdaptiveConfig, AdaptiveScanner};
+use sparser_rs::calibration::KernelCosts;
+use sparser_rs::compression::Decompressor;
+use sparser_rs::formats::csv::{trim_cr, CsvFormat};
+use sparser_rs::formats::log::{LogField, LogFormat, LogPredicate};
+use sparser_rs::optimizer::{sample_csv_records, sample_records, Optimizer, Plan};
+use sparser_rs::parallel::ParallelScanner;
+use sparser_rs::plan_cache::{PlanCache, PlanKey};
+use sparser_rs::query::{JsonVerifier, Predicate, Query};
+use sparser_rs::raw_filter::RawFilter;
+use sparser_rs::sparser_kernels;
+use sparser_rs::stats::ScanStats;
+use sparser_rs::stream::{check_utf8, ChunkReader};
+use sparser_rs::utils::buffer::Buffer;
+use sparser_rs::utils::error::{Result, SparserError};
+use sparser_rs::utils::memory::AllocationMode;
+
+const USAGE: &str = "usage: sparser grep [OPTIONS] <QUERY> [FILE]...
+
+Prints the records matching QUERY, one per line, from each FILE or from stdin if
+there is none or FILE is '-'. Compressed input (gzip, zstd, lz4) is detected and
+decompressed.
+
+QUERY is an expression such as
+    text CONTAINS 'rust' AND user.lang = 'en' OR retweets > 10
+
+options:
+  -c, --count            print only the number of matching records
+  -b, --byte-offset      print the byte offset of each record before it
+  -e, --explain          print how the raw-filter cascade was chosen to stderr
+  -s, --stats            print scan statistics to stderr
+  -j, --threads <N>      scan with N threads, 0 for one per core (default 1);
+                         more than one thread reads each input into memory
+  -f, --format <FORMAT>  json (default), csv, tsv, access or syslog; csv and tsv
+                         take field names from a header line, and their quoted
+                         fields may span lines
+  -p, --plan-cache <FILE>
+                         reuse the cascades chosen for this query on the same
+                         files from FILE, and store new ones there
+  -k, --costs <FILE>     choose cascades with the search and verify costs measured
+                         in FILE; if it does not exist, measure them on the start
+                         of the first input and save them there
+  -u, --utf8             fail on input that is not valid UTF-8
+  -x, --escapes          pass JSON records with escape sequences to the verifier,
+                         so that none is missed however its strings are escaped
+  -l, --lazy             verify JSON records by parsing only the fields the query
+                         reads; records malformed elsewhere may then match
+  -h, --help             print this help";
+
+/// Returns the records of `data` to choose a cascade with.
+fn sample(format: Format, data: &[u8]) -> Vec<&[u8]> {
+    let max = AdaptiveConfig::default().sample_size;
+    match format {
+        Format::Csv(ref csv) => sample_csv_records(csv, data, max),
+        _ => sample_records(data, b'\n', max),
+    }
+}
+
+/// Number of bytes at the start of an input that costs are measured on.
+const CALIBRATION_INPUT_BYTES: u64 = 1 << 20;
+
+/// Record format of the inputs.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Format {
+    Json,
+    Csv(CsvFormat),
+    Log(LogFormat),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Options {
+    query: String,
+    files: Vec<String>,
+    count: bool,
+    offsets: bool,
+    explain: bool,
+    stats: bool,
+    threads: usize,
+    format: Format,
+    plan_cache: Option<String>,
+    costs: Option<String>,
+    utf8: bool,
+    escapes: bool,
+    lazy: bool,
+}
+
+fn parse_args(args: &[String]) -> std::result::Result<Options, String> {
+    let mut args = args.iter();
+    match args.next().map(String::as_str) {
+        Some("grep") => {}
+        Some(command) => return Err(format!("unknown command '{}'", command)),
+        None => return Err("missing command".to_string()),
+    }
+    let mut options = Options {
+        query: String::new(),
+        files: Vec::new(),
+        count: false,
+        offsets: false,
+        explain: false,
+        stats: false,
+        threads: 1,
+        format: Format::Json,
+        plan_cache: None,
+        costs: None,
+        utf8: false,
+        escapes: false,
+        lazy: false,
+    };
+    let mut positional = Vec::new();
+    let mut only_positional = false;
+    while let Some(arg) = args.next() {
+        if only_positional || arg == "-" || !arg.starts_with('-') {
+            positional.push(arg.clone());
+            continue;
+        }
+        // --name=value and -nVALUE carry the value in the same argument
+        let (name, inline) = if arg.starts_with("--") {
+            match arg.find('=') {
+                Some(i) => (&arg[..i], Some(arg[i + 1..].to_string())),
+                None => (arg.as_str(), None),
+            }
+        } else if arg.len() > 2 {
+            (&arg[..2], Some(arg[2..].to_string()))
+        } else {
+            (arg.as_str(), None)
+        };
+        let mut value = || {
+            inline
+                .clone()
+                .or_else(|| args.next().cloned())
+                .ok_or_else(|| format!("{} needs a value", name))
+        };
+        match name {
+            "--" => only_positional = true,
+            "-c" | "--count" => options.count = true,
+            "-b" | "--byte-offset" => options.offsets = true,
+            "-e" | "--explain" => options.explain = true,
+            "-s" | "--stats" => options.stats = true,
+            "-u" | "--utf8" => options.utf8 = true,
+            "-x" | "--escapes" => options.escapes = true,
+            "-l" | "--lazy" => options.lazy = true,
+            "-j" | "--threads" => {
+                let threads = value()?;
+                options.threads = threads
+                    .parse()
+                    .map_err(|_| format!("invalid thread count '{}'", threads))?;
+            }
+            "-f" | "--format" => {
+                options.format = match value()?.as_str() {
+                    "json" => Format::Json,
+                    "csv" => Format::Csv(CsvFormat::csv()),
+                    "tsv" => Format::Csv(CsvFormat::tsv()),
+                    "access" => Format::Log(LogFormat::Access),
+                    "syslog" => Format::Log(LogFormat::Syslog),
+                    format => return Err(format!("unknown format '{}'", format)),
+                }
+            }
+            "-p" | "--plan-cache" => options.plan_cache = Some(value()?),
+            "-k" | "--costs" => options.costs = Some(value()?),
+            "-h" | "--help" => return Err(String::new()),
+            _ => return Err(format!("unknown option '{}'", arg)),
+        }
+        let takes_value = [
+            "-j",
+            "--threads",
+            "-f",
+            "--format",
+            "-p",
+            "--plan-cache",
+            "-k",
+            "--costs",
+        ]
+        .contains(&name);
+        if inline.is_some() && !takes_value {
+            return Err(format!("unknown option '{}'", arg));
+        }
+    }
+    let mut positional = positional.into_iter();
+    options.query = positional.next().ok_or("missing query")?;
+    options.files = positional.collect();
+    if options.files.is_empty() {
+        options.files.push("-".to_string());
+    }
+    Ok(options)
+}
+
+/// Evaluates the query on records of one input and derives its raw filters.
+enum Verifier {
+    Json(JsonVerifier),
+    Csv {
+        format: CsvFormat,
+        columns: HashMap<String, usize>,
+        query: Query,
+    },
+    Log {
+        format: LogFormat,
+        fields: HashMap<String, LogField>,
+        query: Query,
+    },
+}
+
+impl Verifier {
+    /// Creates the verifier for an input; a CSV input's `header` names its columns.
+    fn new(format: Format, query: &Query, header: &[u8]) -> Result<Self> {
+        let verifier = match format {
+            Format::Json => Verifier::Json(JsonVerifier::new(query.clone())),
+            Format::Csv(format) => {
+                let columns = format
+                    .fields(header)
+                    .iter()
+                    .enumerate()
+                    .map(|(i, name)| {
+                        let name = String::from_utf8_lossy(&format.unquote(name)).into_owned();
+                        (name, i)
+                    })
+                    .collect::<HashMap<_, _>>();
+                check_fields(query, |field| columns.contains_key(field))?;
+                Verifier::Csv {
+                    format,
+                    columns,
+                    query: query.clone(),
+                }
+            }
+            Format::Log(format) => {
+                let fields: HashMap<String, LogField> = [
+                    ("timestamp", LogField::Timestamp),
+                    ("host", LogField::Host),
+                    ("status", LogField::Status),
+                    ("path", LogField::Path),
+                    ("message", LogField::Message),
+                ]
+                .iter()
+                .map(|&(name, field)| (name.to_string(), field))
+                .collect();
+                check_fields(query, |field| fields.contains_key(field))?;
+                Verifier::Log {
+                    format,
+                    fields,
+                    query: query.clone(),
+                }
+            }
+        };
+        Ok(verifier)
+    }
+
+    /// Makes the raw filters of JSON records pass records with escapes, which CSV
+    /// and log records do not have.
+    fn with_escape_safety(self) -> Self {
+        match self {
+            Verifier::Json(verifier) => Verifier::Json(verifier.with_escape_safety()),
+            verifier => verifier,
+        }
+    }
+
+    /// Makes JSON records be verified by parsing only the fields the query reads.
+    fn with_lazy_parsing(self) -> Self {
+        match self {
+            Verifier::Json(verifier) => Verifier::Json(verifier.with_lazy_parsing()),
+            verifier => verifier,
+        }
+    }
+
+    fn verify(&self, record: &[u8]) -> bool {
+        match *self {
+            Verifier::Json(ref verifier) => verifier.verify(record),
+            Verifier::Csv {
+                ref format,
+                ref columns,
+                ref query,
+            } => query.evaluate_with(&|p: &Predicate| {
+                format
+                    .field(trim_cr(record), columns[p.field()])
+                    .is_some_and(|field| {
+                        p.evaluate_text(&String::from_utf8_lossy(&format.unquote(field)))
+                    })
+            }),
+            Verifier::Log {
+                ref format,
+                ref fields,
+                ref query,
+            } => match format.parse(record) {
+                Some(parsed) => query.evaluate_with(&|p: &Predicate| {
+                    parsed
+                        .field(fields[p.field()])
+                        .is_some_and(|field| p.evaluate_text(&String::from_utf8_lossy(field)))
+                }),
+                None => false,
+            },
+        }
+    }
+
+    /// Returns the raw filters implied by the query, as optimizer candidates.
+    fn raw_filters(&self) -> Vec<RawFilter> {
+        match *self {
+            Verifier::Json(ref verifier) => verifier.raw_filters(),
+            Verifier::Csv {
+                ref format,
+                ref columns,
+                ref query,
+            } => query.raw_filters_with(&|p: &Predicate| match *p {
+                Predicate::In { ref values, .. } => {
+                    any_of_filters(values, |v| format.escape(v.as_bytes()))
+                }
+                _ => {
+                    let column = columns[p.field()];
+                    p.required_literals()
+                        .iter()
+                        .map(|literal| RawFilter::column(*format, column, format.escape(literal)))
+                        .collect()
+                }
+            }),
+            Verifier::Log {
+                ref format,
+                ref fields,
+                ref query,
+            } => query.raw_filters_with(&|p: &Predicate| match *p {
+                Predicate::Equals { ref value, .. } => LogPredicate::Equals {
+                    field: fields[p.field()],
+                    value: value.as_bytes().to_vec(),
+                }
+                .raw_filter(format)
+                .into_iter()
+                .collect(),
+                Predicate::In { ref values, .. } => {
+                    any_of_filters(values, |v| v.as_bytes().to_vec())
+                }
+                _ => p
+                    .required_literals()
+                    .iter()
+                    .map(RawFilter::substring)
+                    .collect(),
+            }),
+        }
+    }
+}
+
+/// Returns the filters passing lines that contain one of `values` as written by
+/// `encode`, or none if a value is empty or spans lines.
+fn any_of_filters<F: Fn(&str) -> Vec<u8>>(values: &BTreeSet<String>, encode: F) -> Vec<RawFilter> {
+    let literals: Vec<Vec<u8>> = values.iter().map(|v| encode(v)).collect();
+    if literals.iter().any(|l| l.is_empty() || l.contains(&b'\n')) {
+        Vec::new()
+    } else {
+        RawFilter::any_of_candidates(&literals)
+    }
+}
+
+/// Fails with `InvalidQuery` if the query uses a field for which `known` is false.
+fn check_fields<F: Fn(&str) -> bool>(query: &Query, known: F) -> Result<()> {
+    match query.predicates().iter().find(|p| !known(p.field())) {
+        Some(p) => Err(SparserError::InvalidQuery(format!(
+            "unknown field '{}'",
+            p.field()
+        ))),
+        None => Ok(()),
+    }
+}
+
+/// Reads the first line of `reader`, without the line break, one byte at a time so
+/// that nothing after it is consumed.
+fn read_header<R: Read>(reader: &mut R) -> Result<Vec<u8>> {
+    let mut header = Vec::new();
+    let mut byte = [0u8];
+    loop {
+        match reader.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) if byte[0] == b'\n' => break,
+            Ok(_) => header.push(byte[0]),
+            Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+    if header.last() == Some(&b'\r') {
+        header.pop();
+    }
+    Ok(header)
+}
+
+/// Prints why the plan was chosen; `recalibrations` counts the plans before it.
+fn explain<W: Write>(out: &mut W, name: &str, plan: Option<&Plan>, recalibrations: usize) {
+    let _ = match plan {
+        Some(plan) if recalibrations > 0 => writeln!(
+            out,
+            "{}: plan after {} recalibrations\n{}",
+            name, recalibrations, plan.explain
+        ),
+        Some(plan) => writeln!(out, "{}:\n{}", name, plan.explain),
+        None => writeln!(out, "{}: empty input", name),
+    };
+}
+
+/// Scans one input, printing matches to `out`. Returns the number of matches.
+fn grep<W: Write>(
+    options: &Options,
+    query: &Query,
+    name: &str,
+    cache: &mut Option<PlanCache>,
+    costs: &mut Option<KernelCosts>,
+    out: &mut W,
+) -> Result<u64> {
+    let reader: Box<dyn Read> = if name == "-" {
+        Box::new(io::stdin())
+    } else {
+        Box::new(File::open(name).map_err(|e| SparserError::from(e).context(name.to_string()))?)
+    };
+    let mut reader = Decompressor::new(reader)?;
+    let header = match options.format {
+        Format::Csv(_) => read_header(&mut reader)?,
+        _ => Vec::new(),
+    };
+    // offsets count from the start of the (decompressed) input
+    let base = if header.is_empty() {
+        0
+    } else {
+        header.len() as u64 + 1
+    };
+    let mut verifier = Verifier::new(options.format, query, &header)?;
+    if options.escapes {
+        verifier = verifier.with_escape_safety();
+    }
+    if options.lazy {
+        verifier = verifier.with_lazy_parsing();
+    }
+    let mut reader: Box<dyn Read> = Box::new(reader);
+    if let (Some(ref path), None) = (options.costs.as_ref(), costs.as_ref()) {
+        // measure on the start of the input, then scan all of it
+        let mut start = Vec::new();
+        (&mut reader)
+            .take(CALIBRATION_INPUT_BYTES)
+            .read_to_end(&mut start)?;
+        let sample = sample(options.format, &start);
+        if sample.iter().any(|record| !record.is_empty()) {
+            let measured = KernelCosts::measure(&sample, |record| verifier.verify(record))?;
+            measured.save(path)?;
+            *costs = Some(measured);
+        }
+        reader = Box::new(Cursor::new(start).chain(reader));
+    }
+    let optimizer = match *costs {
+        Some(ref costs) => Optimizer::new(costs.cost_model()),
+        None => Optimizer::default(),
+    };
+    // stdin has no identity to cache plans under
+    let key = match *cache {
+        Some(_) if name != "-" => Some(PlanKey::new(
+            query,
+            fs::canonicalize(name)?.to_string_lossy(),
+        )),
+        _ => None,
+    };
+    let prefix = if options.files.len() > 1 {
+        format!("{}:", name)
+    } else {
+        String::new()
+    };
+
+    let mut written: io::Result<()> = Ok(());
+    let mut print = |offset: u64, record: &[u8]| {
+        if options.count || written.is_err() {
+            return;
+        }
+        written = (|| {
+            out.write_all(prefix.as_bytes())?;
+            if options.offsets {
+                write!(out, "{}:", base + offset)?;
+            }
+            match options.format {
+                Format::Csv(_) => out.write_all(trim_cr(record))?,
+                _ => out.write_all(record)?,
+            }
+            out.write_all(b"\n")
+        })();
+    };
+
+    let matches = if options.threads == 1 {
+        let mut chunks = ChunkReader::new(reader, b'\n');
+        if let Format::Csv(format) = options.format {
+            chunks = chunks.with_csv_records(format);
+        }
+        if options.utf8 {
+            chunks = chunks.with_utf8_validation();
+        }
+        let mut scanner = AdaptiveScanner::new(
+            chunks,
+            verifier.raw_filters(),
+            optimizer.clone(),
+            AdaptiveConfig::default(),
+        );
+        if let (Some(plans), Some(key)) = (cache.take(), key) {
+            scanner = scanner.with_plan_cache(plans, key);
+        }
+        let matches = scanner
+            .search(|offset, record| {
+                let matched = verifier.verify(record);
+                if matched {
+                    print(offset, record);
+                }
+                matched
+            })
+            .map_err(|e| match e {
+                // the reader starts after the header
+                SparserError::FormatError {
+                    offset: Some(at), ..
+                } => e.at_offset(base + at),
+                e => e,
+            })?;
+        if let Some(plans) = scanner.take_plan_cache() {
+            *cache = Some(plans);
+        }
+        if options.explain {
+            explain(
+                &mut io::stderr(),
+                name,
+                scanner.plan(),
+                scanner.recalibrations(),
+            );
+        }
+        if options.stats {
+            eprintln!("{}: {}", name, scanner.stats());
+        }
+        matches
+    } else {
+        let read_start = Instant::now();
+        let mut data = Vec::new();
+        reader.read_to_end(&mut data)?;
+        if options.utf8 {
+            check_utf8(&data, base)?;
+        }
+        let buffer = Buffer::copy_from_slice(&data, AllocationMode::Default)?;
+        drop(data);
+        let read_time = read_start.elapsed();
+        let calibration_start = Instant::now();
+        let sample = sample(options.format, buffer.data());
+        let plan = match (cache.as_mut(), key) {
+            _ if sample.is_empty() => None,
+            (Some(plans), Some(key)) => {
+                Some(plans.plan(&key, &optimizer, &sample, &verifier.raw_filters())?)
+            }
+            _ => Some(optimizer.optimize(&sample, &verifier.raw_filters())?),
+        };
+        let calibration_time = calibration_start.elapsed();
+        if options.explain {
+            explain(&mut io::stderr(), name, plan.as_ref(), 0);
+        }
+        let mut scanner = ParallelScanner::new(options.threads, b'\n');
+        if let Format::Csv(format) = options.format {
+            scanner = scanner.with_csv_records(format);
+        }
+        let (offsets, mut stats) = match plan {
+            Some(plan) => {
+                scanner.search_with_stats(&buffer, &plan.cascade, |record| verifier.verify(record))
+            }
+            None => (Vec::new(), ScanStats::new()),
+        };
+        if options.stats {
+            stats.read_time = read_time;
+            stats.calibration_time = calibration_time;
+            eprintln!("{}: {}", name, stats);
+        }
+        let data = buffer.data();
+        for &offset in &offsets {
+            let end = match options.format {
+                Format::Csv(format) => format.record_end(data, offset),
+                _ => sparser_kernels::find_byte(&data[offset..], b'\n')
+                    .map_or(data.len(), |i| offset + i),
+            };
+            print(offset as u64, &data[offset..end]);
+        }
+        offsets.len() as u64
+    };
+    written?;
+    if options.count {
+        writeln!(out, "{}{}", prefix, matches)?;
+    }
+    Ok(matches)
+}
+
+fn run(options: &Options) -> Result<u64> {
+    let query: Query = options.query.parse()?;
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    let mut cache = match options.plan_cache {
+        Some(ref path) => Some(PlanCache::open(path)?),
+        None => None,
+    };
+    let mut costs = match options.costs {
+        Some(ref path) if Path::new(path).exists() => Some(KernelCosts::load(path)?),
+        _ => None,
+    };
+    let mut matches = 0;
+    for name in &options.files {
+        matches += grep(options, &query, name, &mut cache, &mut costs, &mut out)?;
+    }
+    out.flush()?;
+    if let Some(ref cache) = cache {
+        cache.save()?;
+    }
+    Ok(matches)
+}
+
+fn main() {
+    let args: Vec<String> = env::args().skip(1).collect();
+    let options = match parse_args(&args) {
+        Ok(options) => options,
+        Err(message) => {
+            if message.is_empty() {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            eprintln!("sparser: {}\n\n{}", message, USAGE);
+            process::exit(2);
+        }
+    };
+    match run(&options) {
+        Ok(0) => process::exit(1),
+        Ok(_) => {}
+        Err(SparserError::IoError(ref e)) if e.kind() == ErrorKind::BrokenPipe => {}
+        Err(e) => {
+            eprintln!("sparser: {}", e.report());
+            process::exit(2);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+
+    use super::*;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split(' ').map(String::from).collect()
+    }
+
+    #[test]
+    fn test_parse_args() {
+        let options = parse_args(&args("grep -c -j4 --format=csv -- -query a.csv -")).unwrap();
+        assert_eq!("-query", options.query);
+        assert_eq!(vec!["a.csv", "-"], options.files);
+        assert!(options.count && !options.offsets);
+        assert_eq!(4, options.threads);
+        assert_eq!(Format::Csv(CsvFormat::csv()), options.format);
+
+        let options = parse_args(&args("grep q -b --threads 0 -f syslog -e --stats")).unwrap();
+        assert_eq!(vec!["-"], options.files);
+        assert!(options.offsets && options.explain && options.stats);
+        assert_eq!(0, options.threads);
+        assert_eq!(Format::Log(LogFormat::Syslog), options.format);
+        assert_eq!(None, options.plan_cache);
+        assert_eq!(None, options.costs);
+        assert!(!options.utf8 && !options.escapes && !options.lazy);
+        let options = parse_args(&args(
+            "grep --plan-cache=plans.json -u q -x --lazy -k costs.json",
+        ))
+        .unwrap();
+        assert_eq!(Some("plans.json".to_string()), options.plan_cache);
+        assert_eq!(Some("costs.json".to_string()), options.costs);
+        assert!(options.utf8 && options.escapes && options.lazy);
+
+        assert!(parse_args(&args("find q")).is_err());
+        assert!(parse_args(&args("grep")).is_err());
+        assert!(parse_args(&args("grep -j")).is_err());
+        assert!(parse_args(&args("grep -f xml q")).is_err());
+        assert!(parse_args(&args("grep -cb q")).is_err());
+        assert_eq!(Err(String::new()), parse_args(&args("grep --help")));
+    }
+
+    fn verify_all(format: Format, query: &str, data: &[u8]) -> Vec<String> {
+        let query: Query = query.parse().unwrap();
+        let mut reader = Cursor::new(data);
+        let header = match format {
+            Format::Csv(_) => read_header(&mut reader).unwrap(),
+            _ => Vec::new(),
+        };
+        let verifier = Verifier::new(format, &query, &header).unwrap();
+        let filters = verifier.raw_filters();
+        let mut rest = Vec::new();
+        reader.read_to_end(&mut rest).unwrap();
+        rest.split(|&b| b == b'\n')
+            .filter(|record| verifier.verify(record))
+            .map(|record| {
+                // no matching record may fail a candidate filter
+                assert!(filters.iter().all(|f| f.matches(record)));
+                String::from_utf8_lossy(record).into_owned()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_csv_verifier() {
+        let data = b"id,lang,\"retweets\"\n1,en,5\n2,de,50\n3,\"en\",500\n4,en,x\n";
+        assert_eq!(
+            vec!["2,de,50", "3,\"en\",500"],
+            verify_all(Format::Csv(CsvFormat::csv()), "retweets > 10", data)
+        );
+        assert_eq!(
+            vec!["1,en,5", "3,\"en\",500"],
+            verify_all(
+                Format::Csv(CsvFormat::csv()),
+                "lang = 'en' and not retweets ~ /^x/ and id matches '[0-9]'",
+                data
+            )
+        );
+        assert_eq!(
+            vec!["2,de,50"],
+            verify_all(Format::Csv(CsvFormat::csv()), "lang IN ('de', 'fr')", data)
+        );
+        let crlf = b"id,lang,retweets\r\n1,en,5\r\n2,de,50\r\n";
+        assert_eq!(
+            vec!["2,de,50\r"],
+            verify_all(Format::Csv(CsvFormat::csv()), "retweets = '50'", crlf)
+        );
+        assert_eq!(
+            vec!["1,en,5\r"],
+            verify_all(Format::Csv(CsvFormat::csv()), "retweets IN ('5')", crlf)
+        );
+        let query: Query = "text = 'x'".parse().unwrap();
+        assert!(Verifier::new(Format::Csv(CsvFormat::csv()), &query, b"id,lang").is_err());
+    }
+
+    #[test]
+    fn test_grep_csv_with_quoted_newlines() {
+        let path = env::temp_dir().join(format!("sparser-grep-{}.csv", process::id()));
+        let data = "id,lang,text\n1,de,\"a\nfr\"\n2,fr,\"b\n\"\"c\"\"\"\n3,en,fr\n";
+        fs::write(&path, data).unwrap();
+        let mut outputs = Vec::new();
+        for threads in ["1", "2"].iter() {
+            let line = format!("grep -b -j{} -f csv lang='fr' {}", threads, path.display());
+            let options = parse_args(&args(&line)).unwrap();
+            let query: Query = options.query.parse().unwrap();
+            let mut out = Vec::new();
+            let name = &options.files[0];
+            assert_eq!(
+                1,
+                grep(&options, &query, name, &mut None, &mut None, &mut out).unwrap()
+            );
+            outputs.push(String::from_utf8(out).unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!("25:2,fr,\"b\n\"\"c\"\"\"\n", outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_grep_csv_with_crlf() {
+        let path = env::temp_dir().join(format!("sparser-crlf-{}.csv", process::id()));
+        fs::write(&path, "id,lang\r\n1,fr\r\n2,\"de\r\nfr\"\r\n3,en\r\n").unwrap();
+        let mut outputs = Vec::new();
+        for threads in ["1", "2"].iter() {
+            let line = format!("grep -j{} -f csv lang='fr' {}", threads, path.display());
+            let options = parse_args(&args(&line)).unwrap();
+            let query: Query = options.query.parse().unwrap();
+            let mut out = Vec::new();
+            let name = &options.files[0];
+            grep(&options, &query, name, &mut None, &mut None, &mut out).unwrap();
+            outputs.push(String::from_utf8(out).unwrap());
+        }
+        fs::remove_file(&path).unwrap();
+        assert_eq!("1,fr\n", outputs[0]);
+        assert_eq!(outputs[0], outputs[1]);
+    }
+
+    #[test]
+    fn test_grep_with_costs() {
+        let dir = env::temp_dir();
+        let input = dir.join(format!("sparser-costs-{}.json", process::id()));
+        let costs_path = dir.join(format!("sparser-costs-{}.costs", process::id()));
+        let mut data = String::new();
+        for i in 0..100 {
+            data.push_str(&format!("{{\"id\": {}, \"lang\": \"{}\"}}\n", i, i % 3));
+        }
+        fs::write(&input, &data).unwrap();
+        let line = format!(
+            "grep -c -k {} lang='1' {}",
+            costs_path.display(),
+            input.display()
+        );
+        let options = parse_args(&args(&line)).unwrap();
+        let query: Query = options.query.parse().unwrap();
+        let name = &options.files[0];
+
+        // the first input is measured and its costs saved
+        let mut costs = None;
+        let matches = grep(
+            &options,
+            &query,
+            name,
+            &mut None,
+            &mut costs,
+            &mut Vec::new(),
+        );
+        let saved = KernelCosts::load(&costs_path);
+        // and later ones are planned with the same costs
+        let measured = costs;
+        let again = grep(
+            &options,
+            &query,
+            name,
+            &mut None,
+            &mut costs,
+            &mut Vec::new(),
+        );
+        fs::remove_file(&input).unwrap();
+        fs::remove_file(&costs_path).unwrap();
+
+        assert_eq!(33, matches.unwrap());
+        assert_eq!(33, again.unwrap());
+        assert!(saved.is_ok());
+        assert!(measured.is_some());
+        assert_eq!(measured, costs);
+    }
+
+    #[test]
+    fn test_log_verifier() {
+        let data = b"10.0.0.1 - - [10/Oct/2000:13:55:36 -0700] \"GET /a HTTP/1.0\" 500 1\n\
+10.0.0.2 - - [10/Oct/2000:13:55:37 -0700] \"GET /500 HTTP/1.0\" 200 500\n";
+        let format = Format::Log(LogFormat::Access);
+        assert_eq!(1, verify_all(format, "status = '500'", data).len());
+        assert_eq!(
+            2,
+            verify_all(format, "status >= 200 and path contains '/'", data).len()
+        );
+        assert_eq!(
+            1,
+            verify_all(format, "status in ('404', '500')", data).len()
+        );
+        let query: Query = "user = 'x'".parse().unwrap();
+        assert!(Verifier::new(format, &query, b"").is_err());
+    }
+}
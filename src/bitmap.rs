@@ -8,14 +8,23 @@ pub struct Bitmap {
 
 impl Bitmap {
     pub fn new(num_bits: usize) -> Self {
-        let num_bytes = num_bits / 8 + if num_bits % 8 > 0 { 1 } else { 0 };
+        Self::filled(num_bits, 255)
+    }
+
+    /// Creates a bitmap of `num_bits` bits that are all unset.
+    pub fn zeroed(num_bits: usize) -> Self {
+        Self::filled(num_bits, 0)
+    }
+
+    fn filled(num_bits: usize, byte: u8) -> Self {
+        let num_bytes = num_bits.div_ceil(8);
         let r = num_bytes % 64;
         let len = if r == 0 {
             num_bytes
         } else {
             num_bytes + 64 - r
         };
-        let mut v = vec![255; len];
+        let v = vec![byte; len];
         Bitmap {
             bits: Buffer::from(&v[..]),
         }
@@ -25,16 +34,32 @@ impl Bitmap {
         self.bits.len()
     }
 
+    pub fn is_empty(&self) -> bool {
+        self.bits.is_empty()
+    }
+
     pub fn is_set(&self, i: i64) -> bool {
         bit_util::get_bit(self.bits.data(), i)
     }
 
+    /// Sets bit `i`.
+    ///
+    /// # Safety
+    ///
+    /// Writes through the shared underlying buffer; no other clone of it may be
+    /// read concurrently.
     pub unsafe fn set(&mut self, i: i64) {
         let raw_data = self.bits.raw_data() as *mut u8;
         let len = self.bits.len();
         bit_util::set_bit(std::slice::from_raw_parts_mut(raw_data, len), i)
     }
 
+    /// Unsets bit `i`.
+    ///
+    /// # Safety
+    ///
+    /// Writes through the shared underlying buffer; no other clone of it may be
+    /// read concurrently.
     pub unsafe fn unset(&mut self, i: i64) {
         let raw_data = self.bits.raw_data() as *mut u8;
         let len = self.bits.len();
@@ -68,19 +93,19 @@ mod tests {
     #[test]
     fn test_bitmap_is_set() {
         let mut bitmap = Bitmap::from(Buffer::from([0b01001010]));
-        assert_eq!(false, bitmap.is_set(0));
-        assert_eq!(true, bitmap.is_set(1));
-        assert_eq!(false, bitmap.is_set(2));
-        assert_eq!(true, bitmap.is_set(3));
-        assert_eq!(false, bitmap.is_set(4));
-        assert_eq!(false, bitmap.is_set(5));
-        assert_eq!(true, bitmap.is_set(6));
-        assert_eq!(false, bitmap.is_set(7));
+        assert!(!bitmap.is_set(0));
+        assert!(bitmap.is_set(1));
+        assert!(!bitmap.is_set(2));
+        assert!(bitmap.is_set(3));
+        assert!(!bitmap.is_set(4));
+        assert!(!bitmap.is_set(5));
+        assert!(bitmap.is_set(6));
+        assert!(!bitmap.is_set(7));
         unsafe {
             bitmap.set(7);
-            assert_eq!(true, bitmap.is_set(7));
+            assert!(bitmap.is_set(7));
             bitmap.unset(7);
-            assert_eq!(false, bitmap.is_set(7));
+            assert!(!bitmap.is_set(7));
         }
     }
 }
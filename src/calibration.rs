@@ -0,0 +1,207 @@
+use std::fs;
+use std::hint::black_box;
+use std::path::Path;
+use std::time::Instant;
+
+use serde_json::{self, Value};
+
+use cascade::for_each_record;
+use optimizer::CostModel;
+use sparser_kernels;
+use utils::error::{Result, ResultExt, SparserError};
+
+/// Number of input bytes each kernel is timed over by default.
+pub const DEFAULT_CALIBRATION_BYTES: usize = 4 << 20;
+
+/// Verifying is roughly this many times slower than searching, so it is timed over
+/// proportionally fewer bytes.
+const VERIFY_COST_RATIO: usize = 16;
+
+/// Measured costs of the scan's basic operations on this machine, in nanoseconds
+/// per byte they look at. Only the operations the cost model prices are measured.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KernelCosts {
+    /// `sparser_kernels::find`, the substring search raw filters run.
+    pub find_ns_per_byte: f64,
+    /// Locating record boundaries, which a cascade does around every match.
+    pub skip_ns_per_byte: f64,
+    /// The verifier, i.e. fully parsing a record.
+    pub verify_ns_per_byte: f64,
+}
+
+impl KernelCosts {
+    /// Times the kernels over the records of `sample`, repeated to at least
+    /// `DEFAULT_CALIBRATION_BYTES`, and `verifier` on each record.
+    pub fn measure<F: FnMut(&[u8]) -> bool>(sample: &[&[u8]], verifier: F) -> Result<Self> {
+        KernelCosts::measure_bytes(sample, verifier, DEFAULT_CALIBRATION_BYTES)
+    }
+
+    /// Like `measure`, timing each kernel over at least `min_bytes` bytes.
+    pub fn measure_bytes<F: FnMut(&[u8]) -> bool>(
+        sample: &[&[u8]],
+        mut verifier: F,
+        min_bytes: usize,
+    ) -> Result<Self> {
+        if sample.iter().all(|r| r.is_empty()) {
+            return Err(SparserError::OptimizerError(
+                "cannot calibrate on an empty sample".to_string(),
+            ));
+        }
+        let mut data = Vec::new();
+        for record in sample {
+            data.extend_from_slice(record);
+            data.push(b'\n');
+        }
+        let rounds = min_bytes.div_ceil(data.len()).max(1);
+
+        let find_ns_per_byte = time_per_byte(&data, rounds, |data| {
+            // a needle that never matches, so that the whole input is searched
+            sparser_kernels::find(data, b"\x00\x01\x02\x03").is_some() as usize
+        });
+        let skip_ns_per_byte = time_per_byte(&data, rounds, |data| {
+            let mut records = 0;
+            for_each_record(data, b'\n', |_, _| records += 1);
+            records
+        });
+
+        let verify_rounds = min_bytes.div_ceil(data.len() * VERIFY_COST_RATIO).max(1);
+        let verify_ns_per_byte = time_per_byte(&data, verify_rounds, |_| {
+            sample.iter().filter(|r| verifier(r)).count()
+        });
+
+        Ok(KernelCosts {
+            find_ns_per_byte,
+            skip_ns_per_byte,
+            verify_ns_per_byte,
+        })
+    }
+
+    /// Returns the optimizer's cost model for these costs. A raw filter is priced as
+    /// a substring search plus locating the boundaries of the records it reaches.
+    pub fn cost_model(&self) -> CostModel {
+        CostModel {
+            search_ns_per_byte: self.find_ns_per_byte + self.skip_ns_per_byte,
+            verify_ns_per_byte: self.verify_ns_per_byte,
+        }
+    }
+
+    pub fn to_json(&self) -> Value {
+        serde_json::json!({
+            "find_ns_per_byte": self.find_ns_per_byte,
+            "skip_ns_per_byte": self.skip_ns_per_byte,
+            "verify_ns_per_byte": self.verify_ns_per_byte,
+        })
+    }
+
+    pub fn from_json(value: &Value) -> Result<Self> {
+        let field = |name: &str| {
+            value[name]
+                .as_f64()
+                .filter(|cost| *cost >= 0.0)
+                .ok_or_else(|| SparserError::format("calibration", format!("missing {}", name)))
+        };
+        Ok(KernelCosts {
+            find_ns_per_byte: field("find_ns_per_byte")?,
+            skip_ns_per_byte: field("skip_ns_per_byte")?,
+            verify_ns_per_byte: field("verify_ns_per_byte")?,
+        })
+    }
+
+    /// Writes the costs to `path` as JSON.
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, self.to_json().to_string())
+            .context(format!("writing calibration {}", path.display()))
+    }
+
+    /// Reads costs written by `save`.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let context = format!("reading calibration {}", path.display());
+        let text = fs::read_to_string(path).context(context.clone())?;
+        let value = serde_json::from_str(&text)
+            .map_err(|e| SparserError::format("calibration", e.to_string()))
+            .context(context.clone())?;
+        KernelCosts::from_json(&value).context(context)
+    }
+}
+
+/// Returns the time per byte of running `kernel` over `data` `rounds` times. The
+/// kernel's result is kept alive so that the work cannot be optimized away.
+fn time_per_byte<F: FnMut(&[u8]) -> usize>(data: &[u8], rounds: usize, mut kernel: F) -> f64 {
+    let start = Instant::now();
+    for _ in 0..rounds {
+        black_box(kernel(black_box(data)));
+    }
+    let elapsed = start.elapsed();
+    elapsed.as_nanos() as f64 / (data.len() * rounds) as f64
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use optimizer::sample_records;
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..200 {
+            data.extend_from_slice(
+                format!("{{\"id\": {}, \"text\": \"tweet {}\"}}\n", i, i).as_bytes(),
+            );
+        }
+        data
+    }
+
+    fn measure() -> KernelCosts {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 100);
+        KernelCosts::measure_bytes(
+            &sample,
+            |record| serde_json::from_slice::<Value>(record).is_ok(),
+            1 << 16,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_measure() {
+        let costs = measure();
+        for cost in [costs.find_ns_per_byte, costs.skip_ns_per_byte].iter() {
+            assert!(cost.is_finite() && *cost >= 0.0);
+        }
+        // parsing JSON is much slower than searching it
+        assert!(costs.verify_ns_per_byte > costs.find_ns_per_byte);
+        let model = costs.cost_model();
+        assert_eq!(costs.verify_ns_per_byte, model.verify_ns_per_byte);
+        assert!(model.cascade_cost(&[0.01]) < model.cascade_cost(&[]));
+
+        assert!(KernelCosts::measure(&[], |_| true).is_err());
+        assert!(KernelCosts::measure(&[b""], |_| true).is_err());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let costs = measure();
+        let path = env::temp_dir().join(format!("sparser-calibration-{}.json", process::id()));
+        costs.save(&path).unwrap();
+        let loaded = KernelCosts::load(&path);
+        fs::write(&path, "{\"find_ns_per_byte\": 0.1}").unwrap();
+        let invalid = KernelCosts::load(&path);
+        fs::remove_file(&path).unwrap();
+
+        // serde_json may round the last bit of a float it reads back
+        let loaded = loaded.unwrap();
+        let (a, b) = (costs.to_json(), loaded.to_json());
+        for (name, cost) in a.as_object().unwrap() {
+            let cost = cost.as_f64().unwrap();
+            assert!((cost - b[name].as_f64().unwrap()).abs() <= cost * 1e-12);
+        }
+        let e = invalid.unwrap_err();
+        assert!(e.to_string().starts_with("reading calibration"));
+        assert!(e.report().contains("missing skip_ns_per_byte"));
+        assert!(KernelCosts::load(&path).is_err());
+    }
+}
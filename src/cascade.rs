@@ -0,0 +1,271 @@
+use formats::csv::CsvFormat;
+use raw_filter::RawFilter;
+use sparser_kernels;
+
+/// A cascade is an ordered list of raw filters. A record is a candidate if it
+/// passes every filter; filters are evaluated in order, so the cheapest and most
+/// selective ones should come first.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, Default)]
+pub struct Cascade {
+    filters: Vec<RawFilter>,
+}
+
+impl Cascade {
+    pub fn new(filters: Vec<RawFilter>) -> Self {
+        Cascade { filters }
+    }
+
+    /// Returns the filters in evaluation order.
+    pub fn filters(&self) -> &[RawFilter] {
+        &self.filters
+    }
+
+    pub fn len(&self) -> usize {
+        self.filters.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.filters.is_empty()
+    }
+
+    /// Returns whether `record` passes every filter of the cascade.
+    pub fn matches(&self, record: &[u8]) -> bool {
+        self.filters.iter().all(|f| f.matches(record))
+    }
+
+    /// Calls `f` with the offset and bytes of every record in `data` that passes
+    /// the cascade. Records are separated by `delimiter`, which is not part of the
+    /// record, even inside quotes; CSV data whose quoted fields contain newlines
+    /// goes through `for_each_csv_candidate_counted` instead.
+    ///
+    /// The first filter is searched for across record boundaries; only records
+    /// containing one of its matches are located and checked against the rest.
+    pub fn for_each_candidate<'a, F: FnMut(usize, &'a [u8])>(
+        &self,
+        data: &'a [u8],
+        delimiter: u8,
+        f: F,
+    ) {
+        let mut counts = LevelCounts::new(self.len());
+        self.for_each_candidate_counted(data, delimiter, &mut counts, f)
+    }
+
+    /// Like `for_each_candidate`, and adds the records and bytes passing each level
+    /// of the cascade to `counts`.
+    pub fn for_each_candidate_counted<'a, F: FnMut(usize, &'a [u8])>(
+        &self,
+        data: &'a [u8],
+        delimiter: u8,
+        counts: &mut LevelCounts,
+        mut f: F,
+    ) {
+        let (first, rest) = match self.filters.split_first() {
+            Some(split) => split,
+            None => return for_each_record(data, delimiter, f),
+        };
+        counts.resize(self.len());
+
+        // `pos` always points at the start of a record that has not been visited.
+        let mut pos = 0;
+        while pos < data.len() {
+            let m = match first.find(&data[pos..]) {
+                Some(m) => (pos + m.start)..(pos + m.end),
+                None => break,
+            };
+            let start = sparser_kernels::rfind_byte(&data[pos..m.start], delimiter)
+                .map_or(pos, |i| pos + i + 1);
+            let end = sparser_kernels::find_byte(&data[m.start..], delimiter)
+                .map_or(data.len(), |i| m.start + i);
+            pos = end + 1;
+            if m.end > end {
+                continue;
+            }
+            let record = &data[start..end];
+            if !first.find_is_exact() && !first.matches(record) {
+                continue;
+            }
+            counts.add(0, record.len());
+            if passes(rest, 1, record, counts) {
+                f(start, record);
+            }
+        }
+    }
+
+    /// Like `for_each_candidate_counted` for CSV data, whose quoted fields may
+    /// contain newlines. Every record is located with `format` before the filters
+    /// run, so the first filter cannot skip ahead over the data.
+    pub fn for_each_csv_candidate_counted<'a, F: FnMut(usize, &'a [u8])>(
+        &self,
+        format: &CsvFormat,
+        data: &'a [u8],
+        counts: &mut LevelCounts,
+        mut f: F,
+    ) {
+        counts.resize(self.len());
+        format.for_each_record(data, |offset, record| {
+            if passes(&self.filters, 0, record, counts) {
+                f(offset, record);
+            }
+        })
+    }
+
+    /// Returns the number of records in `data` that pass the cascade and `verifier`.
+    pub fn search<F: FnMut(&[u8]) -> bool>(
+        &self,
+        data: &[u8],
+        delimiter: u8,
+        mut verifier: F,
+    ) -> usize {
+        let mut passed = 0;
+        self.for_each_candidate(data, delimiter, |_, record| {
+            if verifier(record) {
+                passed += 1;
+            }
+        });
+        passed
+    }
+}
+
+/// Returns whether `record` passes all of `filters`, which start at cascade level
+/// `level`, counting it at each level it passes.
+fn passes(filters: &[RawFilter], level: usize, record: &[u8], counts: &mut LevelCounts) -> bool {
+    for (i, filter) in filters.iter().enumerate() {
+        if !filter.matches(record) {
+            return false;
+        }
+        counts.add(level + i, record.len());
+    }
+    true
+}
+
+/// Number of records and bytes that passed each level of a cascade during a scan.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct LevelCounts {
+    /// `records[i]` is the number of records passing the first `i + 1` filters.
+    pub records: Vec<u64>,
+    /// `bytes[i]` is the total length of the records counted in `records[i]`.
+    pub bytes: Vec<u64>,
+}
+
+impl LevelCounts {
+    pub fn new(levels: usize) -> Self {
+        LevelCounts {
+            records: vec![0; levels],
+            bytes: vec![0; levels],
+        }
+    }
+
+    fn resize(&mut self, levels: usize) {
+        self.records.resize(levels, 0);
+        self.bytes.resize(levels, 0);
+    }
+
+    #[inline]
+    fn add(&mut self, level: usize, len: usize) {
+        self.records[level] += 1;
+        self.bytes[level] += len as u64;
+    }
+
+    /// Adds the counts of `other`, e.g. from another partition of the same scan.
+    pub fn merge(&mut self, other: &LevelCounts) {
+        self.resize(self.records.len().max(other.records.len()));
+        for (i, (records, bytes)) in other.records.iter().zip(&other.bytes).enumerate() {
+            self.records[i] += records;
+            self.bytes[i] += bytes;
+        }
+    }
+}
+
+/// Calls `f` with the offset and bytes of every record in `data`. A trailing
+/// delimiter does not start another record.
+pub fn for_each_record<'a, F: FnMut(usize, &'a [u8])>(data: &'a [u8], delimiter: u8, mut f: F) {
+    let mut pos = 0;
+    while pos < data.len() {
+        let end =
+            sparser_kernels::find_byte(&data[pos..], delimiter).map_or(data.len(), |i| pos + i);
+        f(pos, &data[pos..end]);
+        pos = end + 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"{\"text\": \"rust is fast\", \"lang\": \"en\"}\n\
+{\"text\": \"c is fast\", \"lang\": \"en\"}\n\
+{\"text\": \"rust ist schnell\", \"lang\": \"de\"}\n\
+{\"text\": \"rust\\nrust\", \"lang\": \"en\"}";
+
+    fn candidates(cascade: &Cascade, data: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        cascade.for_each_candidate(data, b'\n', |offset, _| offsets.push(offset));
+        offsets
+    }
+
+    fn naive(cascade: &Cascade, data: &[u8]) -> Vec<usize> {
+        let mut offsets = Vec::new();
+        for_each_record(data, b'\n', |offset, record| {
+            if cascade.matches(record) {
+                offsets.push(offset);
+            }
+        });
+        offsets
+    }
+
+    #[test]
+    fn test_for_each_record() {
+        let mut records = Vec::new();
+        for_each_record(b"a\n\nbc\n", b'\n', |offset, r| {
+            records.push((offset, r.to_vec()))
+        });
+        assert_eq!(
+            vec![(0, b"a".to_vec()), (2, vec![]), (3, b"bc".to_vec())],
+            records
+        );
+    }
+
+    #[test]
+    fn test_empty_cascade_passes_every_record() {
+        assert_eq!(vec![0, 39, 75, 118], candidates(&Cascade::default(), DATA));
+    }
+
+    #[test]
+    fn test_cascade_candidates() {
+        let cascade = Cascade::new(vec![
+            RawFilter::substring("rust"),
+            RawFilter::substring("\"en\""),
+        ]);
+        assert_eq!(vec![0, 118], candidates(&cascade, DATA));
+        assert_eq!(naive(&cascade, DATA), candidates(&cascade, DATA));
+
+        let cascade = Cascade::new(vec![RawFilter::substring("fast")]);
+        assert_eq!(
+            1,
+            cascade.search(DATA, b'\n', |r| r.starts_with(b"{\"text\": \"c"))
+        );
+    }
+
+    #[test]
+    fn test_level_counts() {
+        let cascade = Cascade::new(vec![
+            RawFilter::substring("rust"),
+            RawFilter::substring("\"en\""),
+        ]);
+        let mut counts = LevelCounts::new(0);
+        cascade.for_each_candidate_counted(DATA, b'\n', &mut counts, |_, _| {});
+        assert_eq!(vec![3, 2], counts.records);
+        assert_eq!(vec![38 + 42 + 36, 38 + 36], counts.bytes);
+
+        let mut total = LevelCounts::new(0);
+        total.merge(&counts);
+        total.merge(&counts);
+        assert_eq!(vec![6, 4], total.records);
+    }
+
+    #[test]
+    fn test_match_spanning_records_is_ignored() {
+        let cascade = Cascade::new(vec![RawFilter::substring("a\nb")]);
+        assert!(candidates(&cascade, b"xa\nbx\n").is_empty());
+    }
+}
@@ -0,0 +1,232 @@
+use std::fmt;
+use std::io::{self, BufReader, Chain, Cursor, ErrorKind, Read};
+
+use flate2::read::MultiGzDecoder;
+use lz4_flex::frame::FrameDecoder;
+use zstd::stream::read::Decoder as ZstdDecoder;
+
+use utils::error::Result;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+const LZ4_MAGIC: &[u8] = &[0x04, 0x22, 0x4d, 0x18];
+
+/// Longest magic number that is looked for.
+const MAGIC_LEN: usize = 4;
+
+/// Compression format of an input stream.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Compression {
+    None,
+    Gzip,
+    Zstd,
+    /// The LZ4 frame format.
+    Lz4,
+}
+
+impl Compression {
+    /// Detects the compression format from the first bytes of a stream.
+    pub fn detect(prefix: &[u8]) -> Self {
+        if prefix.starts_with(GZIP_MAGIC) {
+            Compression::Gzip
+        } else if prefix.starts_with(ZSTD_MAGIC) {
+            Compression::Zstd
+        } else if prefix.starts_with(LZ4_MAGIC) {
+            Compression::Lz4
+        } else {
+            Compression::None
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        };
+        f.write_str(name)
+    }
+}
+
+/// The stream with the bytes read for detection put back in front.
+type Peeked<R> = Chain<Cursor<Vec<u8>>, R>;
+
+enum Inner<R: Read> {
+    None(Peeked<R>),
+    Gzip(MultiGzDecoder<Peeked<R>>),
+    Zstd(ZstdDecoder<'static, BufReader<Peeked<R>>>),
+    Lz4(FrameDecoder<Peeked<R>>),
+}
+
+/// Decompressor transparently decompresses a stream whose format is detected from
+/// its magic number; streams in no known format are passed through unchanged.
+///
+/// Data is decompressed incrementally as it is read, so wrapping the decompressor in
+/// a `ChunkReader` decompresses block by block straight into the aligned chunk
+/// buffer, and raw filters run on each chunk without the whole input ever being
+/// materialized. Offsets reported by the scanners are then offsets into the
+/// decompressed stream.
+pub struct Decompressor<R: Read> {
+    inner: Inner<R>,
+    compression: Compression,
+}
+
+impl<R: Read> Decompressor<R> {
+    /// Reads the magic number of `reader` and sets up the matching decoder.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut prefix = Vec::with_capacity(MAGIC_LEN);
+        while prefix.len() < MAGIC_LEN {
+            let mut buf = [0u8; MAGIC_LEN];
+            match reader.read(&mut buf[..MAGIC_LEN - prefix.len()]) {
+                Ok(0) => break,
+                Ok(n) => prefix.extend_from_slice(&buf[..n]),
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        let compression = Compression::detect(&prefix);
+        let peeked = Cursor::new(prefix).chain(reader);
+        let inner = match compression {
+            Compression::None => Inner::None(peeked),
+            Compression::Gzip => Inner::Gzip(MultiGzDecoder::new(peeked)),
+            Compression::Zstd => Inner::Zstd(ZstdDecoder::new(peeked)?),
+            Compression::Lz4 => Inner::Lz4(FrameDecoder::new(peeked)),
+        };
+        Ok(Decompressor { inner, compression })
+    }
+
+    /// Returns the detected compression format.
+    pub fn compression(&self) -> Compression {
+        self.compression
+    }
+}
+
+impl<R: Read> Read for Decompressor<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self.inner {
+            Inner::None(ref mut r) => r.read(buf),
+            Inner::Gzip(ref mut r) => r.read(buf),
+            Inner::Zstd(ref mut r) => r.read(buf),
+            Inner::Lz4(ref mut r) => r.read(buf),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::write::GzEncoder;
+    use lz4_flex::frame::FrameEncoder;
+
+    use super::*;
+    use cascade::Cascade;
+    use raw_filter::RawFilter;
+    use stream::{ChunkReader, StreamScanner};
+    use utils::memory::AllocationMode;
+
+    fn logs(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..n {
+            let status = if i % 7 == 0 { 500 } else { 200 };
+            data.extend_from_slice(
+                format!("10.0.0.{} GET /index/{} {} 512\n", i % 256, i, status).as_bytes(),
+            );
+        }
+        data
+    }
+
+    fn compress(data: &[u8], compression: Compression) -> Vec<u8> {
+        match compression {
+            Compression::None => data.to_vec(),
+            Compression::Gzip => {
+                let mut encoder = GzEncoder::new(Vec::new(), flate2::Compression::default());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+            Compression::Zstd => zstd::encode_all(data, 3).unwrap(),
+            Compression::Lz4 => {
+                let mut encoder = FrameEncoder::new(Vec::new());
+                encoder.write_all(data).unwrap();
+                encoder.finish().unwrap()
+            }
+        }
+    }
+
+    fn scan(compressed: &[u8]) -> (Compression, Vec<u64>) {
+        let decompressor = Decompressor::new(compressed).unwrap();
+        let compression = decompressor.compression();
+        let chunks =
+            ChunkReader::with_chunk_size(decompressor, b'\n', 4096, AllocationMode::Default)
+                .unwrap();
+        let cascade = Cascade::new(vec![RawFilter::substring(" 500 ")]);
+        let mut scanner = StreamScanner::with_chunk_reader(chunks, cascade);
+        let mut offsets = Vec::new();
+        scanner
+            .search(|offset, _| {
+                offsets.push(offset);
+                true
+            })
+            .unwrap();
+        (compression, offsets)
+    }
+
+    #[test]
+    fn test_detect() {
+        assert_eq!(Compression::Gzip, Compression::detect(&[0x1f, 0x8b, 8]));
+        assert_eq!(
+            Compression::Zstd,
+            Compression::detect(&[0x28, 0xb5, 0x2f, 0xfd, 0])
+        );
+        assert_eq!(
+            Compression::Lz4,
+            Compression::detect(&[4, 0x22, 0x4d, 0x18])
+        );
+        assert_eq!(Compression::None, Compression::detect(b"\x1f"));
+        assert_eq!(Compression::None, Compression::detect(b"{\"id\": 1}"));
+    }
+
+    #[test]
+    fn test_scan_compressed_streams() {
+        let data = logs(5000);
+        let (_, expected) = scan(&data);
+        assert_eq!(715, expected.len());
+        for &compression in [Compression::Gzip, Compression::Zstd, Compression::Lz4].iter() {
+            let compressed = compress(&data, compression);
+            assert!(compressed.len() < data.len() / 2);
+            assert_eq!((compression, expected.clone()), scan(&compressed));
+        }
+    }
+
+    #[test]
+    fn test_concatenated_gzip_members() {
+        let data = logs(100);
+        let (first, second) = data.split_at(data.len() / 2);
+        let mut compressed = compress(first, Compression::Gzip);
+        compressed.extend(compress(second, Compression::Gzip));
+        let mut out = Vec::new();
+        Decompressor::new(&compressed[..])
+            .unwrap()
+            .read_to_end(&mut out)
+            .unwrap();
+        assert_eq!(data, out);
+    }
+
+    #[test]
+    fn test_short_and_corrupt_input() {
+        let mut out = Vec::new();
+        let mut decompressor = Decompressor::new(&b"ab"[..]).unwrap();
+        assert_eq!(Compression::None, decompressor.compression());
+        decompressor.read_to_end(&mut out).unwrap();
+        assert_eq!(b"ab".to_vec(), out);
+
+        let mut corrupt = compress(&logs(100), Compression::Zstd);
+        let len = corrupt.len();
+        corrupt.truncate(len / 2);
+        let mut decompressor = Decompressor::new(&corrupt[..]).unwrap();
+        assert!(decompressor.read_to_end(&mut Vec::new()).is_err());
+    }
+}
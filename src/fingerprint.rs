@@ -0,0 +1,261 @@
+//! Hashed fingerprints for finding any of a large set of literals.
+//!
+//! The first `width` bytes (at most four) of every literal are hashed into a bit
+//! table. A scan hashes the window starting at each position of the input, eight
+//! positions at a time with AVX2, and only where the window's bit is set compares
+//! the input against the literals sharing that prefix. The table holds many bits
+//! per literal, so most positions are rejected by one table lookup.
+
+use std::arch::x86_64::*;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
+
+/// Longest literal prefix that is hashed.
+pub const MAX_WIDTH: usize = 4;
+
+/// Table bits per literal; more bits mean fewer positions to compare.
+const BITS_PER_LITERAL: usize = 64;
+const MIN_TABLE_BITS: u32 = 10;
+const MAX_TABLE_BITS: u32 = 22;
+
+/// Multiplier of the window hash (the golden ratio), whose top bits index the table.
+const HASH_MULTIPLIER: u32 = 0x9e37_79b1;
+
+/// A set of literals with a hashed fingerprint table of their prefixes.
+///
+/// Literals must not contain the record delimiter of the data they are searched in.
+#[derive(Debug, Clone)]
+pub struct Fingerprint {
+    /// Sorted and deduplicated, so literals sharing a prefix are adjacent.
+    literals: Vec<Vec<u8>>,
+    width: usize,
+    shift: u32,
+    table: Vec<u32>,
+}
+
+impl Fingerprint {
+    /// Creates the fingerprint of `literals`, ignoring empty ones.
+    pub fn new<T: AsRef<[u8]>>(literals: &[T]) -> Self {
+        let mut literals: Vec<Vec<u8>> = literals
+            .iter()
+            .map(|l| l.as_ref().to_vec())
+            .filter(|l| !l.is_empty())
+            .collect();
+        literals.sort();
+        literals.dedup();
+        let width = literals
+            .iter()
+            .map(|l| l.len())
+            .min()
+            .unwrap_or(1)
+            .min(MAX_WIDTH);
+        let table_bits = (literals.len() * BITS_PER_LITERAL)
+            .next_power_of_two()
+            .trailing_zeros()
+            .clamp(MIN_TABLE_BITS, MAX_TABLE_BITS);
+        let mut fingerprint = Fingerprint {
+            literals,
+            width,
+            shift: 32 - table_bits,
+            table: vec![0; 1 << (table_bits - 5)],
+        };
+        for i in 0..fingerprint.literals.len() {
+            let h = fingerprint.hash(window(&fingerprint.literals[i], width));
+            fingerprint.table[h as usize >> 5] |= 1 << (h & 31);
+        }
+        fingerprint
+    }
+
+    /// Returns the literals, sorted.
+    pub fn literals(&self) -> &[Vec<u8>] {
+        &self.literals
+    }
+
+    /// Returns the number of leading bytes of each literal that are hashed.
+    pub fn width(&self) -> usize {
+        self.width
+    }
+
+    /// Returns the byte range of the first occurrence of any literal in `data`; of
+    /// the literals starting there, the shortest.
+    pub fn find(&self, data: &[u8]) -> Option<Range<usize>> {
+        if self.literals.is_empty() || data.len() < self.width {
+            return None;
+        }
+        if is_x86_feature_detected!("avx2") {
+            unsafe { self.find_avx2(data) }
+        } else {
+            self.find_scalar(data, 0)
+        }
+    }
+
+    #[inline]
+    fn hash(&self, window: u32) -> u32 {
+        window.wrapping_mul(HASH_MULTIPLIER) >> self.shift
+    }
+
+    #[inline]
+    fn is_set(&self, h: u32) -> bool {
+        self.table[h as usize >> 5] & (1 << (h & 31)) != 0
+    }
+
+    /// Returns the length of the shortest literal starting at `data[i..]`.
+    fn literal_at(&self, data: &[u8], i: usize) -> Option<usize> {
+        let prefix = &data[i..i + self.width];
+        let first = self.literals.partition_point(|l| &l[..self.width] < prefix);
+        self.literals[first..]
+            .iter()
+            .take_while(|l| &l[..self.width] == prefix)
+            .filter(|l| data[i..].starts_with(l))
+            .map(|l| l.len())
+            .min()
+    }
+
+    fn find_scalar(&self, data: &[u8], from: usize) -> Option<Range<usize>> {
+        for i in from..data.len() + 1 - self.width {
+            if self.is_set(self.hash(window(&data[i..], self.width))) {
+                if let Some(len) = self.literal_at(data, i) {
+                    return Some(i..i + len);
+                }
+            }
+        }
+        None
+    }
+
+    /// Hashes the windows at eight positions at once: 16 input bytes are loaded
+    /// into both lanes, shuffled into eight overlapping 32-bit windows, masked to
+    /// `width` bytes, hashed and looked up in the table with a gather.
+    #[target_feature(enable = "avx2")]
+    unsafe fn find_avx2(&self, data: &[u8]) -> Option<Range<usize>> {
+        let windows = _mm256_setr_epi8(
+            0, 1, 2, 3, 1, 2, 3, 4, 2, 3, 4, 5, 3, 4, 5, 6, //
+            4, 5, 6, 7, 5, 6, 7, 8, 6, 7, 8, 9, 7, 8, 9, 10,
+        );
+        let width_mask = _mm256_set1_epi32((u64::MAX >> (64 - 8 * self.width)) as i32);
+        let multiplier = _mm256_set1_epi32(HASH_MULTIPLIER as i32);
+        let shift = _mm_cvtsi32_si128(self.shift as i32);
+        let low_bits = _mm256_set1_epi32(31);
+        let table = self.table.as_ptr() as *const i32;
+
+        let mut i = 0;
+        while i + 16 <= data.len() {
+            let bytes = _mm_loadu_si128(data.as_ptr().add(i) as *const __m128i);
+            let block = _mm256_shuffle_epi8(_mm256_broadcastsi128_si256(bytes), windows);
+            let hashes = _mm256_srl_epi32(
+                _mm256_mullo_epi32(_mm256_and_si256(block, width_mask), multiplier),
+                shift,
+            );
+            let words = _mm256_i32gather_epi32(table, _mm256_srli_epi32(hashes, 5), 4);
+            let bits = _mm256_srlv_epi32(words, _mm256_and_si256(hashes, low_bits));
+            let mut mask = _mm256_movemask_ps(_mm256_castsi256_ps(_mm256_slli_epi32(bits, 31)));
+            while mask != 0 {
+                let j = i + mask.trailing_zeros() as usize;
+                if let Some(len) = self.literal_at(data, j) {
+                    return Some(j..j + len);
+                }
+                mask &= mask - 1;
+            }
+            i += 8;
+        }
+        self.find_scalar(data, i)
+    }
+}
+
+/// Returns the first `width` bytes of `data` as a little-endian number.
+#[inline]
+fn window(data: &[u8], width: usize) -> u32 {
+    let mut bytes = [0u8; 4];
+    bytes[..width].copy_from_slice(&data[..width]);
+    u32::from_le_bytes(bytes)
+}
+
+impl PartialEq for Fingerprint {
+    fn eq(&self, other: &Fingerprint) -> bool {
+        self.literals == other.literals
+    }
+}
+
+impl Eq for Fingerprint {}
+
+/// The table is derived from the literals, so they alone identify a fingerprint.
+impl Hash for Fingerprint {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        self.literals.hash(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    fn naive(literals: &[Vec<u8>], data: &[u8]) -> Option<Range<usize>> {
+        (0..data.len()).find_map(|i| {
+            literals
+                .iter()
+                .filter(|l| data[i..].starts_with(l))
+                .map(|l| i..i + l.len())
+                .min_by_key(|r| r.end)
+        })
+    }
+
+    #[test]
+    fn test_find() {
+        let fingerprint = Fingerprint::new(&["\"#rust\"", "\"#go\"", "\"#rustlang\"", ""]);
+        assert_eq!(3, fingerprint.literals().len());
+        assert_eq!(4, fingerprint.width());
+        let data = b"{\"tags\": [\"#java\", \"#rustlang\", \"#go\"]}";
+        assert_eq!(Some(19..30), fingerprint.find(data));
+        assert_eq!(
+            Some(32..37),
+            fingerprint
+                .find(&data[20..])
+                .map(|r| r.start + 20..r.end + 20)
+        );
+        assert_eq!(None, fingerprint.find(b"{\"tags\": [\"#java\"]}"));
+        assert_eq!(None, fingerprint.find(b"\"#"));
+        assert_eq!(None, Fingerprint::new::<&str>(&[]).find(b"anything"));
+
+        let short = Fingerprint::new(&["ab", "xyz"]);
+        assert_eq!(2, short.width());
+        assert_eq!(Some(3..5), short.find(b"xyaab"));
+    }
+
+    #[test]
+    fn test_matches_naive_search() {
+        let mut rng = StdRng::seed_from_u64(46);
+        let alphabet = b"abcd\"#";
+        for _ in 0..50 {
+            // the shortest literal sets the window width, so vary it per set
+            let shortest = rng.gen_range(1, 6);
+            let count = rng.gen_range(1, 300);
+            let literals: Vec<Vec<u8>> = (0..count)
+                .map(|_| {
+                    let len = rng.gen_range(shortest, 16);
+                    (0..len).map(|_| *rng.choose(alphabet).unwrap()).collect()
+                })
+                .collect();
+            let fingerprint = Fingerprint::new(&literals);
+            let data: Vec<u8> = (0..rng.gen_range(0, 200))
+                .map(|_| *rng.choose(alphabet).unwrap())
+                .collect();
+            assert_eq!(naive(&literals, &data), fingerprint.find(&data));
+            // the scalar tail, as the vector loop hands it over at any offset
+            for from in 0..(data.len() + 1).saturating_sub(fingerprint.width()) {
+                let expected =
+                    naive(&literals, &data[from..]).map(|r| r.start + from..r.end + from);
+                assert_eq!(expected, fingerprint.find_scalar(&data, from));
+            }
+        }
+    }
+
+    #[test]
+    fn test_equality_ignores_order() {
+        let a = Fingerprint::new(&["b", "a", "a"]);
+        let b = Fingerprint::new(&["a", "b"]);
+        assert_eq!(a, b);
+        assert!(a != Fingerprint::new(&["a"]));
+    }
+}
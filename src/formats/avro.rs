@@ -0,0 +1,909 @@
+use std::collections::HashMap;
+
+use serde_json;
+
+use cascade::Cascade;
+use raw_filter::RawFilter;
+use sparser_kernels;
+use utils::buffer::Buffer;
+use utils::error::{Result, SparserError};
+
+const MAGIC: &[u8] = b"Obj\x01";
+const SYNC_SIZE: usize = 16;
+
+/// Most items a block may hold when they take no bytes, e.g. an array of nulls.
+/// Other blocks cannot hold more items than they have bytes left.
+const MAX_EMPTY_ITEMS: u64 = 1 << 20;
+
+/// An Avro schema.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Schema {
+    Null,
+    Boolean,
+    Int,
+    Long,
+    Float,
+    Double,
+    Bytes,
+    String,
+    Record {
+        name: String,
+        fields: Vec<(String, Schema)>,
+    },
+    Enum {
+        name: String,
+        symbols: Vec<String>,
+    },
+    Array(Box<Schema>),
+    Map(Box<Schema>),
+    Union(Vec<Schema>),
+    Fixed {
+        name: String,
+        size: usize,
+    },
+}
+
+impl Schema {
+    /// Parses a schema from its JSON representation.
+    pub fn parse(json: &str) -> Result<Schema> {
+        let value: serde_json::Value = serde_json::from_str(json)
+            .map_err(|e| SparserError::format("avro", format!("invalid schema: {}", e)))?;
+        Schema::from_json(&value, &mut HashMap::new())
+    }
+
+    fn from_json(value: &serde_json::Value, named: &mut HashMap<String, Schema>) -> Result<Schema> {
+        use serde_json::Value as Json;
+        match *value {
+            Json::String(ref name) => Schema::from_name(name, named),
+            Json::Array(ref branches) => Ok(Schema::Union(
+                branches
+                    .iter()
+                    .map(|b| Schema::from_json(b, named))
+                    .collect::<Result<_>>()?,
+            )),
+            Json::Object(ref object) => {
+                let kind = object.get("type").and_then(|t| t.as_str()).ok_or_else(|| {
+                    SparserError::format("avro", "schema object without a type name")
+                })?;
+                let name = || -> Result<String> {
+                    object
+                        .get("name")
+                        .and_then(|n| n.as_str())
+                        .map(|n| n.to_string())
+                        .ok_or_else(|| SparserError::format("avro", "named schema without name"))
+                };
+                let schema = match kind {
+                    "record" | "error" => {
+                        let name = name()?;
+                        let fields = object
+                            .get("fields")
+                            .and_then(|f| f.as_array())
+                            .ok_or_else(|| SparserError::format("avro", "record without fields"))?;
+                        let mut parsed = Vec::with_capacity(fields.len());
+                        for field in fields {
+                            let field_name =
+                                field.get("name").and_then(|n| n.as_str()).ok_or_else(|| {
+                                    SparserError::format("avro", "record field without name")
+                                })?;
+                            let field_type = field.get("type").ok_or_else(|| {
+                                SparserError::format("avro", "record field without type")
+                            })?;
+                            parsed.push((
+                                field_name.to_string(),
+                                Schema::from_json(field_type, named)?,
+                            ));
+                        }
+                        Schema::Record {
+                            name,
+                            fields: parsed,
+                        }
+                    }
+                    "enum" => Schema::Enum {
+                        name: name()?,
+                        symbols: object
+                            .get("symbols")
+                            .and_then(|s| s.as_array())
+                            .map(|s| {
+                                s.iter()
+                                    .filter_map(|s| s.as_str().map(|s| s.to_string()))
+                                    .collect()
+                            })
+                            .unwrap_or_default(),
+                    },
+                    "fixed" => Schema::Fixed {
+                        name: name()?,
+                        size: object.get("size").and_then(|s| s.as_u64()).ok_or_else(|| {
+                            SparserError::format("avro", "fixed schema without size")
+                        })? as usize,
+                    },
+                    "array" => Schema::Array(Box::new(Schema::from_json(
+                        object.get("items").ok_or_else(|| {
+                            SparserError::format("avro", "array schema without items")
+                        })?,
+                        named,
+                    )?)),
+                    "map" => Schema::Map(Box::new(Schema::from_json(
+                        object.get("values").ok_or_else(|| {
+                            SparserError::format("avro", "map schema without values")
+                        })?,
+                        named,
+                    )?)),
+                    primitive => Schema::from_name(primitive, named)?,
+                };
+                match schema {
+                    Schema::Record { ref name, .. }
+                    | Schema::Enum { ref name, .. }
+                    | Schema::Fixed { ref name, .. } => {
+                        named.insert(name.clone(), schema.clone());
+                    }
+                    _ => {}
+                }
+                Ok(schema)
+            }
+            _ => Err(SparserError::format("avro", "invalid schema")),
+        }
+    }
+
+    /// Returns whether a datum of this schema is encoded in zero bytes.
+    pub fn takes_no_bytes(&self) -> bool {
+        match *self {
+            Schema::Null => true,
+            Schema::Record { ref fields, .. } => fields.iter().all(|(_, f)| f.takes_no_bytes()),
+            Schema::Fixed { size, .. } => size == 0,
+            _ => false,
+        }
+    }
+
+    fn from_name(name: &str, named: &HashMap<String, Schema>) -> Result<Schema> {
+        Ok(match name {
+            "null" => Schema::Null,
+            "boolean" => Schema::Boolean,
+            "int" => Schema::Int,
+            "long" => Schema::Long,
+            "float" => Schema::Float,
+            "double" => Schema::Double,
+            "bytes" => Schema::Bytes,
+            "string" => Schema::String,
+            other => named.get(other).cloned().ok_or_else(|| {
+                SparserError::format("avro", format!("unknown type name {}", other))
+            })?,
+        })
+    }
+
+    /// Returns the schema of the field at the dot-separated `path` of a record
+    /// schema. Unions are looked through.
+    pub fn field(&self, path: &str) -> Option<&Schema> {
+        let mut schema = self;
+        for name in path.split('.') {
+            schema = schema.record_field(name)?;
+        }
+        Some(schema)
+    }
+
+    fn record_field(&self, name: &str) -> Option<&Schema> {
+        match *self {
+            Schema::Record { ref fields, .. } => fields.iter().find(|f| f.0 == name).map(|f| &f.1),
+            Schema::Union(ref branches) => branches.iter().find_map(|b| b.record_field(name)),
+            _ => None,
+        }
+    }
+}
+
+/// A decoded Avro datum.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Null,
+    Boolean(bool),
+    Int(i32),
+    Long(i64),
+    Float(f32),
+    Double(f64),
+    Bytes(Vec<u8>),
+    String(String),
+    Record(Vec<(String, Value)>),
+    Enum(usize, String),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+    Fixed(Vec<u8>),
+}
+
+impl Value {
+    /// Returns the value of the field at the dot-separated `path` of a record.
+    pub fn field(&self, path: &str) -> Option<&Value> {
+        let mut value = self;
+        for name in path.split('.') {
+            value = match *value {
+                Value::Record(ref fields) => fields.iter().find(|f| f.0 == name).map(|f| &f.1)?,
+                _ => return None,
+            };
+        }
+        Some(value)
+    }
+
+    fn as_i64(&self) -> Option<i64> {
+        match *self {
+            Value::Int(i) => Some(i as i64),
+            Value::Long(l) => Some(l),
+            _ => None,
+        }
+    }
+
+    fn as_bytes(&self) -> Option<&[u8]> {
+        match *self {
+            Value::String(ref s) => Some(s.as_bytes()),
+            Value::Bytes(ref b) | Value::Fixed(ref b) => Some(b),
+            Value::Enum(_, ref s) => Some(s.as_bytes()),
+            _ => None,
+        }
+    }
+}
+
+/// Reads Avro's binary encoding.
+pub struct Decoder<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Decoder<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Decoder { data, pos: 0 }
+    }
+
+    /// Returns the number of bytes consumed so far.
+    pub fn position(&self) -> usize {
+        self.pos
+    }
+
+    fn error<M: Into<String>>(&self, message: M) -> SparserError {
+        SparserError::format("avro", message).at_offset(self.pos as u64)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+        if self.data.len() - self.pos < n {
+            return Err(self.error("unexpected end of data"));
+        }
+        let bytes = &self.data[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(bytes)
+    }
+
+    /// Reads a zig-zag encoded variable length long.
+    pub fn read_long(&mut self) -> Result<i64> {
+        let mut value: u64 = 0;
+        let mut shift = 0;
+        loop {
+            let b = self.take(1)?[0];
+            if shift >= 64 {
+                return Err(self.error("varint is too long"));
+            }
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                break;
+            }
+            shift += 7;
+        }
+        Ok((value >> 1) as i64 ^ -((value & 1) as i64))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8]> {
+        let len = self.read_long()?;
+        if len < 0 {
+            return Err(self.error("negative length"));
+        }
+        self.take(len as usize)
+    }
+
+    pub fn read_string(&mut self) -> Result<String> {
+        let start = self.pos;
+        let bytes = self.read_bytes()?;
+        String::from_utf8(bytes.to_vec()).map_err(|_| {
+            SparserError::format("avro", "invalid utf-8 in string").at_offset(start as u64)
+        })
+    }
+
+    /// Reads the blocks of an array or map, calling `item` once per item.
+    /// `empty_items` tells whether items may take no bytes.
+    fn read_blocks<F>(&mut self, empty_items: bool, mut item: F) -> Result<()>
+    where
+        F: FnMut(&mut Self) -> Result<()>,
+    {
+        loop {
+            let mut count = self.read_long()?;
+            if count == 0 {
+                return Ok(());
+            }
+            if count < 0 {
+                // a negative count is followed by the block size in bytes
+                count = count
+                    .checked_neg()
+                    .ok_or_else(|| self.error("block count out of range"))?;
+                self.read_long()?;
+            }
+            if count as u64 > max_items(empty_items, self.data.len() - self.pos) {
+                return Err(self.error("block count exceeds the remaining data"));
+            }
+            for _ in 0..count {
+                item(self)?;
+            }
+        }
+    }
+
+    /// Reads a datum of type `schema`.
+    pub fn read_value(&mut self, schema: &Schema) -> Result<Value> {
+        Ok(match *schema {
+            Schema::Null => Value::Null,
+            Schema::Boolean => Value::Boolean(self.take(1)?[0] != 0),
+            Schema::Int => Value::Int(self.read_long()? as i32),
+            Schema::Long => Value::Long(self.read_long()?),
+            Schema::Float => {
+                let mut b = [0; 4];
+                b.copy_from_slice(self.take(4)?);
+                Value::Float(f32::from_le_bytes(b))
+            }
+            Schema::Double => {
+                let mut b = [0; 8];
+                b.copy_from_slice(self.take(8)?);
+                Value::Double(f64::from_le_bytes(b))
+            }
+            Schema::Bytes => Value::Bytes(self.read_bytes()?.to_vec()),
+            Schema::String => Value::String(self.read_string()?),
+            Schema::Record { ref fields, .. } => {
+                let mut values = Vec::with_capacity(fields.len());
+                for (name, schema) in fields {
+                    values.push((name.clone(), self.read_value(schema)?));
+                }
+                Value::Record(values)
+            }
+            Schema::Enum { ref symbols, .. } => {
+                let index = self.read_long()? as usize;
+                let symbol = symbols
+                    .get(index)
+                    .ok_or_else(|| self.error("enum index out of range"))?;
+                Value::Enum(index, symbol.clone())
+            }
+            Schema::Array(ref items) => {
+                let mut values = Vec::new();
+                self.read_blocks(items.takes_no_bytes(), |d| {
+                    values.push(d.read_value(items)?);
+                    Ok(())
+                })?;
+                Value::Array(values)
+            }
+            Schema::Map(ref items) => {
+                let mut values = Vec::new();
+                // every entry starts with its key
+                self.read_blocks(false, |d| {
+                    let key = d.read_string()?;
+                    values.push((key, d.read_value(items)?));
+                    Ok(())
+                })?;
+                Value::Map(values)
+            }
+            Schema::Union(ref branches) => {
+                let index = self.read_long()?;
+                let branch = branches
+                    .get(index as usize)
+                    .ok_or_else(|| self.error("union index out of range"))?;
+                self.read_value(branch)?
+            }
+            Schema::Fixed { size, .. } => Value::Fixed(self.take(size)?.to_vec()),
+        })
+    }
+}
+
+/// Returns the most items a block with `remaining` bytes left can hold.
+fn max_items(empty_items: bool, remaining: usize) -> u64 {
+    if empty_items {
+        MAX_EMPTY_ITEMS
+    } else {
+        remaining as u64
+    }
+}
+
+/// Appends the zig-zag variable length encoding of `value` to `out`.
+pub fn encode_long(value: i64, out: &mut Vec<u8>) {
+    let mut n = ((value << 1) ^ (value >> 63)) as u64;
+    while n >= 0x80 {
+        out.push((n as u8 & 0x7f) | 0x80);
+        n >>= 7;
+    }
+    out.push(n as u8);
+}
+
+/// A block of records in an object container file.
+#[derive(Debug, Clone)]
+pub struct Block {
+    /// Offset of the block's record data in the file.
+    pub offset: u64,
+    /// Number of records in the block.
+    pub count: u64,
+    /// The encoded records.
+    pub data: Buffer,
+}
+
+/// AvroReader reads an Avro object container file held in memory.
+///
+/// Raw filters are run over the encoded bytes of each block, where string values
+/// appear verbatim after their length. Records are only decoded for blocks that
+/// pass the cascade.
+#[derive(Debug)]
+pub struct AvroReader<'a> {
+    data: &'a [u8],
+    schema: Schema,
+    metadata: HashMap<String, Vec<u8>>,
+    sync: [u8; SYNC_SIZE],
+    /// Offset of the first block.
+    body: usize,
+}
+
+impl<'a> AvroReader<'a> {
+    /// Reads the file header: magic, metadata and sync marker.
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if !data.starts_with(MAGIC) {
+            return Err(SparserError::format("avro", "not an object container file").at_offset(0));
+        }
+        let mut decoder = Decoder::new(&data[MAGIC.len()..]);
+        let mut metadata = HashMap::new();
+        decoder.read_blocks(false, |d| {
+            let key = d.read_string()?;
+            let value = d.read_bytes()?.to_vec();
+            metadata.insert(key, value);
+            Ok(())
+        })?;
+        let mut sync = [0; SYNC_SIZE];
+        sync.copy_from_slice(decoder.take(SYNC_SIZE)?);
+        let body = MAGIC.len() + decoder.position();
+
+        match metadata.get("avro.codec").map(|c| c.as_slice()) {
+            None | Some(b"null") => {}
+            Some(codec) => {
+                return Err(SparserError::format(
+                    "avro",
+                    format!("unsupported codec {}", String::from_utf8_lossy(codec)),
+                ))
+            }
+        }
+        let schema = metadata
+            .get("avro.schema")
+            .ok_or_else(|| SparserError::format("avro", "file has no schema"))?;
+        let schema = Schema::parse(::std::str::from_utf8(schema)?)?;
+        Ok(AvroReader {
+            data,
+            schema,
+            metadata,
+            sync,
+            body,
+        })
+    }
+
+    pub fn schema(&self) -> &Schema {
+        &self.schema
+    }
+
+    pub fn metadata(&self, key: &str) -> Option<&[u8]> {
+        self.metadata.get(key).map(|v| v.as_slice())
+    }
+
+    /// Returns the data blocks of the file.
+    pub fn blocks(&self) -> Result<Vec<Block>> {
+        let mut blocks = Vec::new();
+        let mut pos = self.body;
+        while pos < self.data.len() {
+            let mut decoder = Decoder::new(&self.data[pos..]);
+            let header = decoder
+                .read_long()
+                .and_then(|count| Ok((count, decoder.read_long()?)));
+            let (count, size) = header.map_err(|e| e.at_offset(pos as u64))?;
+            if count < 0 || size < 0 {
+                return Err(
+                    SparserError::format("avro", "negative block header").at_offset(pos as u64)
+                );
+            }
+            let start = pos + decoder.position();
+            let end = match start.checked_add(size as usize) {
+                Some(end) if end <= self.data.len() && self.data.len() - end >= SYNC_SIZE => end,
+                _ => {
+                    return Err(
+                        SparserError::format("avro", "truncated block").at_offset(pos as u64)
+                    )
+                }
+            };
+            if self.data[end..end + SYNC_SIZE] != self.sync {
+                return Err(SparserError::format("avro", "bad sync marker").at_offset(end as u64));
+            }
+            blocks.push(Block {
+                offset: start as u64,
+                count: count as u64,
+                data: Buffer::from(&self.data[start..end]),
+            });
+            pos = end + SYNC_SIZE;
+        }
+        Ok(blocks)
+    }
+
+    /// Decodes the records of `block`.
+    pub fn records(&self, block: &Block) -> Result<Vec<Value>> {
+        let mut decoder = Decoder::new(block.data.data());
+        let max = max_items(self.schema.takes_no_bytes(), block.data.len());
+        if block.count > max {
+            return Err(
+                SparserError::format("avro", "block count exceeds the block size")
+                    .at_offset(block.offset),
+            );
+        }
+        let mut records = Vec::with_capacity(block.count as usize);
+        for _ in 0..block.count {
+            let record = decoder
+                .read_value(&self.schema)
+                .map_err(|e| e.at_offset(block.offset + decoder.position() as u64))?;
+            records.push(record);
+        }
+        Ok(records)
+    }
+
+    /// Calls `verifier` with every record in a block passing `cascade`. Returns the
+    /// number of records for which `verifier` returned true.
+    pub fn search<F: FnMut(&Value) -> bool>(
+        &self,
+        cascade: &Cascade,
+        mut verifier: F,
+    ) -> Result<u64> {
+        let mut passed = 0;
+        for block in self.blocks()? {
+            if !cascade.matches(block.data.data()) {
+                continue;
+            }
+            for record in self.records(&block)? {
+                if verifier(&record) {
+                    passed += 1;
+                }
+            }
+        }
+        Ok(passed)
+    }
+}
+
+/// A predicate on a field of an Avro record.
+#[derive(Debug, Clone, PartialEq)]
+pub enum AvroPredicate {
+    /// The field at the dot-separated path equals the value. Ints and longs compare
+    /// by numeric value.
+    Equals { field: String, value: Value },
+    /// The string or bytes field at the dot-separated path contains the bytes.
+    Contains { field: String, value: Vec<u8> },
+}
+
+impl AvroPredicate {
+    fn field(&self) -> &str {
+        match *self {
+            AvroPredicate::Equals { ref field, .. } | AvroPredicate::Contains { ref field, .. } => {
+                field
+            }
+        }
+    }
+
+    /// Evaluates the predicate against a decoded record.
+    pub fn evaluate(&self, record: &Value) -> bool {
+        let actual = match record.field(self.field()) {
+            Some(actual) => actual,
+            None => return false,
+        };
+        match *self {
+            AvroPredicate::Equals { ref value, .. } => match (actual.as_i64(), value.as_i64()) {
+                (Some(a), Some(b)) => a == b,
+                _ => match (actual.as_bytes(), value.as_bytes()) {
+                    (Some(a), Some(b)) => a == b,
+                    _ => actual == value,
+                },
+            },
+            AvroPredicate::Contains { ref value, .. } => actual
+                .as_bytes()
+                .is_some_and(|a| sparser_kernels::find(a, value).is_some()),
+        }
+    }
+
+    /// Returns a raw filter on the encoded block bytes implied by the predicate.
+    /// Only string and bytes fields are stored verbatim, so other fields (including
+    /// enums, which are stored as indexes) have none. String equality includes the
+    /// length prefix, which makes it more selective.
+    pub fn raw_filter(&self, schema: &Schema) -> Option<RawFilter> {
+        if !schema.field(self.field()).is_some_and(stored_verbatim) {
+            return None;
+        }
+        match *self {
+            AvroPredicate::Equals { ref value, .. } => match *value {
+                Value::String(_) | Value::Bytes(_) => {
+                    let bytes = value.as_bytes().unwrap();
+                    let mut encoded = Vec::with_capacity(bytes.len() + 10);
+                    encode_long(bytes.len() as i64, &mut encoded);
+                    encoded.extend_from_slice(bytes);
+                    Some(RawFilter::Substring(encoded))
+                }
+                _ => None,
+            },
+            AvroPredicate::Contains { ref value, .. } if !value.is_empty() => {
+                Some(RawFilter::substring(value))
+            }
+            AvroPredicate::Contains { .. } => None,
+        }
+    }
+}
+
+fn stored_verbatim(schema: &Schema) -> bool {
+    match *schema {
+        Schema::String | Schema::Bytes => true,
+        Schema::Union(ref branches) => branches
+            .iter()
+            .all(|b| *b == Schema::Null || stored_verbatim(b)),
+        _ => false,
+    }
+}
+
+/// AvroVerifier evaluates a conjunction of field predicates on decoded records.
+#[derive(Debug, Clone)]
+pub struct AvroVerifier {
+    schema: Schema,
+    predicates: Vec<AvroPredicate>,
+}
+
+impl AvroVerifier {
+    /// Creates a verifier, checking that every predicate refers to a field of `schema`.
+    pub fn new(schema: &Schema, predicates: Vec<AvroPredicate>) -> Result<Self> {
+        for predicate in &predicates {
+            if schema.field(predicate.field()).is_none() {
+                return Err(SparserError::InvalidQuery(format!(
+                    "no field {} in the avro schema",
+                    predicate.field()
+                )));
+            }
+        }
+        Ok(AvroVerifier {
+            schema: schema.clone(),
+            predicates,
+        })
+    }
+
+    pub fn verify(&self, record: &Value) -> bool {
+        self.predicates.iter().all(|p| p.evaluate(record))
+    }
+
+    /// Returns the raw filters implied by the predicates, as optimizer candidates.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        self.predicates
+            .iter()
+            .filter_map(|p| p.raw_filter(&self.schema))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCHEMA: &str = r#"{
+        "type": "record", "name": "Tweet",
+        "fields": [
+            {"name": "id", "type": "long"},
+            {"name": "text", "type": "string"},
+            {"name": "user", "type": {"type": "record", "name": "User", "fields": [
+                {"name": "lang", "type": {"type": "enum", "name": "Lang", "symbols": ["en", "de"]}},
+                {"name": "name", "type": ["null", "string"]}
+            ]}},
+            {"name": "tags", "type": {"type": "array", "items": "string"}}
+        ]
+    }"#;
+
+    fn encode_string(s: &str, out: &mut Vec<u8>) {
+        encode_long(s.len() as i64, out);
+        out.extend_from_slice(s.as_bytes());
+    }
+
+    fn encode_tweet(id: i64, text: &str, lang: i64, name: Option<&str>, out: &mut Vec<u8>) {
+        encode_long(id, out);
+        encode_string(text, out);
+        encode_long(lang, out);
+        match name {
+            Some(name) => {
+                encode_long(1, out);
+                encode_string(name, out);
+            }
+            None => encode_long(0, out),
+        }
+        encode_long(1, out);
+        encode_string("tag", out);
+        encode_long(0, out);
+    }
+
+    /// Writes `n` tweets as an object container file with `per_block` records per block.
+    fn container(n: i64, per_block: i64) -> Vec<u8> {
+        let sync = *b"0123456789abcdef";
+        let mut file = MAGIC.to_vec();
+        encode_long(1, &mut file);
+        encode_string("avro.schema", &mut file);
+        encode_string(SCHEMA, &mut file);
+        encode_long(0, &mut file);
+        file.extend_from_slice(&sync);
+
+        let mut id = 0;
+        while id < n {
+            let count = per_block.min(n - id);
+            let mut block = Vec::new();
+            for i in id..id + count {
+                let text = if i == 42 {
+                    "sparser on avro"
+                } else {
+                    "just a tweet"
+                };
+                let name = if i % 2 == 0 { Some("ann") } else { None };
+                encode_tweet(i, text, i % 2, name, &mut block);
+            }
+            encode_long(count, &mut file);
+            encode_long(block.len() as i64, &mut file);
+            file.extend_from_slice(&block);
+            file.extend_from_slice(&sync);
+            id += count;
+        }
+        file
+    }
+
+    #[test]
+    fn test_encode_long() {
+        for &(value, encoded) in
+            [(0i64, &[0u8][..]), (-1, &[1]), (1, &[2]), (64, &[0x80, 1])].iter()
+        {
+            let mut out = Vec::new();
+            encode_long(value, &mut out);
+            assert_eq!(encoded, &out[..]);
+            assert_eq!(value, Decoder::new(&out).read_long().unwrap());
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        let schema = Schema::parse(SCHEMA).unwrap();
+        assert_eq!(Some(&Schema::Long), schema.field("id"));
+        assert_eq!(
+            Some(&Schema::Union(vec![Schema::Null, Schema::String])),
+            schema.field("user.name")
+        );
+        assert!(schema.field("user.age").is_none());
+        assert!(Schema::parse(
+            r#"{"type": "record", "name": "R", "fields": [{"name": "x", "type": "Nope"}]}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_takes_no_bytes() {
+        let empty = Schema::parse(
+            r#"{"type": "record", "name": "e", "fields": [
+            {"name": "a", "type": "null"},
+            {"name": "b", "type": {"type": "fixed", "name": "f", "size": 0}}
+        ]}"#,
+        )
+        .unwrap();
+        assert!(empty.takes_no_bytes());
+        assert!(!Schema::Array(Box::new(Schema::Null)).takes_no_bytes());
+        assert!(!Schema::Union(vec![Schema::Null]).takes_no_bytes());
+        assert!(!Schema::parse(SCHEMA).unwrap().takes_no_bytes());
+    }
+
+    #[test]
+    fn test_read_container() {
+        let file = container(10, 4);
+        let reader = AvroReader::new(&file).unwrap();
+        let blocks = reader.blocks().unwrap();
+        assert_eq!(
+            vec![4, 4, 2],
+            blocks.iter().map(|b| b.count).collect::<Vec<_>>()
+        );
+        let records = reader.records(&blocks[0]).unwrap();
+        assert_eq!(Some(&Value::Long(1)), records[1].field("id"));
+        assert_eq!(
+            Some(&Value::Enum(1, "de".to_string())),
+            records[1].field("user.lang")
+        );
+        assert_eq!(Some(&Value::Null), records[1].field("user.name"));
+        assert_eq!(
+            Some(&Value::String("ann".to_string())),
+            records[2].field("user.name")
+        );
+    }
+
+    #[test]
+    fn test_search_skips_blocks() {
+        let file = container(1000, 100);
+        let reader = AvroReader::new(&file).unwrap();
+        let verifier = AvroVerifier::new(
+            reader.schema(),
+            vec![
+                AvroPredicate::Equals {
+                    field: "text".to_string(),
+                    value: Value::String("sparser on avro".to_string()),
+                },
+                AvroPredicate::Equals {
+                    field: "user.lang".to_string(),
+                    value: Value::String("en".to_string()),
+                },
+            ],
+        )
+        .unwrap();
+        let cascade = Cascade::new(verifier.raw_filters());
+        assert_eq!(1, cascade.len());
+
+        let mut decoded = 0;
+        let mut ids = Vec::new();
+        let passed = reader
+            .search(&cascade, |record| {
+                decoded += 1;
+                if verifier.verify(record) {
+                    ids.push(record.field("id").cloned());
+                    true
+                } else {
+                    false
+                }
+            })
+            .unwrap();
+        assert_eq!(1, passed);
+        assert_eq!(vec![Some(Value::Long(42))], ids);
+        // only the block holding record 42 is decoded
+        assert_eq!(100, decoded);
+    }
+
+    #[test]
+    fn test_invalid_files() {
+        assert!(AvroReader::new(b"nope").is_err());
+        let mut file = container(10, 5);
+        let last = file.len() - 1;
+        file[last] ^= 1;
+        let reader = AvroReader::new(&file).unwrap();
+        match reader.blocks() {
+            Err(SparserError::FormatError { offset, .. }) => assert!(offset.is_some()),
+            other => panic!("unexpected {:?}", other),
+        }
+
+        // a block size reaching past the end of the address space
+        let mut file = container(0, 1);
+        encode_long(1, &mut file);
+        encode_long(i64::MAX, &mut file);
+        assert!(AvroReader::new(&file).unwrap().blocks().is_err());
+
+        // a record count far beyond what the block holds
+        let mut file = container(0, 1);
+        encode_long(i64::MAX, &mut file);
+        encode_long(1, &mut file);
+        file.push(0);
+        file.extend_from_slice(b"0123456789abcdef");
+        let reader = AvroReader::new(&file).unwrap();
+        let blocks = reader.blocks().unwrap();
+        assert!(reader.records(&blocks[0]).is_err());
+
+        // an array block count that cannot be negated
+        let mut data = Vec::new();
+        encode_long(i64::MIN, &mut data);
+        encode_long(0, &mut data);
+        let array = Schema::Array(Box::new(Schema::Long));
+        assert!(Decoder::new(&data).read_value(&array).is_err());
+
+        // more items than bytes left, unless the items take no bytes
+        let mut data = Vec::new();
+        encode_long(3, &mut data);
+        encode_long(0, &mut data);
+        assert!(Decoder::new(&data).read_value(&array).is_err());
+        let nulls = Schema::Array(Box::new(Schema::Null));
+        assert_eq!(
+            Value::Array(vec![Value::Null; 3]),
+            Decoder::new(&data).read_value(&nulls).unwrap()
+        );
+        let mut data = Vec::new();
+        encode_long(i64::MAX, &mut data);
+        assert!(Decoder::new(&data).read_value(&nulls).is_err());
+
+        let schema = Schema::parse(SCHEMA).unwrap();
+        let unknown = AvroPredicate::Contains {
+            field: "user.age".to_string(),
+            value: b"1".to_vec(),
+        };
+        assert!(AvroVerifier::new(&schema, vec![unknown]).is_err());
+    }
+}
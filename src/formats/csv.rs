@@ -0,0 +1,396 @@
+use std::borrow::Cow;
+
+use cascade::Cascade;
+use raw_filter::RawFilter;
+use sparser_kernels;
+
+/// Field separator and quote character of a CSV dialect.
+///
+/// Records end at a newline that is not inside a quoted field; a trailing `\r` is
+/// not part of the record. Inside quoted fields the quote character is escaped by
+/// doubling it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CsvFormat {
+    pub separator: u8,
+    pub quote: u8,
+}
+
+impl CsvFormat {
+    /// Comma separated values.
+    pub fn csv() -> Self {
+        CsvFormat {
+            separator: b',',
+            quote: b'"',
+        }
+    }
+
+    /// Tab separated values.
+    pub fn tsv() -> Self {
+        CsvFormat {
+            separator: b'\t',
+            quote: b'"',
+        }
+    }
+
+    /// Calls `f` with the offset and bytes of every record in `data`.
+    pub fn for_each_record<'a, F: FnMut(usize, &'a [u8])>(&self, data: &'a [u8], mut f: F) {
+        let mut start = 0;
+        while start < data.len() {
+            let end = self.record_end(data, start);
+            f(start, trim_cr(&data[start..end]));
+            start = end + 1;
+        }
+    }
+
+    /// Returns the offset of the newline ending the record that starts at `start`,
+    /// or `data.len()` if the record is not terminated.
+    pub fn record_end(&self, data: &[u8], start: usize) -> usize {
+        let mut from = start;
+        let mut quotes = 0;
+        loop {
+            let end = match sparser_kernels::find_byte(&data[from..], b'\n') {
+                Some(i) => from + i,
+                None => return data.len(),
+            };
+            quotes += sparser_kernels::count_byte(&data[from..end], self.quote);
+            if quotes % 2 == 0 {
+                return end;
+            }
+            // the newline is inside a quoted field
+            from = end + 1;
+        }
+    }
+
+    /// Returns the offset of the first record of `data` that starts at or after `at`,
+    /// or `data.len()` if there is none. `data` must start at a record.
+    pub fn next_record_start(&self, data: &[u8], at: usize) -> usize {
+        let mut start = 0;
+        while start < at && start < data.len() {
+            start = self.record_end(data, start) + 1;
+        }
+        start.min(data.len())
+    }
+
+    /// Returns the length of the longest prefix of `data` that holds only complete,
+    /// newline-terminated records. `data` must start at a record.
+    pub fn complete_len(&self, data: &[u8]) -> usize {
+        let mut start = 0;
+        while start < data.len() {
+            let end = self.record_end(data, start);
+            if end == data.len() {
+                break;
+            }
+            start = end + 1;
+        }
+        start
+    }
+
+    /// Returns whether a quoted field in `data` contains a newline, i.e. whether
+    /// splitting `data` at every newline would split a record. `data` must start at
+    /// a record.
+    pub fn has_quoted_newline(&self, data: &[u8]) -> bool {
+        if sparser_kernels::count_byte(data, self.quote) == 0 {
+            return false;
+        }
+        let mut pos = 0;
+        while let Some(i) = sparser_kernels::find_byte(&data[pos..], b'\n') {
+            if sparser_kernels::count_byte(&data[pos..pos + i], self.quote) % 2 == 1 {
+                return true;
+            }
+            pos += i + 1;
+        }
+        false
+    }
+
+    /// Calls `f` with the offset and bytes of every record in `data` that passes
+    /// `cascade`.
+    pub fn for_each_candidate<'a, F: FnMut(usize, &'a [u8])>(
+        &self,
+        data: &'a [u8],
+        cascade: &Cascade,
+        mut f: F,
+    ) {
+        self.for_each_record(data, |offset, record| {
+            if cascade.matches(record) {
+                f(offset, record)
+            }
+        })
+    }
+
+    /// Returns the raw bytes of field `index` of `record`, including any quotes.
+    pub fn field<'a>(&self, record: &'a [u8], index: usize) -> Option<&'a [u8]> {
+        let mut column = 0;
+        let mut start = 0;
+        let mut in_quotes = false;
+        for (i, &b) in record.iter().enumerate() {
+            if b == self.quote {
+                in_quotes = !in_quotes;
+            } else if b == self.separator && !in_quotes {
+                if column == index {
+                    return Some(&record[start..i]);
+                }
+                column += 1;
+                start = i + 1;
+            }
+        }
+        if column == index {
+            Some(&record[start..])
+        } else {
+            None
+        }
+    }
+
+    /// Returns the raw bytes of every field of `record`.
+    pub fn fields<'a>(&self, record: &'a [u8]) -> Vec<&'a [u8]> {
+        let mut fields = Vec::new();
+        while let Some(field) = self.field(record, fields.len()) {
+            fields.push(field);
+        }
+        fields
+    }
+
+    /// Returns the value of a raw field, without surrounding quotes and with doubled
+    /// quotes collapsed.
+    pub fn unquote<'a>(&self, field: &'a [u8]) -> Cow<'a, [u8]> {
+        let q = self.quote;
+        if field.len() < 2 || field[0] != q || field[field.len() - 1] != q {
+            return Cow::Borrowed(field);
+        }
+        let inner = &field[1..field.len() - 1];
+        if !inner.contains(&q) {
+            return Cow::Borrowed(inner);
+        }
+        let mut value = Vec::with_capacity(inner.len());
+        let mut i = 0;
+        while i < inner.len() {
+            value.push(inner[i]);
+            i += if inner[i] == q && inner.get(i + 1) == Some(&q) {
+                2
+            } else {
+                1
+            };
+        }
+        Cow::Owned(value)
+    }
+
+    /// Returns the value as it appears in a raw field, with quotes doubled.
+    pub fn escape(&self, value: &[u8]) -> Vec<u8> {
+        let mut escaped = Vec::with_capacity(value.len());
+        for &b in value {
+            escaped.push(b);
+            if b == self.quote {
+                escaped.push(b);
+            }
+        }
+        escaped
+    }
+
+    /// Returns the index of the column called `name` in a header record.
+    pub fn column_index(&self, header: &[u8], name: &str) -> Option<usize> {
+        self.fields(header)
+            .iter()
+            .position(|field| &*self.unquote(field) == name.as_bytes())
+    }
+}
+
+/// Returns `record` without a trailing `\r`, which ends CRLF terminated records.
+pub fn trim_cr(record: &[u8]) -> &[u8] {
+    match record.last() {
+        Some(&b'\r') => &record[..record.len() - 1],
+        _ => record,
+    }
+}
+
+/// A predicate on a single column of a CSV record.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ColumnPredicate {
+    /// The value of the column equals `value`.
+    Equals { column: usize, value: Vec<u8> },
+    /// The value of the column contains `value`.
+    Contains { column: usize, value: Vec<u8> },
+}
+
+impl ColumnPredicate {
+    pub fn column(&self) -> usize {
+        match *self {
+            ColumnPredicate::Equals { column, .. } | ColumnPredicate::Contains { column, .. } => {
+                column
+            }
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match *self {
+            ColumnPredicate::Equals { ref value, .. }
+            | ColumnPredicate::Contains { ref value, .. } => value,
+        }
+    }
+
+    /// Evaluates the predicate exactly against `record`.
+    pub fn evaluate(&self, format: &CsvFormat, record: &[u8]) -> bool {
+        let field = match format.field(record, self.column()) {
+            Some(field) => format.unquote(field),
+            None => return false,
+        };
+        match *self {
+            ColumnPredicate::Equals { ref value, .. } => &*field == value.as_slice(),
+            ColumnPredicate::Contains { ref value, .. } => {
+                sparser_kernels::find(&field, value).is_some()
+            }
+        }
+    }
+
+    /// Returns a column-scoped raw filter implied by the predicate. A value containing
+    /// the quote character can only appear in a quoted field, where it is doubled.
+    pub fn raw_filter(&self, format: &CsvFormat) -> Option<RawFilter> {
+        if self.value().is_empty() {
+            return None;
+        }
+        Some(RawFilter::column(
+            *format,
+            self.column(),
+            format.escape(self.value()),
+        ))
+    }
+}
+
+/// CsvVerifier evaluates a conjunction of column predicates exactly.
+#[derive(Debug, Clone)]
+pub struct CsvVerifier {
+    format: CsvFormat,
+    predicates: Vec<ColumnPredicate>,
+}
+
+impl CsvVerifier {
+    pub fn new(format: CsvFormat, predicates: Vec<ColumnPredicate>) -> Self {
+        CsvVerifier { format, predicates }
+    }
+
+    /// Returns whether `record` satisfies every predicate.
+    pub fn verify(&self, record: &[u8]) -> bool {
+        self.predicates
+            .iter()
+            .all(|p| p.evaluate(&self.format, record))
+    }
+
+    /// Returns the raw filters implied by the predicates, as optimizer candidates.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        self.predicates
+            .iter()
+            .filter_map(|p| p.raw_filter(&self.format))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DATA: &[u8] = b"id,lang,text\r\n\
+1,en,\"hello, world\"\r\n\
+2,de,\"multi\nline \"\"en\"\" text\"\r\n\
+3,fr,often\r\n\
+4,en,\"rust\"";
+
+    fn records(format: &CsvFormat, data: &[u8]) -> Vec<(usize, Vec<u8>)> {
+        let mut records = Vec::new();
+        format.for_each_record(data, |offset, record| {
+            records.push((offset, record.to_vec()))
+        });
+        records
+    }
+
+    #[test]
+    fn test_records_with_quoted_newlines() {
+        let records = records(&CsvFormat::csv(), DATA);
+        assert_eq!(5, records.len());
+        assert_eq!(b"id,lang,text".to_vec(), records[0].1);
+        assert_eq!(
+            b"2,de,\"multi\nline \"\"en\"\" text\"".to_vec(),
+            records[2].1
+        );
+        assert_eq!(&DATA[records[3].0..records[3].0 + 10], b"3,fr,often");
+        assert_eq!(b"4,en,\"rust\"".to_vec(), records[4].1);
+    }
+
+    #[test]
+    fn test_record_boundaries() {
+        let format = CsvFormat::csv();
+        let starts: Vec<usize> = records(&format, DATA).iter().map(|r| r.0).collect();
+        let multi = starts[2];
+        assert_eq!(starts[3] - 1, format.record_end(DATA, multi));
+        assert_eq!(DATA.len(), format.record_end(DATA, starts[4]));
+        assert_eq!(multi, format.next_record_start(DATA, multi));
+        assert_eq!(starts[3], format.next_record_start(DATA, multi + 1));
+        assert_eq!(DATA.len(), format.next_record_start(DATA, starts[4] + 1));
+
+        // neither the unterminated last record nor a cut quoted field is complete
+        assert_eq!(starts[4], format.complete_len(DATA));
+        assert_eq!(multi, format.complete_len(&DATA[..multi + 10]));
+        assert_eq!(0, format.complete_len(b"\"a\nb"));
+
+        assert!(format.has_quoted_newline(DATA));
+        assert!(!format.has_quoted_newline(&DATA[..multi]));
+        assert!(!format.has_quoted_newline(&DATA[starts[3]..]));
+        assert!(!format.has_quoted_newline(b"a\nb\n"));
+    }
+
+    #[test]
+    fn test_fields() {
+        let format = CsvFormat::csv();
+        let record = b"2,de,\"multi\nline \"\"en\"\", text\"";
+        assert_eq!(Some(&b"de"[..]), format.field(record, 1));
+        assert_eq!(None, format.field(record, 3));
+        assert_eq!(3, format.fields(record).len());
+        assert_eq!(
+            b"multi\nline \"en\", text".to_vec(),
+            format
+                .unquote(format.field(record, 2).unwrap())
+                .into_owned()
+        );
+        assert_eq!(Some(2), format.column_index(b"id,lang,\"text\"", "text"));
+        assert_eq!(
+            vec![&b"a"[..], b"", b"c"],
+            CsvFormat::tsv().fields(b"a\t\tc")
+        );
+    }
+
+    #[test]
+    fn test_verifier() {
+        let format = CsvFormat::csv();
+        let verifier = CsvVerifier::new(
+            format,
+            vec![ColumnPredicate::Equals {
+                column: 1,
+                value: b"en".to_vec(),
+            }],
+        );
+        let cascade = Cascade::new(verifier.raw_filters());
+        let mut ids = Vec::new();
+        format.for_each_candidate(DATA, &cascade, |_, record| {
+            if verifier.verify(record) {
+                ids.push(format.field(record, 0).unwrap().to_vec());
+            }
+        });
+        assert_eq!(vec![b"1".to_vec(), b"4".to_vec()], ids);
+    }
+
+    #[test]
+    fn test_column_filter_rejects_wrong_column() {
+        let format = CsvFormat::csv();
+        let predicate = ColumnPredicate::Contains {
+            column: 2,
+            value: b"\"en\"".to_vec(),
+        };
+        let filter = predicate.raw_filter(&format).unwrap();
+        assert_eq!(RawFilter::column(format, 2, b"\"\"en\"\""), filter);
+        let records = records(&format, DATA);
+        let passing: Vec<bool> = records.iter().map(|r| filter.matches(&r.1)).collect();
+        assert_eq!(vec![false, false, true, false, false], passing);
+        assert!(predicate.evaluate(&format, &records[2].1));
+
+        // "en" occurs in the text of record 3 but not in its lang column
+        let filter = RawFilter::column(format, 1, "en");
+        assert!(!filter.matches(&records[3].1));
+    }
+}
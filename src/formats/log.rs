@@ -0,0 +1,424 @@
+use raw_filter::RawFilter;
+use sparser_kernels;
+
+/// Layout of a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogFormat {
+    /// Common or combined log format, as written by Apache and nginx:
+    /// `host ident user [timestamp] "request" status bytes ...`.
+    Access,
+    /// RFC 5424 syslog:
+    /// `<pri>version timestamp host app procid msgid structured-data message`.
+    Syslog,
+}
+
+/// A field extracted from a log line.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LogField {
+    Timestamp,
+    Host,
+    /// HTTP status code of an access log line.
+    Status,
+    /// Request path of an access log line.
+    Path,
+    /// The request line of an access log line, the free-form message of a syslog line.
+    Message,
+}
+
+/// The fields of a parsed log line. Each field borrows the raw bytes of the line;
+/// fields that are absent or nil (`-` in syslog) are `None`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LogRecord<'a> {
+    pub timestamp: Option<&'a [u8]>,
+    pub host: Option<&'a [u8]>,
+    pub status: Option<&'a [u8]>,
+    pub path: Option<&'a [u8]>,
+    pub message: Option<&'a [u8]>,
+}
+
+impl<'a> LogRecord<'a> {
+    pub fn field(&self, field: LogField) -> Option<&'a [u8]> {
+        match field {
+            LogField::Timestamp => self.timestamp,
+            LogField::Host => self.host,
+            LogField::Status => self.status,
+            LogField::Path => self.path,
+            LogField::Message => self.message,
+        }
+    }
+}
+
+impl LogFormat {
+    /// Parses `line`, returning `None` if it is not in this format.
+    pub fn parse<'a>(&self, line: &'a [u8]) -> Option<LogRecord<'a>> {
+        let line = match line.last() {
+            Some(&b'\r') => &line[..line.len() - 1],
+            _ => line,
+        };
+        match *self {
+            LogFormat::Access => parse_access(line),
+            LogFormat::Syslog => parse_syslog(line),
+        }
+    }
+
+    /// Returns the bytes that always surround `field` in a line of this format, so
+    /// that raw filters for exact values can include them.
+    fn delimiters(&self, field: LogField) -> (&'static [u8], &'static [u8]) {
+        match (*self, field) {
+            (LogFormat::Access, LogField::Host) => (b"", b" "),
+            (LogFormat::Access, LogField::Timestamp) => (b"[", b"]"),
+            (LogFormat::Access, LogField::Status) => (b"\" ", b" "),
+            (LogFormat::Access, LogField::Path) => (b" ", b""),
+            (LogFormat::Access, LogField::Message) => (b"\"", b"\""),
+            (LogFormat::Syslog, LogField::Timestamp) | (LogFormat::Syslog, LogField::Host) => {
+                (b" ", b" ")
+            }
+            (LogFormat::Syslog, _) => (b"", b""),
+        }
+    }
+}
+
+/// Splits off the next space-terminated token of `line[*pos..]`.
+fn token<'a>(line: &'a [u8], pos: &mut usize) -> Option<&'a [u8]> {
+    let rest = line.get(*pos..)?;
+    let len = sparser_kernels::find_byte(rest, b' ').unwrap_or(rest.len());
+    if len == 0 {
+        return None;
+    }
+    *pos += len + 1;
+    Some(&rest[..len])
+}
+
+/// Consumes `byte` at `line[*pos]`.
+fn expect(line: &[u8], pos: &mut usize, byte: u8) -> Option<()> {
+    if line.get(*pos) == Some(&byte) {
+        *pos += 1;
+        Some(())
+    } else {
+        None
+    }
+}
+
+fn parse_access(line: &[u8]) -> Option<LogRecord<'_>> {
+    let mut pos = 0;
+    let host = token(line, &mut pos)?;
+    token(line, &mut pos)?; // ident
+    token(line, &mut pos)?; // user
+    expect(line, &mut pos, b'[')?;
+    let len = sparser_kernels::find_byte(&line[pos..], b']')?;
+    let timestamp = &line[pos..pos + len];
+    pos += len + 1;
+    expect(line, &mut pos, b' ')?;
+    expect(line, &mut pos, b'"')?;
+    // nginx escapes quotes in the request line as \"
+    let start = pos;
+    while line.get(pos) != Some(&b'"') {
+        pos += if line.get(pos)? == &b'\\' { 2 } else { 1 };
+    }
+    let request = &line[start..pos];
+    pos += 1;
+    expect(line, &mut pos, b' ')?;
+    let status = token(line, &mut pos)?;
+    if status.len() != 3 || !status.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    token(line, &mut pos)?; // bytes
+
+    let mut parts = request.split(|&b| b == b' ').filter(|p| !p.is_empty());
+    let path = parts.nth(1);
+    Some(LogRecord {
+        timestamp: Some(timestamp),
+        host: Some(host),
+        status: Some(status),
+        path,
+        message: Some(request),
+    })
+}
+
+fn parse_syslog(line: &[u8]) -> Option<LogRecord<'_>> {
+    let mut pos = 0;
+    expect(line, &mut pos, b'<')?;
+    let len = sparser_kernels::find_byte(&line[pos..], b'>')?;
+    if len == 0 || len > 3 || !line[pos..pos + len].iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    pos += len + 1;
+    let version = token(line, &mut pos)?;
+    if !version.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    let timestamp = token(line, &mut pos)?;
+    let host = token(line, &mut pos)?;
+    token(line, &mut pos)?; // app name
+    token(line, &mut pos)?; // process id
+    token(line, &mut pos)?; // message id
+
+    // structured data is either nil or a run of [id param="value" ...] elements,
+    // where values may contain escaped \] and \"
+    match line.get(pos) {
+        Some(&b'-') => pos += 1,
+        Some(&b'[') => {
+            while line.get(pos) == Some(&b'[') {
+                let mut in_quotes = false;
+                loop {
+                    pos += 1;
+                    match *line.get(pos)? {
+                        b'\\' => pos += 1,
+                        b'"' => in_quotes = !in_quotes,
+                        b']' if !in_quotes => break,
+                        _ => {}
+                    }
+                }
+                pos += 1;
+            }
+        }
+        _ => return None,
+    }
+    let message = match line.get(pos) {
+        None => None,
+        Some(&b' ') => {
+            let message = &line[pos + 1..];
+            Some(message.strip_prefix(b"\xef\xbb\xbf").unwrap_or(message))
+        }
+        Some(_) => return None,
+    };
+    Some(LogRecord {
+        timestamp: nil(timestamp),
+        host: nil(host),
+        status: None,
+        path: None,
+        message,
+    })
+}
+
+fn nil(value: &[u8]) -> Option<&[u8]> {
+    if value == b"-" {
+        None
+    } else {
+        Some(value)
+    }
+}
+
+/// A predicate on a single field of a log line.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LogPredicate {
+    /// The field equals `value`.
+    Equals { field: LogField, value: Vec<u8> },
+    /// The field contains `value`.
+    Contains { field: LogField, value: Vec<u8> },
+}
+
+impl LogPredicate {
+    pub fn field(&self) -> LogField {
+        match *self {
+            LogPredicate::Equals { field, .. } | LogPredicate::Contains { field, .. } => field,
+        }
+    }
+
+    pub fn value(&self) -> &[u8] {
+        match *self {
+            LogPredicate::Equals { ref value, .. } | LogPredicate::Contains { ref value, .. } => {
+                value
+            }
+        }
+    }
+
+    /// Evaluates the predicate exactly against a parsed line.
+    pub fn evaluate(&self, record: &LogRecord) -> bool {
+        let field = match record.field(self.field()) {
+            Some(field) => field,
+            None => return false,
+        };
+        match *self {
+            LogPredicate::Equals { ref value, .. } => field == value.as_slice(),
+            LogPredicate::Contains { ref value, .. } => {
+                sparser_kernels::find(field, value).is_some()
+            }
+        }
+    }
+
+    /// Returns a raw filter implied by the predicate. Fields are verbatim slices of
+    /// the line, so the value itself always occurs in a matching line; an exact value
+    /// also occurs together with the bytes the format puts around the field.
+    pub fn raw_filter(&self, format: &LogFormat) -> Option<RawFilter> {
+        if self.value().is_empty() {
+            return None;
+        }
+        match *self {
+            LogPredicate::Equals { field, ref value } => {
+                let (prefix, suffix) = format.delimiters(field);
+                let mut bytes = prefix.to_vec();
+                bytes.extend_from_slice(value);
+                bytes.extend_from_slice(suffix);
+                Some(RawFilter::Substring(bytes))
+            }
+            LogPredicate::Contains { ref value, .. } => Some(RawFilter::substring(value)),
+        }
+    }
+}
+
+/// LogVerifier evaluates a conjunction of field predicates exactly. Lines that do
+/// not parse fail verification.
+#[derive(Debug, Clone)]
+pub struct LogVerifier {
+    format: LogFormat,
+    predicates: Vec<LogPredicate>,
+}
+
+impl LogVerifier {
+    pub fn new(format: LogFormat, predicates: Vec<LogPredicate>) -> Self {
+        LogVerifier { format, predicates }
+    }
+
+    /// Returns whether `line` parses and satisfies every predicate.
+    pub fn verify(&self, line: &[u8]) -> bool {
+        match self.format.parse(line) {
+            Some(record) => self.predicates.iter().all(|p| p.evaluate(&record)),
+            None => false,
+        }
+    }
+
+    /// Returns the raw filters implied by the predicates, as optimizer candidates.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        self.predicates
+            .iter()
+            .filter_map(|p| p.raw_filter(&self.format))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cascade::Cascade;
+
+    const ACCESS: &[u8] = b"127.0.0.1 - frank [10/Oct/2000:13:55:36 -0700] \"GET /apache_pb.gif HTTP/1.0\" 200 2326\n\
+10.0.0.2 - - [10/Oct/2000:13:56:01 -0700] \"POST /login HTTP/1.1\" 500 0 \"-\" \"curl/7.0\"\n\
+10.0.0.3 - - [10/Oct/2000:13:56:02 -0700] \"GET /errors/ 500 HTTP/1.1\" 404 500\n\
+10.0.0.4 - - [10/Oct/2000:13:56:03 -0700] \"GET /q?\\\"x\\\" HTTP/1.1\" 500 17\n\
+not an access log line\n";
+
+    const SYSLOG: &[u8] = b"<34>1 2003-10-11T22:14:15.003Z mymachine.example.com su - ID47 - 'su root' failed for lonvick\n\
+<165>1 2003-08-24T05:14:15.000003-07:00 192.0.2.1 myproc 8710 - - %% It's time to make the do-nothing\n\
+<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut=\"3\" eventSource=\"App]lication\"] \xef\xbb\xbfAn application event\n\
+<13>1 - - - - - -\n";
+
+    fn lines(data: &[u8]) -> Vec<&[u8]> {
+        data.split(|&b| b == b'\n')
+            .filter(|l| !l.is_empty())
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_access() {
+        let lines = lines(ACCESS);
+        let record = LogFormat::Access.parse(lines[0]).unwrap();
+        assert_eq!(Some(&b"127.0.0.1"[..]), record.host);
+        assert_eq!(Some(&b"10/Oct/2000:13:55:36 -0700"[..]), record.timestamp);
+        assert_eq!(Some(&b"200"[..]), record.status);
+        assert_eq!(Some(&b"/apache_pb.gif"[..]), record.path);
+        assert_eq!(Some(&b"GET /apache_pb.gif HTTP/1.0"[..]), record.message);
+
+        let record = LogFormat::Access.parse(lines[3]).unwrap();
+        assert_eq!(Some(&b"/q?\\\"x\\\""[..]), record.path);
+        assert_eq!(Some(&b"500"[..]), record.status);
+        assert_eq!(None, LogFormat::Access.parse(lines[4]));
+        assert_eq!(None, LogFormat::Syslog.parse(lines[0]));
+    }
+
+    #[test]
+    fn test_parse_syslog() {
+        let lines = lines(SYSLOG);
+        let record = LogFormat::Syslog.parse(lines[0]).unwrap();
+        assert_eq!(Some(&b"2003-10-11T22:14:15.003Z"[..]), record.timestamp);
+        assert_eq!(Some(&b"mymachine.example.com"[..]), record.host);
+        assert_eq!(Some(&b"'su root' failed for lonvick"[..]), record.message);
+        assert_eq!(None, record.status);
+
+        let record = LogFormat::Syslog.parse(lines[2]).unwrap();
+        assert_eq!(Some(&b"An application event"[..]), record.message);
+
+        let record = LogFormat::Syslog.parse(lines[3]).unwrap();
+        assert_eq!(LogRecord::default(), record);
+        assert_eq!(
+            None,
+            LogFormat::Syslog.parse(b"<34>1 2003-10-11T22:14:15.003Z host")
+        );
+    }
+
+    #[test]
+    fn test_status_filter_is_confirmed() {
+        let verifier = LogVerifier::new(
+            LogFormat::Access,
+            vec![LogPredicate::Equals {
+                field: LogField::Status,
+                value: b"500".to_vec(),
+            }],
+        );
+        assert_eq!(
+            vec![RawFilter::substring("\" 500 ")],
+            verifier.raw_filters()
+        );
+        let cascade = Cascade::new(vec![RawFilter::substring(" 500 ")]);
+        let mut hosts = Vec::new();
+        for line in lines(ACCESS) {
+            if cascade.matches(line) && verifier.verify(line) {
+                hosts.push(LogFormat::Access.parse(line).unwrap().host.unwrap());
+            }
+        }
+        // the third line passes " 500 " only in its path
+        assert_eq!(vec![&b"10.0.0.2"[..], b"10.0.0.4"], hosts);
+    }
+
+    #[test]
+    fn test_raw_filters_have_no_false_negatives() {
+        let predicates = vec![
+            (
+                LogFormat::Access,
+                LogPredicate::Equals {
+                    field: LogField::Path,
+                    value: b"/login".to_vec(),
+                },
+            ),
+            (
+                LogFormat::Access,
+                LogPredicate::Equals {
+                    field: LogField::Host,
+                    value: b"10.0.0.3".to_vec(),
+                },
+            ),
+            (
+                LogFormat::Syslog,
+                LogPredicate::Equals {
+                    field: LogField::Host,
+                    value: b"192.0.2.1".to_vec(),
+                },
+            ),
+            (
+                LogFormat::Syslog,
+                LogPredicate::Contains {
+                    field: LogField::Message,
+                    value: b"application".to_vec(),
+                },
+            ),
+        ];
+        for (format, predicate) in predicates {
+            let data = if format == LogFormat::Access {
+                ACCESS
+            } else {
+                SYSLOG
+            };
+            let filter = predicate.raw_filter(&format).unwrap();
+            let mut matched = 0;
+            for line in lines(data) {
+                if let Some(record) = format.parse(line) {
+                    if predicate.evaluate(&record) {
+                        assert!(filter.matches(line), "{} missed {:?}", filter, line);
+                        matched += 1;
+                    }
+                }
+            }
+            assert_eq!(1, matched);
+        }
+    }
+}
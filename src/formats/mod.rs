@@ -0,0 +1,4 @@
+pub mod avro;
+pub mod csv;
+pub mod log;
+pub mod parquet;
@@ -0,0 +1,1142 @@
+use bitmap::Bitmap;
+use raw_filter::RawFilter;
+use sparser_kernels;
+use utils::error::{Result, SparserError};
+
+const MAGIC: &[u8] = b"PAR1";
+
+const TYPE_BYTE_ARRAY: i32 = 6;
+
+const CODEC_UNCOMPRESSED: i32 = 0;
+
+const PAGE_DATA: i32 = 0;
+const PAGE_DICTIONARY: i32 = 2;
+const PAGE_DATA_V2: i32 = 3;
+
+const ENCODING_PLAIN: i32 = 0;
+const ENCODING_PLAIN_DICTIONARY: i32 = 2;
+const ENCODING_RLE_DICTIONARY: i32 = 8;
+
+const REPETITION_REPEATED: i32 = 2;
+
+fn format_error<M: Into<String>>(message: M) -> SparserError {
+    SparserError::format("parquet", message)
+}
+
+/// Converts a size or count read from the metadata, which must not be negative.
+fn to_usize(value: i32, what: &str) -> Result<usize> {
+    if value < 0 {
+        return Err(format_error(format!("negative {}", what)));
+    }
+    Ok(value as usize)
+}
+
+/// Thrift compact protocol types.
+const CT_STOP: u8 = 0;
+const CT_BOOLEAN_TRUE: u8 = 1;
+const CT_BOOLEAN_FALSE: u8 = 2;
+const CT_BYTE: u8 = 3;
+const CT_I16: u8 = 4;
+const CT_I32: u8 = 5;
+const CT_I64: u8 = 6;
+const CT_DOUBLE: u8 = 7;
+const CT_BINARY: u8 = 8;
+const CT_LIST: u8 = 9;
+const CT_SET: u8 = 10;
+const CT_MAP: u8 = 11;
+const CT_STRUCT: u8 = 12;
+
+/// Reads the subset of the Thrift compact protocol used by Parquet metadata.
+/// Callers pick the fields they need and skip the rest.
+struct Thrift<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Thrift<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Thrift { data, pos: 0 }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let b = *self
+            .data
+            .get(self.pos)
+            .ok_or_else(|| format_error("truncated metadata").at_offset(self.pos as u64))?;
+        self.pos += 1;
+        Ok(b)
+    }
+
+    fn varint(&mut self) -> Result<u64> {
+        let mut value = 0u64;
+        let mut shift = 0;
+        loop {
+            let b = self.byte()?;
+            if shift >= 64 {
+                return Err(format_error("varint is too long").at_offset(self.pos as u64));
+            }
+            value |= ((b & 0x7f) as u64) << shift;
+            if b & 0x80 == 0 {
+                return Ok(value);
+            }
+            shift += 7;
+        }
+    }
+
+    fn i64(&mut self) -> Result<i64> {
+        let n = self.varint()?;
+        Ok((n >> 1) as i64 ^ -((n & 1) as i64))
+    }
+
+    fn i32(&mut self) -> Result<i32> {
+        Ok(self.i64()? as i32)
+    }
+
+    fn binary(&mut self) -> Result<&'a [u8]> {
+        let len = self.varint()? as usize;
+        if self.data.len() - self.pos < len {
+            return Err(format_error("truncated metadata").at_offset(self.pos as u64));
+        }
+        let bytes = &self.data[self.pos..self.pos + len];
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    fn string(&mut self) -> Result<String> {
+        Ok(String::from_utf8_lossy(self.binary()?).into_owned())
+    }
+
+    /// Returns the element type and size of a list or set.
+    fn list_header(&mut self) -> Result<(u8, usize)> {
+        let header = self.byte()?;
+        let size = match header >> 4 {
+            15 => self.varint()?,
+            size => size as u64,
+        };
+        Ok((header & 0x0f, self.collection_size(size)?))
+    }
+
+    /// Checks the size of a list, set or map against the remaining input, in which
+    /// each of its elements takes at least one byte.
+    fn collection_size(&self, size: u64) -> Result<usize> {
+        if size > (self.data.len() - self.pos) as u64 {
+            return Err(
+                format_error("collection is larger than the metadata").at_offset(self.pos as u64)
+            );
+        }
+        Ok(size as usize)
+    }
+
+    /// Skips a value inside a list, set or map, where booleans take one byte each.
+    fn skip_element(&mut self, ty: u8) -> Result<()> {
+        if ty == CT_BOOLEAN_TRUE || ty == CT_BOOLEAN_FALSE {
+            self.byte()?;
+            return Ok(());
+        }
+        self.skip(ty)
+    }
+
+    /// Reads a struct, calling `field` with each field id and type. `field` must
+    /// consume the value (or call `skip`).
+    fn read_struct<F: FnMut(&mut Self, i16, u8) -> Result<()>>(
+        &mut self,
+        mut field: F,
+    ) -> Result<()> {
+        let mut last_id: i16 = 0;
+        loop {
+            let header = self.byte()?;
+            let ty = header & 0x0f;
+            if ty == CT_STOP {
+                return Ok(());
+            }
+            let id = match header >> 4 {
+                0 => self.i64()? as i16,
+                delta => last_id.checked_add(delta as i16).ok_or_else(|| {
+                    format_error("field id out of range").at_offset(self.pos as u64)
+                })?,
+            };
+            last_id = id;
+            field(self, id, ty)?;
+        }
+    }
+
+    fn read_list<F: FnMut(&mut Self, u8) -> Result<()>>(&mut self, mut item: F) -> Result<()> {
+        let (ty, size) = self.list_header()?;
+        for _ in 0..size {
+            item(self, ty)?;
+        }
+        Ok(())
+    }
+
+    fn skip(&mut self, ty: u8) -> Result<()> {
+        match ty {
+            CT_BOOLEAN_TRUE | CT_BOOLEAN_FALSE => {}
+            CT_BYTE => {
+                self.byte()?;
+            }
+            CT_I16 | CT_I32 | CT_I64 => {
+                self.varint()?;
+            }
+            CT_DOUBLE => {
+                for _ in 0..8 {
+                    self.byte()?;
+                }
+            }
+            CT_BINARY => {
+                self.binary()?;
+            }
+            CT_LIST | CT_SET => {
+                let (elem, size) = self.list_header()?;
+                for _ in 0..size {
+                    self.skip_element(elem)?;
+                }
+            }
+            CT_MAP => {
+                let size = self.varint()?;
+                let size = self.collection_size(size)?;
+                if size > 0 {
+                    let types = self.byte()?;
+                    for _ in 0..size {
+                        self.skip_element(types >> 4)?;
+                        self.skip_element(types & 0x0f)?;
+                    }
+                }
+            }
+            CT_STRUCT => self.read_struct(|t, _, ty| t.skip(ty))?,
+            _ => {
+                return Err(
+                    format_error(format!("unknown thrift type {}", ty)).at_offset(self.pos as u64)
+                )
+            }
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct SchemaElement {
+    physical_type: Option<i32>,
+    repetition: i32,
+    name: String,
+    num_children: usize,
+}
+
+#[derive(Debug, Clone, Default)]
+struct ColumnMeta {
+    physical_type: i32,
+    path: Vec<String>,
+    codec: i32,
+    num_values: i64,
+    data_page_offset: i64,
+    dictionary_page_offset: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default)]
+struct RowGroup {
+    columns: Vec<ColumnMeta>,
+    num_rows: i64,
+}
+
+/// A leaf column of the file schema.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Column {
+    /// Dot-separated path of the column.
+    pub path: String,
+    /// Parquet physical type number.
+    pub physical_type: Option<i32>,
+    pub max_def_level: i16,
+    pub max_rep_level: i16,
+}
+
+fn read_schema_element(t: &mut Thrift) -> Result<SchemaElement> {
+    let mut element = SchemaElement::default();
+    t.read_struct(|t, id, ty| {
+        match id {
+            1 => element.physical_type = Some(t.i32()?),
+            3 => element.repetition = t.i32()?,
+            4 => element.name = t.string()?,
+            5 => element.num_children = t.i32()? as usize,
+            _ => t.skip(ty)?,
+        }
+        Ok(())
+    })?;
+    Ok(element)
+}
+
+fn read_column_meta(t: &mut Thrift) -> Result<ColumnMeta> {
+    let mut meta = ColumnMeta::default();
+    t.read_struct(|t, id, ty| {
+        match id {
+            1 => meta.physical_type = t.i32()?,
+            3 => t.read_list(|t, _| {
+                meta.path.push(t.string()?);
+                Ok(())
+            })?,
+            4 => meta.codec = t.i32()?,
+            5 => meta.num_values = t.i64()?,
+            9 => meta.data_page_offset = t.i64()?,
+            11 => meta.dictionary_page_offset = Some(t.i64()?),
+            _ => t.skip(ty)?,
+        }
+        Ok(())
+    })?;
+    Ok(meta)
+}
+
+fn read_row_group(t: &mut Thrift) -> Result<RowGroup> {
+    let mut group = RowGroup::default();
+    t.read_struct(|t, id, ty| {
+        match id {
+            1 => t.read_list(|t, _| {
+                let mut meta = None;
+                t.read_struct(|t, id, ty| {
+                    match id {
+                        3 => meta = Some(read_column_meta(t)?),
+                        _ => t.skip(ty)?,
+                    }
+                    Ok(())
+                })?;
+                group
+                    .columns
+                    .push(meta.ok_or_else(|| format_error("column chunk without metadata"))?);
+                Ok(())
+            })?,
+            3 => {
+                group.num_rows = t.i64()?;
+                if group.num_rows < 0 {
+                    return Err(format_error("negative row count"));
+                }
+            }
+            _ => t.skip(ty)?,
+        }
+        Ok(())
+    })?;
+    Ok(group)
+}
+
+#[derive(Debug, Default)]
+struct PageHeader {
+    page_type: i32,
+    uncompressed_size: usize,
+    compressed_size: usize,
+    num_values: usize,
+    encoding: i32,
+}
+
+fn read_page_header(t: &mut Thrift) -> Result<PageHeader> {
+    let mut header = PageHeader::default();
+    t.read_struct(|t, id, ty| {
+        match id {
+            1 => header.page_type = t.i32()?,
+            2 => header.uncompressed_size = to_usize(t.i32()?, "page size")?,
+            3 => header.compressed_size = to_usize(t.i32()?, "page size")?,
+            // data page and dictionary page headers share the first two fields
+            5 | 7 => t.read_struct(|t, id, ty| {
+                match id {
+                    1 => header.num_values = to_usize(t.i32()?, "value count")?,
+                    2 => header.encoding = t.i32()?,
+                    _ => t.skip(ty)?,
+                }
+                Ok(())
+            })?,
+            _ => t.skip(ty)?,
+        }
+        Ok(())
+    })?;
+    Ok(header)
+}
+
+/// A predicate on the values of a BYTE_ARRAY column.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ByteArrayPredicate {
+    Equals(Vec<u8>),
+    Contains(Vec<u8>),
+}
+
+impl ByteArrayPredicate {
+    pub fn evaluate(&self, value: &[u8]) -> bool {
+        match *self {
+            ByteArrayPredicate::Equals(ref v) => value == v.as_slice(),
+            ByteArrayPredicate::Contains(ref v) => sparser_kernels::find(value, v).is_some(),
+        }
+    }
+
+    /// Returns a raw filter over PLAIN encoded page bytes implied by the predicate.
+    /// PLAIN values are prefixed with their 4-byte length, which equality includes.
+    pub fn raw_filter(&self) -> RawFilter {
+        match *self {
+            ByteArrayPredicate::Equals(ref v) => {
+                let mut bytes = (v.len() as u32).to_le_bytes().to_vec();
+                bytes.extend_from_slice(v);
+                RawFilter::Substring(bytes)
+            }
+            ByteArrayPredicate::Contains(ref v) => RawFilter::substring(v),
+        }
+    }
+}
+
+/// Result of searching a column.
+#[derive(Debug)]
+pub struct ColumnSearch {
+    /// One bit per row of the file, set for the rows whose value matches.
+    pub rows: Bitmap,
+    /// Number of rows with a matching value.
+    pub matches: u64,
+    pub pages_scanned: usize,
+    pub pages_skipped: usize,
+    /// Number of column chunks whose dictionary holds no matching value. Their
+    /// dictionary encoded pages are skipped without decoding.
+    pub row_groups_skipped: usize,
+}
+
+/// ParquetReader applies raw filters directly to the pages of a Parquet file held
+/// in memory.
+///
+/// Supported are flat BYTE_ARRAY columns in uncompressed chunks with PLAIN or
+/// dictionary encoded v1 data pages. A page whose bytes fail the predicate's raw
+/// filter is skipped without decoding, and so is every dictionary encoded page of a
+/// column chunk whose dictionary contains no matching value.
+#[derive(Debug)]
+pub struct ParquetReader<'a> {
+    data: &'a [u8],
+    columns: Vec<Column>,
+    row_groups: Vec<RowGroup>,
+    num_rows: u64,
+}
+
+impl<'a> ParquetReader<'a> {
+    /// Reads the file footer.
+    pub fn new(data: &'a [u8]) -> Result<Self> {
+        if data.len() < 12 || !data.starts_with(MAGIC) || !data.ends_with(MAGIC) {
+            return Err(format_error("not a parquet file").at_offset(0));
+        }
+        let footer_len = u32::from_le_bytes([
+            data[data.len() - 8],
+            data[data.len() - 7],
+            data[data.len() - 6],
+            data[data.len() - 5],
+        ]) as usize;
+        if footer_len + 12 > data.len() {
+            return Err(format_error("footer length exceeds the file size"));
+        }
+        let footer_start = data.len() - 8 - footer_len;
+        let mut t = Thrift::new(&data[footer_start..data.len() - 8]);
+        let mut schema = Vec::new();
+        let mut row_groups = Vec::new();
+        let mut num_rows = 0;
+        t.read_struct(|t, id, ty| {
+            match id {
+                2 => t.read_list(|t, _| {
+                    schema.push(read_schema_element(t)?);
+                    Ok(())
+                })?,
+                3 => {
+                    num_rows = t.i64()?;
+                    if num_rows < 0 {
+                        return Err(format_error("negative row count"));
+                    }
+                }
+                4 => t.read_list(|t, _| {
+                    row_groups.push(read_row_group(t)?);
+                    Ok(())
+                })?,
+                _ => t.skip(ty)?,
+            }
+            Ok(())
+        })
+        .map_err(|e| e.at_offset(footer_start as u64))?;
+
+        let mut columns = Vec::new();
+        if !schema.is_empty() {
+            let mut index = 1;
+            collect_leaves(
+                &schema,
+                &mut index,
+                schema[0].num_children,
+                "",
+                0,
+                0,
+                &mut columns,
+            );
+        }
+        // the search allocates a bit per row, so a row count that no row group
+        // accounts for is not trusted
+        let group_rows = row_groups
+            .iter()
+            .fold(0u64, |sum, group| sum.saturating_add(group.num_rows as u64));
+        Ok(ParquetReader {
+            data,
+            columns,
+            row_groups,
+            num_rows: (num_rows as u64).min(group_rows),
+        })
+    }
+
+    pub fn num_rows(&self) -> u64 {
+        self.num_rows
+    }
+
+    pub fn num_row_groups(&self) -> usize {
+        self.row_groups.len()
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// Returns the index of the column with the dot-separated `path`.
+    pub fn column_index(&self, path: &str) -> Option<usize> {
+        self.columns.iter().position(|c| c.path == path)
+    }
+
+    /// Returns the rows whose value in `column` satisfies `predicate`.
+    pub fn search(&self, column: usize, predicate: &ByteArrayPredicate) -> Result<ColumnSearch> {
+        let info = self
+            .columns
+            .get(column)
+            .ok_or_else(|| SparserError::InvalidQuery(format!("no column {}", column)))?;
+        if info.physical_type != Some(TYPE_BYTE_ARRAY) {
+            return Err(SparserError::InvalidQuery(format!(
+                "column {} is not a BYTE_ARRAY column",
+                info.path
+            )));
+        }
+        if info.max_rep_level > 0 || info.max_def_level > 1 {
+            return Err(SparserError::InvalidQuery(format!(
+                "nested column {} is not supported",
+                info.path
+            )));
+        }
+
+        let mut rows = Bitmap::zeroed(self.num_rows as usize);
+        let mut search = ColumnSearch {
+            rows: Bitmap::zeroed(0),
+            matches: 0,
+            pages_scanned: 0,
+            pages_skipped: 0,
+            row_groups_skipped: 0,
+        };
+        let filter = predicate.raw_filter();
+        let mut first_row = 0;
+        for group in &self.row_groups {
+            let meta = group
+                .columns
+                .get(column)
+                .ok_or_else(|| format_error("row group is missing a column"))?;
+            let mut chunk = ChunkScan {
+                reader: self,
+                meta,
+                max_def_level: info.max_def_level,
+                predicate,
+                filter: &filter,
+                rows: &mut rows,
+                first_row,
+                search: &mut search,
+            };
+            chunk.scan()?;
+            first_row = first_row.saturating_add(group.num_rows as usize);
+        }
+        search.rows = rows;
+        Ok(search)
+    }
+}
+
+fn collect_leaves(
+    schema: &[SchemaElement],
+    index: &mut usize,
+    children: usize,
+    prefix: &str,
+    def: i16,
+    rep: i16,
+    columns: &mut Vec<Column>,
+) {
+    for _ in 0..children {
+        let element = match schema.get(*index) {
+            Some(element) => element,
+            None => return,
+        };
+        *index += 1;
+        let path = if prefix.is_empty() {
+            element.name.clone()
+        } else {
+            format!("{}.{}", prefix, element.name)
+        };
+        // OPTIONAL and REPEATED fields both add a definition level
+        let def = def + (element.repetition != 0) as i16;
+        let rep = rep + (element.repetition == REPETITION_REPEATED) as i16;
+        if element.num_children == 0 {
+            columns.push(Column {
+                path,
+                physical_type: element.physical_type,
+                max_def_level: def,
+                max_rep_level: rep,
+            });
+        } else {
+            collect_leaves(
+                schema,
+                index,
+                element.num_children,
+                &path,
+                def,
+                rep,
+                columns,
+            );
+        }
+    }
+}
+
+/// Scans one column chunk, page by page.
+struct ChunkScan<'r, 'a: 'r> {
+    reader: &'r ParquetReader<'a>,
+    meta: &'r ColumnMeta,
+    max_def_level: i16,
+    predicate: &'r ByteArrayPredicate,
+    filter: &'r RawFilter,
+    rows: &'r mut Bitmap,
+    first_row: usize,
+    search: &'r mut ColumnSearch,
+}
+
+impl<'r, 'a> ChunkScan<'r, 'a> {
+    fn scan(&mut self) -> Result<()> {
+        if self.meta.codec != CODEC_UNCOMPRESSED {
+            return Err(format_error(format!(
+                "unsupported compression codec {} in column {}",
+                self.meta.codec,
+                self.meta.path.join(".")
+            )));
+        }
+        let data = self.reader.data;
+        let mut pos = match self.meta.dictionary_page_offset {
+            Some(offset) if offset > 0 => offset,
+            _ => self.meta.data_page_offset,
+        } as usize;
+
+        // matching[i] tells whether dictionary entry i satisfies the predicate
+        let mut dictionary: Option<Vec<bool>> = None;
+        let mut dictionary_matches = true;
+        let mut values_seen = 0;
+        let mut row = self.first_row;
+        while (values_seen as i64) < self.meta.num_values {
+            if pos >= data.len() {
+                return Err(format_error("column chunk runs past the end of the file"));
+            }
+            let mut t = Thrift::new(&data[pos..]);
+            let header = read_page_header(&mut t).map_err(|e| e.at_offset(pos as u64))?;
+            let body_start = pos + t.pos;
+            let body_end = body_start.checked_add(header.compressed_size);
+            let body = match body_end.and_then(|end| data.get(body_start..end)) {
+                Some(body) => body,
+                None => return Err(format_error("truncated page").at_offset(pos as u64)),
+            };
+            pos = body_start + body.len();
+
+            match header.page_type {
+                PAGE_DICTIONARY => {
+                    let matching = self.scan_dictionary(body, header.num_values)?;
+                    dictionary_matches = matching.iter().any(|&m| m);
+                    if !dictionary_matches {
+                        self.search.row_groups_skipped += 1;
+                    }
+                    dictionary = Some(matching);
+                }
+                PAGE_DATA => {
+                    let end = (row as u64).checked_add(header.num_values as u64);
+                    if end.is_none_or(|end| end > self.reader.num_rows) {
+                        return Err(
+                            format_error("page holds more values than the file has rows")
+                                .at_offset(body_start as u64),
+                        );
+                    }
+                    if !dictionary_matches && is_dictionary_encoded(header.encoding) {
+                        // no value of the page can match; PLAIN pages written after
+                        // the dictionary filled up still have to be searched
+                        self.search.pages_skipped += 1;
+                    } else {
+                        self.scan_data_page(body, &header, dictionary.as_deref(), row)?;
+                    }
+                    values_seen += header.num_values;
+                    row += header.num_values;
+                }
+                PAGE_DATA_V2 => {
+                    return Err(format_error("data page v2 is not supported").at_offset(pos as u64))
+                }
+                _ => {}
+            }
+        }
+        Ok(())
+    }
+
+    fn scan_dictionary(&mut self, body: &[u8], num_values: usize) -> Result<Vec<bool>> {
+        // every PLAIN value starts with a four byte length
+        if num_values > body.len() / 4 {
+            return Err(format_error("truncated dictionary page"));
+        }
+        if !self.filter.matches(body) {
+            self.search.pages_skipped += 1;
+            return Ok(vec![false; num_values]);
+        }
+        self.search.pages_scanned += 1;
+        let mut values = PlainValues { data: body, pos: 0 };
+        (0..num_values)
+            .map(|_| Ok(self.predicate.evaluate(values.next()?)))
+            .collect()
+    }
+
+    fn scan_data_page(
+        &mut self,
+        body: &[u8],
+        header: &PageHeader,
+        dictionary: Option<&[bool]>,
+        first_row: usize,
+    ) -> Result<()> {
+        let dictionary_encoded = is_dictionary_encoded(header.encoding);
+        if !dictionary_encoded {
+            if header.encoding != ENCODING_PLAIN {
+                return Err(format_error(format!(
+                    "unsupported encoding {}",
+                    header.encoding
+                )));
+            }
+            if !self.filter.matches(body) {
+                self.search.pages_skipped += 1;
+                return Ok(());
+            }
+        }
+        if header.uncompressed_size != body.len() {
+            return Err(format_error(
+                "page size does not match its uncompressed size",
+            ));
+        }
+        // every PLAIN value of a required column starts with a four byte length
+        if !dictionary_encoded
+            && self.max_def_level == 0
+            && header.num_values > header.uncompressed_size / 4
+        {
+            return Err(format_error("page holds more values than fit in its size"));
+        }
+        self.search.pages_scanned += 1;
+
+        // None if every value is defined
+        let mut pos = 0;
+        let defined: Option<Vec<bool>> = if self.max_def_level > 0 {
+            if body.len() < 4 {
+                return Err(format_error("truncated definition levels"));
+            }
+            let len = u32::from_le_bytes([body[0], body[1], body[2], body[3]]) as usize;
+            pos = 4 + len;
+            let levels = decode_hybrid(body.get(4..pos).unwrap_or(&[]), 1, header.num_values)?;
+            Some(
+                levels
+                    .iter()
+                    .map(|&l| l as i16 == self.max_def_level)
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let present = match defined {
+            Some(ref defined) => defined.iter().filter(|&&d| d).count(),
+            None => header.num_values,
+        };
+        let values = &body[pos.min(body.len())..];
+
+        let matching: Vec<bool> = if dictionary_encoded {
+            let dictionary =
+                dictionary.ok_or_else(|| format_error("dictionary page is missing"))?;
+            let bit_width = *values.first().unwrap_or(&0) as u32;
+            let indices = decode_hybrid(values.get(1..).unwrap_or(&[]), bit_width, present)?;
+            indices
+                .iter()
+                .map(|&i| {
+                    dictionary
+                        .get(i as usize)
+                        .cloned()
+                        .ok_or_else(|| format_error("dictionary index out of range"))
+                })
+                .collect::<Result<_>>()?
+        } else {
+            let mut plain = PlainValues {
+                data: values,
+                pos: 0,
+            };
+            (0..present)
+                .map(|_| Ok(self.predicate.evaluate(plain.next()?)))
+                .collect::<Result<_>>()?
+        };
+
+        let mut value = 0;
+        for i in 0..header.num_values {
+            if defined.as_ref().is_some_and(|defined| !defined[i]) {
+                continue;
+            }
+            if matching[value] {
+                unsafe { self.rows.set((first_row + i) as i64) };
+                self.search.matches += 1;
+            }
+            value += 1;
+        }
+        Ok(())
+    }
+}
+
+fn is_dictionary_encoded(encoding: i32) -> bool {
+    encoding == ENCODING_RLE_DICTIONARY || encoding == ENCODING_PLAIN_DICTIONARY
+}
+
+/// Iterates over PLAIN encoded BYTE_ARRAY values.
+struct PlainValues<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> PlainValues<'a> {
+    fn next(&mut self) -> Result<&'a [u8]> {
+        let d = self.data;
+        if d.len() < self.pos + 4 {
+            return Err(format_error("truncated PLAIN value"));
+        }
+        let len = u32::from_le_bytes([
+            d[self.pos],
+            d[self.pos + 1],
+            d[self.pos + 2],
+            d[self.pos + 3],
+        ]) as usize;
+        let start = self.pos + 4;
+        if d.len() < start + len {
+            return Err(format_error("truncated PLAIN value"));
+        }
+        self.pos = start + len;
+        Ok(&d[start..start + len])
+    }
+}
+
+/// Decodes `count` values of the RLE / bit-packing hybrid encoding.
+fn decode_hybrid(data: &[u8], bit_width: u32, count: usize) -> Result<Vec<u32>> {
+    if bit_width > 32 {
+        return Err(format_error(format!(
+            "bit width {} is too large",
+            bit_width
+        )));
+    }
+    let mut t = Thrift::new(data);
+    // runs can repeat a value any number of times, but the hint must not exceed
+    // what the input could plausibly hold
+    let mut values = Vec::with_capacity(count.min(data.len().saturating_mul(8)));
+    let byte_width = bit_width.div_ceil(8) as usize;
+    while values.len() < count {
+        let header = t.varint()?;
+        if header & 1 == 0 {
+            // run of one repeated value
+            let mut value = 0u32;
+            for i in 0..byte_width {
+                value |= (t.byte()? as u32) << (8 * i);
+            }
+            let run = (header >> 1) as usize;
+            values.extend(::std::iter::repeat_n(value, run.min(count - values.len())));
+        } else {
+            // groups of eight bit-packed values
+            let n = ((header >> 1) as usize).saturating_mul(8);
+            let bytes = n.saturating_mul(bit_width as usize) / 8;
+            if data.len() - t.pos < bytes {
+                return Err(format_error("truncated bit-packed run"));
+            }
+            let packed = &data[t.pos..t.pos + bytes];
+            t.pos += bytes;
+            for i in 0..n {
+                if values.len() == count {
+                    break;
+                }
+                let mut value = 0u32;
+                for b in 0..bit_width as usize {
+                    let bit = i * bit_width as usize + b;
+                    if packed[bit / 8] & (1 << (bit % 8)) != 0 {
+                        value |= 1 << b;
+                    }
+                }
+                values.push(value);
+            }
+        }
+    }
+    Ok(values)
+}
+
+#[cfg(test)]
+mod tests {
+    extern crate parquet;
+
+    use self::parquet::basic::Compression;
+    use self::parquet::data_type::{ByteArray, ByteArrayType, Int64Type};
+    use self::parquet::file::properties::WriterProperties;
+    use self::parquet::file::writer::SerializedFileWriter;
+    use self::parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    use super::*;
+
+    const ROWS_PER_GROUP: usize = 200;
+
+    fn text(row: usize) -> String {
+        if row == 321 {
+            "sparser reads parquet".to_string()
+        } else {
+            format!("tweet number {}", row)
+        }
+    }
+
+    fn lang(row: usize) -> Option<&'static str> {
+        match row % 4 {
+            0 => None,
+            1 => Some("de"),
+            // "en" only occurs in the second row group
+            _ if (ROWS_PER_GROUP..2 * ROWS_PER_GROUP).contains(&row) => Some("en"),
+            _ => Some("fr"),
+        }
+    }
+
+    /// Writes three row groups of tweets with small pages.
+    fn write_file(dictionary: bool) -> Vec<u8> {
+        write_file_with(dictionary, 1 << 20)
+    }
+
+    /// Like `write_file`, falling back to PLAIN pages once a column's dictionary
+    /// reaches `dictionary_size` bytes.
+    fn write_file_with(dictionary: bool, dictionary_size: usize) -> Vec<u8> {
+        let schema = Arc::new(
+            parse_message_type(
+                "message tweet {
+                    required int64 id;
+                    required binary text (UTF8);
+                    optional binary lang (UTF8);
+                }",
+            )
+            .unwrap(),
+        );
+        let props = Arc::new(
+            WriterProperties::builder()
+                .set_compression(Compression::UNCOMPRESSED)
+                .set_dictionary_enabled(dictionary)
+                .set_dictionary_page_size_limit(dictionary_size)
+                .set_data_page_size_limit(512)
+                .set_write_batch_size(16)
+                .build(),
+        );
+        let mut file = Vec::new();
+        let mut writer = SerializedFileWriter::new(&mut file, schema, props).unwrap();
+        for group in 0..3 {
+            let rows: Vec<usize> = (group * ROWS_PER_GROUP..(group + 1) * ROWS_PER_GROUP).collect();
+            let mut row_group = writer.next_row_group().unwrap();
+            let mut column = 0;
+            while let Some(mut col) = row_group.next_column().unwrap() {
+                match column {
+                    0 => {
+                        let ids: Vec<i64> = rows.iter().map(|&r| r as i64).collect();
+                        col.typed::<Int64Type>()
+                            .write_batch(&ids, None, None)
+                            .unwrap();
+                    }
+                    1 => {
+                        let texts: Vec<ByteArray> = rows
+                            .iter()
+                            .map(|&r| ByteArray::from(text(r).as_str()))
+                            .collect();
+                        col.typed::<ByteArrayType>()
+                            .write_batch(&texts, None, None)
+                            .unwrap();
+                    }
+                    _ => {
+                        let langs: Vec<ByteArray> = rows
+                            .iter()
+                            .filter_map(|&r| lang(r).map(ByteArray::from))
+                            .collect();
+                        let levels: Vec<i16> =
+                            rows.iter().map(|&r| lang(r).is_some() as i16).collect();
+                        col.typed::<ByteArrayType>()
+                            .write_batch(&langs, Some(&levels), None)
+                            .unwrap();
+                    }
+                }
+                col.close().unwrap();
+                column += 1;
+            }
+            row_group.close().unwrap();
+        }
+        writer.close().unwrap();
+        file
+    }
+
+    fn matching_rows(search: &ColumnSearch, num_rows: usize) -> Vec<usize> {
+        (0..num_rows)
+            .filter(|&r| search.rows.is_set(r as i64))
+            .collect()
+    }
+
+    #[test]
+    fn test_read_footer() {
+        let file = write_file(false);
+        let reader = ParquetReader::new(&file).unwrap();
+        assert_eq!(600, reader.num_rows());
+        assert_eq!(3, reader.num_row_groups());
+        assert_eq!(Some(2), reader.column_index("lang"));
+        assert_eq!(1, reader.columns()[2].max_def_level);
+        assert!(ParquetReader::new(b"PAR1 not really PAR1").is_err());
+    }
+
+    #[test]
+    fn test_row_count_is_capped_by_row_groups() {
+        let mut file = write_file(false);
+        // field 3 of the file metadata, 600 rows, raised to 8191
+        let len = &file[file.len() - 8..file.len() - 4];
+        let footer = file.len() - 8 - u32::from_le_bytes([len[0], len[1], len[2], len[3]]) as usize;
+        let at = file[footer..]
+            .windows(3)
+            .position(|w| w == [0x16, 0xb0, 0x09])
+            .unwrap();
+        file[footer + at + 1..footer + at + 3].copy_from_slice(&[0xfe, 0x7f]);
+        let reader = ParquetReader::new(&file).unwrap();
+        assert_eq!(600, reader.num_rows());
+    }
+
+    #[test]
+    fn test_plain_pages_are_skipped() {
+        let file = write_file(false);
+        let reader = ParquetReader::new(&file).unwrap();
+        let predicate = ByteArrayPredicate::Contains(b"parquet".to_vec());
+        let search = reader.search(1, &predicate).unwrap();
+        assert_eq!(vec![321], matching_rows(&search, 600));
+        assert_eq!(1, search.matches);
+        assert_eq!(1, search.pages_scanned);
+        assert!(search.pages_skipped > 3);
+    }
+
+    #[test]
+    fn test_optional_plain_column() {
+        let file = write_file(false);
+        let reader = ParquetReader::new(&file).unwrap();
+        let search = reader
+            .search(2, &ByteArrayPredicate::Equals(b"en".to_vec()))
+            .unwrap();
+        let expected: Vec<usize> = (0..600).filter(|&r| lang(r) == Some("en")).collect();
+        assert_eq!(expected, matching_rows(&search, 600));
+        assert_eq!(100, search.matches);
+    }
+
+    #[test]
+    fn test_dictionary_skips_row_groups() {
+        let file = write_file(true);
+        let reader = ParquetReader::new(&file).unwrap();
+        let search = reader
+            .search(2, &ByteArrayPredicate::Equals(b"en".to_vec()))
+            .unwrap();
+        let expected: Vec<usize> = (0..600).filter(|&r| lang(r) == Some("en")).collect();
+        assert_eq!(expected, matching_rows(&search, 600));
+        assert_eq!(2, search.row_groups_skipped);
+
+        let search = reader
+            .search(1, &ByteArrayPredicate::Contains(b"number 59".to_vec()))
+            .unwrap();
+        let expected: Vec<usize> = (0..600)
+            .filter(|&r| text(r).contains("number 59"))
+            .collect();
+        assert_eq!(expected, matching_rows(&search, 600));
+    }
+
+    #[test]
+    fn test_plain_fallback_pages_after_dictionary() {
+        // each dictionary holds the first few texts of its row group only
+        let file = write_file_with(true, 256);
+        let reader = ParquetReader::new(&file).unwrap();
+        let predicate = ByteArrayPredicate::Contains(b"parquet".to_vec());
+        let search = reader.search(1, &predicate).unwrap();
+        assert_eq!(vec![321], matching_rows(&search, 600));
+        assert_eq!(3, search.row_groups_skipped);
+    }
+
+    #[test]
+    fn test_unsupported_columns() {
+        let file = write_file(false);
+        let reader = ParquetReader::new(&file).unwrap();
+        let predicate = ByteArrayPredicate::Equals(b"1".to_vec());
+        assert!(reader.search(0, &predicate).is_err());
+        assert!(reader.search(9, &predicate).is_err());
+    }
+
+    #[test]
+    fn test_malformed_metadata() {
+        // a page header with a negative uncompressed size
+        let header = [0x15, 0x00, 0x15, 0x01, 0x00];
+        assert!(read_page_header(&mut Thrift::new(&header)).is_err());
+
+        // field 32767 followed by a field id delta of one
+        let fields = [0x05, 0xfe, 0xff, 0x03, 0x00, 0x15, 0x00, 0x00];
+        let result = Thrift::new(&fields).read_struct(|t, _, ty| t.skip(ty));
+        assert!(result.is_err());
+
+        // a map of two i32 to boolean entries
+        let map = [0x02, 0x51, 0x02, 0x01, 0x04, 0x02, 0x07];
+        let mut t = Thrift::new(&map);
+        t.skip(CT_MAP).unwrap();
+        assert_eq!(&[0x07], &map[t.pos..]);
+        // collections claiming more elements than there are bytes left
+        let map = [0xff, 0xff, 0xff, 0xff, 0x0f, 0x55];
+        assert!(Thrift::new(&map).skip(CT_MAP).is_err());
+        let list = [0xf5, 0xff, 0xff, 0xff, 0xff, 0x0f];
+        assert!(Thrift::new(&list).skip(CT_LIST).is_err());
+        assert!(Thrift::new(&list).read_list(|t, ty| t.skip(ty)).is_err());
+    }
+
+    #[test]
+    fn test_dictionary_index_out_of_range() {
+        let file = write_file(true);
+        let reader = ParquetReader::new(&file).unwrap();
+        let predicate = ByteArrayPredicate::Equals(b"tweet".to_vec());
+        let filter = predicate.raw_filter();
+        let mut rows = Bitmap::zeroed(600);
+        let mut search = ColumnSearch {
+            rows: Bitmap::zeroed(0),
+            matches: 0,
+            pages_scanned: 0,
+            pages_skipped: 0,
+            row_groups_skipped: 0,
+        };
+        let mut chunk = ChunkScan {
+            reader: &reader,
+            meta: &reader.row_groups[0].columns[1],
+            max_def_level: 0,
+            predicate: &predicate,
+            filter: &filter,
+            rows: &mut rows,
+            first_row: 0,
+            search: &mut search,
+        };
+        // bit width 2 and a run of two 3s, for a dictionary of two entries
+        let body = [2, 0b100, 3];
+        let header = PageHeader {
+            page_type: PAGE_DATA,
+            uncompressed_size: body.len(),
+            compressed_size: body.len(),
+            num_values: 2,
+            encoding: ENCODING_RLE_DICTIONARY,
+        };
+        let e = chunk
+            .scan_data_page(&body, &header, Some(&[true, false]), 0)
+            .unwrap_err();
+        assert!(e.to_string().contains("dictionary index out of range"));
+    }
+
+    #[test]
+    fn test_decode_hybrid() {
+        // a run of four 1s followed by one bit-packed group 0..8 with bit width 3
+        let data = [0b1000, 1, 0b11, 0b10001000, 0b11000110, 0b11111010];
+        assert_eq!(
+            vec![1, 1, 1, 1, 0, 1, 2, 3, 4, 5, 6, 7],
+            decode_hybrid(&data, 3, 12).unwrap()
+        );
+        assert!(decode_hybrid(&data, 33, 12).is_err());
+        // a count that does not fit the input is not allocated up front
+        assert!(decode_hybrid(&data, 3, usize::MAX).is_err());
+        // a bit-packed run longer than the address space
+        let data = [0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert!(decode_hybrid(&data, 32, 1).is_err());
+    }
+}
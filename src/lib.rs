@@ -1,7 +1,29 @@
+extern crate flate2;
 extern crate libc;
+extern crate lz4_flex;
 extern crate rand;
+extern crate regex;
+extern crate regex_syntax;
+extern crate serde_json;
+extern crate unicode_normalization;
+extern crate zstd;
+pub mod adaptive;
+pub mod aho_corasick;
 pub mod bitmap;
+pub mod calibration;
+pub mod cascade;
+pub mod compression;
+pub mod fingerprint;
+pub mod formats;
+pub mod optimizer;
+pub mod parallel;
+pub mod plan_cache;
+pub mod query;
+pub mod raw_filter;
 pub mod sparser_kernels;
+pub mod stats;
+pub mod stream;
+pub mod structural;
 pub mod utils;
 #[cfg(test)]
 mod tests {
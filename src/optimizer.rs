@@ -0,0 +1,696 @@
+use std::fmt;
+
+use serde_json::{self, Value};
+
+use cascade::{for_each_record, Cascade};
+use formats::csv::CsvFormat;
+use raw_filter::RawFilter;
+use utils::error::{Result, SparserError};
+
+/// Longest cascade the optimizer considers.
+pub const DEFAULT_MAX_DEPTH: usize = 4;
+
+/// Number of most selective candidate filters the optimizer enumerates cascades over.
+pub const DEFAULT_MAX_CANDIDATES: usize = 12;
+
+/// Per-operation costs the optimizer uses to compare cascades, in nanoseconds per
+/// byte of input the operation looks at.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CostModel {
+    /// Cost of running one raw filter over a byte.
+    pub search_ns_per_byte: f64,
+    /// Cost of verifying (fully parsing) a byte of a candidate record.
+    pub verify_ns_per_byte: f64,
+}
+
+impl Default for CostModel {
+    fn default() -> Self {
+        CostModel {
+            search_ns_per_byte: 0.1,
+            verify_ns_per_byte: 2.0,
+        }
+    }
+}
+
+impl CostModel {
+    /// Returns the estimated cost per input byte of a cascade, where `passed[i]` is
+    /// the fraction of input bytes in records passing the first `i + 1` filters.
+    /// The first filter looks at every byte, each later filter only at the records
+    /// passing the filters before it, and the verifier at the records passing all.
+    pub fn cascade_cost(&self, passed: &[f64]) -> f64 {
+        self.cascade_cost_with(&vec![1.0; passed.len()], passed)
+    }
+
+    /// Like `cascade_cost`, with the search cost of filter `i` scaled by
+    /// `search_costs[i]`, as returned by `RawFilter::search_cost`.
+    pub fn cascade_cost_with(&self, search_costs: &[f64], passed: &[f64]) -> f64 {
+        let mut reaching = 1.0;
+        let mut cost = 0.0;
+        for (&search_cost, &fraction) in search_costs.iter().zip(passed) {
+            cost += self.search_ns_per_byte * search_cost * reaching;
+            reaching = fraction;
+        }
+        cost + self.verify_ns_per_byte * reaching
+    }
+
+    /// Returns the estimated cost per input byte of `cascade`, with `passed` as in
+    /// `cascade_cost`.
+    pub fn filter_cascade_cost(&self, cascade: &Cascade, passed: &[f64]) -> f64 {
+        self.cascade_cost_with(&search_costs(cascade.filters()), passed)
+    }
+}
+
+/// The cascade chosen by the optimizer together with the estimates it was chosen on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub cascade: Cascade,
+    /// Fraction of sample bytes in records passing each level of the cascade.
+    pub passed: Vec<f64>,
+    /// Estimated cost per input byte, in nanoseconds.
+    pub estimated_cost: f64,
+    /// Measurements the cascade was chosen on.
+    pub explain: Explain,
+}
+
+/// A candidate filter as measured on the optimizer's sample.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CandidateStats {
+    pub filter: RawFilter,
+    /// Fraction of sample bytes in records passing the filter.
+    pub passed: f64,
+    /// Estimated cost of running the filter over a byte, in nanoseconds.
+    pub ns_per_byte: f64,
+    /// Whether the filter was among the most selective candidates cascades were
+    /// enumerated over.
+    pub considered: bool,
+}
+
+/// Why the optimizer chose a plan: what it measured and what it estimated.
+///
+/// Renders as text with `Display` and as JSON with `to_json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Explain {
+    pub sample_records: usize,
+    pub sample_bytes: usize,
+    /// Distinct candidates, most selective first.
+    pub candidates: Vec<CandidateStats>,
+    /// `joint[i][j]` is the fraction of sample bytes in records passing both
+    /// candidates `i` and `j`.
+    pub joint: Vec<Vec<f64>>,
+    /// Indices into `candidates` of the cascade's filters, in cascade order.
+    pub chosen: Vec<usize>,
+    /// Fraction of sample bytes in records passing each level of the cascade.
+    pub passed: Vec<f64>,
+    /// Estimated cost per input byte of the cascade, in nanoseconds.
+    pub estimated_cost: f64,
+    /// Estimated cost per input byte of verifying every record, in nanoseconds.
+    pub parse_everything_cost: f64,
+}
+
+impl Explain {
+    pub fn to_json(&self) -> Value {
+        let candidates = self
+            .candidates
+            .iter()
+            .map(|c| {
+                serde_json::json!({
+                    "filter": c.filter.to_string(),
+                    "passed": c.passed,
+                    "ns_per_byte": c.ns_per_byte,
+                    "considered": c.considered,
+                })
+            })
+            .collect::<Vec<_>>();
+        let cascade = self
+            .chosen
+            .iter()
+            .zip(&self.passed)
+            .map(|(&i, &passed)| serde_json::json!({"candidate": i, "passed": passed}))
+            .collect::<Vec<_>>();
+        serde_json::json!({
+            "sample_records": self.sample_records,
+            "sample_bytes": self.sample_bytes,
+            "candidates": candidates,
+            "joint": self.joint,
+            "cascade": cascade,
+            "estimated_cost": self.estimated_cost,
+            "parse_everything_cost": self.parse_everything_cost,
+        })
+    }
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "candidates on a sample of {} records ({} bytes):",
+            self.sample_records, self.sample_bytes
+        )?;
+        writeln!(f, "  #     passed  ns/byte  filter")?;
+        for (i, c) in self.candidates.iter().enumerate() {
+            writeln!(
+                f,
+                "  {:<3} {:>7.2}%  {:>7.3}  {}{}",
+                i,
+                c.passed * 100.0,
+                c.ns_per_byte,
+                c.filter,
+                if c.considered {
+                    ""
+                } else {
+                    " (not considered)"
+                }
+            )?;
+        }
+        if self.candidates.len() > 1 {
+            writeln!(f, "joint pass rates:")?;
+            write!(f, "     ")?;
+            for j in 0..self.candidates.len() {
+                write!(f, " {:>7}", j)?;
+            }
+            writeln!(f)?;
+            for (i, row) in self.joint.iter().enumerate() {
+                write!(f, "  {:<3}", i)?;
+                for rate in row {
+                    write!(f, " {:>6.2}%", rate * 100.0)?;
+                }
+                writeln!(f)?;
+            }
+        }
+        if self.chosen.is_empty() {
+            writeln!(f, "cascade: none, every record is verified")?;
+        } else {
+            writeln!(f, "cascade:")?;
+            for (level, (&i, passed)) in self.chosen.iter().zip(&self.passed).enumerate() {
+                writeln!(
+                    f,
+                    "  {}. #{} {} passes {:.2}%",
+                    level + 1,
+                    i,
+                    self.candidates[i].filter,
+                    passed * 100.0
+                )?;
+            }
+        }
+        write!(
+            f,
+            "estimated cost {:.3} ns/byte, parsing everything {:.3} ns/byte",
+            self.estimated_cost, self.parse_everything_cost
+        )
+    }
+}
+
+/// Optimizer picks the cheapest raw-filter cascade for a sample of records.
+///
+/// Every candidate is run over every sample record once, recording which records
+/// pass. Cascades are then costed from these bitmaps, so joint selectivities of
+/// correlated filters are measured rather than assumed independent. All candidates
+/// must be necessary conditions of the query, so that any subset is sound.
+#[derive(Debug, Clone)]
+pub struct Optimizer {
+    cost_model: CostModel,
+    max_depth: usize,
+    max_candidates: usize,
+}
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Optimizer::new(CostModel::default())
+    }
+}
+
+impl Optimizer {
+    pub fn new(cost_model: CostModel) -> Self {
+        Optimizer {
+            cost_model,
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_candidates: DEFAULT_MAX_CANDIDATES,
+        }
+    }
+
+    pub fn with_max_depth(mut self, max_depth: usize) -> Self {
+        self.max_depth = max_depth;
+        self
+    }
+
+    pub fn with_max_candidates(mut self, max_candidates: usize) -> Self {
+        self.max_candidates = max_candidates;
+        self
+    }
+
+    pub fn cost_model(&self) -> &CostModel {
+        &self.cost_model
+    }
+
+    /// Returns the cheapest cascade of at most `max_depth` filters from `candidates`
+    /// for the records in `sample`.
+    pub fn optimize(&self, sample: &[&[u8]], candidates: &[RawFilter]) -> Result<Plan> {
+        let weights = sample_weights(sample)?;
+        let total = weights.iter().sum::<f64>().max(1.0);
+
+        let mut measured: Vec<(RawFilter, Vec<u64>, f64)> = Vec::new();
+        for filter in candidates {
+            if measured.iter().any(|m| m.0 == *filter) {
+                continue;
+            }
+            let bits = pass_bitmap(sample, filter);
+            let passed = weighted(&bits, &weights) / total;
+            measured.push((filter.clone(), bits, passed));
+        }
+        // keep the most selective candidates; the sort is stable for equal rates
+        measured.sort_by(|a, b| a.2.partial_cmp(&b.2).unwrap());
+        let considered = measured.len().min(self.max_candidates);
+
+        let mut search = Search {
+            cost_model: &self.cost_model,
+            max_depth: self.max_depth,
+            bits: measured[..considered].iter().map(|m| m.1.clone()).collect(),
+            search_costs: measured[..considered]
+                .iter()
+                .map(|m| m.0.search_cost())
+                .collect(),
+            weights: &weights,
+            total,
+            best_cost: self.cost_model.cascade_cost(&[]),
+            best: Vec::new(),
+            best_passed: Vec::new(),
+        };
+        let all = vec![!0u64; bitmap_words(sample.len())];
+        search.extend(&all, &mut Vec::new(), &mut Vec::new(), 0.0);
+
+        let explain = Explain {
+            chosen: search.best,
+            passed: search.best_passed,
+            estimated_cost: search.best_cost,
+            ..self.explain(&weights, &measured, considered)
+        };
+        Ok(self.plan(explain))
+    }
+
+    /// Returns the plan for a given cascade, with its pass rates and cost estimated
+    /// on `sample`, e.g. to check whether a previously chosen cascade still fits.
+    pub fn evaluate(&self, sample: &[&[u8]], cascade: &Cascade) -> Result<Plan> {
+        let weights = sample_weights(sample)?;
+        let total = weights.iter().sum::<f64>().max(1.0);
+
+        let mut mask = vec![!0u64; bitmap_words(sample.len())];
+        let mut measured = Vec::with_capacity(cascade.len());
+        let mut passed = Vec::with_capacity(cascade.len());
+        for filter in cascade.filters() {
+            let bits = pass_bitmap(sample, filter);
+            for (m, b) in mask.iter_mut().zip(&bits) {
+                *m &= b;
+            }
+            passed.push(weighted(&mask, &weights) / total);
+            let alone = weighted(&bits, &weights) / total;
+            measured.push((filter.clone(), bits, alone));
+        }
+
+        let explain = Explain {
+            chosen: (0..cascade.len()).collect(),
+            estimated_cost: self.cost_model.filter_cascade_cost(cascade, &passed),
+            passed,
+            ..self.explain(&weights, &measured, cascade.len())
+        };
+        Ok(self.plan(explain))
+    }
+
+    /// Returns the explanation for the measured candidates, of which the first
+    /// `considered` were enumerated over, without a chosen cascade.
+    fn explain(
+        &self,
+        weights: &[f64],
+        measured: &[(RawFilter, Vec<u64>, f64)],
+        considered: usize,
+    ) -> Explain {
+        let total = weights.iter().sum::<f64>().max(1.0);
+        let joint = measured
+            .iter()
+            .map(|a| {
+                measured
+                    .iter()
+                    .map(|b| {
+                        let both: Vec<u64> = a.1.iter().zip(&b.1).map(|(x, y)| x & y).collect();
+                        weighted(&both, weights) / total
+                    })
+                    .collect()
+            })
+            .collect();
+        Explain {
+            sample_records: weights.len(),
+            sample_bytes: weights.iter().sum::<f64>() as usize,
+            candidates: measured
+                .iter()
+                .enumerate()
+                .map(|(i, m)| CandidateStats {
+                    filter: m.0.clone(),
+                    passed: m.2,
+                    ns_per_byte: self.cost_model.search_ns_per_byte * m.0.search_cost(),
+                    considered: i < considered,
+                })
+                .collect(),
+            joint,
+            chosen: Vec::new(),
+            passed: Vec::new(),
+            estimated_cost: self.cost_model.cascade_cost(&[]),
+            parse_everything_cost: self.cost_model.cascade_cost(&[]),
+        }
+    }
+
+    fn plan(&self, explain: Explain) -> Plan {
+        Plan {
+            cascade: Cascade::new(
+                explain
+                    .chosen
+                    .iter()
+                    .map(|&i| explain.candidates[i].filter.clone())
+                    .collect(),
+            ),
+            passed: explain.passed.clone(),
+            estimated_cost: explain.estimated_cost,
+            explain,
+        }
+    }
+}
+
+/// Returns the relative search cost of each filter.
+fn search_costs(filters: &[RawFilter]) -> Vec<f64> {
+    filters.iter().map(RawFilter::search_cost).collect()
+}
+
+/// Returns the length of each sample record, which weighs it in pass rates.
+fn sample_weights(sample: &[&[u8]]) -> Result<Vec<f64>> {
+    if sample.is_empty() {
+        return Err(SparserError::OptimizerError(
+            "cannot calibrate on an empty sample".to_string(),
+        ));
+    }
+    Ok(sample.iter().map(|r| r.len() as f64).collect())
+}
+
+/// Depth-first enumeration of ordered cascades, pruned once the search cost of a
+/// prefix alone exceeds the best complete cascade.
+struct Search<'a> {
+    cost_model: &'a CostModel,
+    max_depth: usize,
+    bits: Vec<Vec<u64>>,
+    search_costs: Vec<f64>,
+    weights: &'a [f64],
+    total: f64,
+    best_cost: f64,
+    best: Vec<usize>,
+    best_passed: Vec<f64>,
+}
+
+impl<'a> Search<'a> {
+    fn extend(&mut self, mask: &[u64], prefix: &mut Vec<usize>, passed: &mut Vec<f64>, cost: f64) {
+        if prefix.len() == self.max_depth {
+            return;
+        }
+        let reaching = passed.last().cloned().unwrap_or(1.0);
+        if cost + self.cost_model.search_ns_per_byte * reaching >= self.best_cost {
+            return;
+        }
+        for i in 0..self.bits.len() {
+            if prefix.contains(&i) {
+                continue;
+            }
+            let cost = cost + self.cost_model.search_ns_per_byte * self.search_costs[i] * reaching;
+            if cost >= self.best_cost {
+                continue;
+            }
+            let next: Vec<u64> = mask.iter().zip(&self.bits[i]).map(|(a, b)| a & b).collect();
+            let fraction = weighted(&next, self.weights) / self.total;
+            prefix.push(i);
+            passed.push(fraction);
+            let total_cost = cost + self.cost_model.verify_ns_per_byte * fraction;
+            if total_cost < self.best_cost {
+                self.best_cost = total_cost;
+                self.best = prefix.clone();
+                self.best_passed = passed.clone();
+            }
+            self.extend(&next, prefix, passed, cost);
+            prefix.pop();
+            passed.pop();
+        }
+    }
+}
+
+fn bitmap_words(n: usize) -> usize {
+    n.div_ceil(64)
+}
+
+fn pass_bitmap(sample: &[&[u8]], filter: &RawFilter) -> Vec<u64> {
+    let mut bits = vec![0u64; bitmap_words(sample.len())];
+    for (i, record) in sample.iter().enumerate() {
+        if filter.matches(record) {
+            bits[i / 64] |= 1 << (i % 64);
+        }
+    }
+    bits
+}
+
+fn weighted(bits: &[u64], weights: &[f64]) -> f64 {
+    let mut sum = 0.0;
+    for (w, &word) in bits.iter().enumerate() {
+        let mut word = word;
+        while word != 0 {
+            let i = w * 64 + word.trailing_zeros() as usize;
+            if i < weights.len() {
+                sum += weights[i];
+            }
+            word &= word - 1;
+        }
+    }
+    sum
+}
+
+/// Returns up to `max` records of `data`, spread evenly over the whole input.
+pub fn sample_records(data: &[u8], delimiter: u8, max: usize) -> Vec<&[u8]> {
+    let mut records = Vec::new();
+    for_each_record(data, delimiter, |_, record| records.push(record));
+    spread(records, max)
+}
+
+/// Like `sample_records`, for CSV records, which may contain quoted newlines.
+pub fn sample_csv_records<'a>(format: &CsvFormat, data: &'a [u8], max: usize) -> Vec<&'a [u8]> {
+    let mut records = Vec::new();
+    format.for_each_record(data, |_, record| records.push(record));
+    spread(records, max)
+}
+
+fn spread(records: Vec<&[u8]>, max: usize) -> Vec<&[u8]> {
+    if records.len() <= max {
+        return records;
+    }
+    let step = records.len() as f64 / max as f64;
+    (0..max)
+        .map(|i| records[(i as f64 * step) as usize])
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_data() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..1000 {
+            // "lang" appears everywhere, "en" in half the records, "rust" in a fifth
+            let lang = if i % 2 == 0 { "en" } else { "de" };
+            let text = if i % 5 == 0 { "rust" } else { "golang" };
+            data.extend_from_slice(
+                format!("{{\"text\": \"{} {}\", \"lang\": \"{}\"}}\n", text, i, lang).as_bytes(),
+            );
+        }
+        data
+    }
+
+    #[test]
+    fn test_cascade_cost() {
+        let model = CostModel {
+            search_ns_per_byte: 1.0,
+            verify_ns_per_byte: 10.0,
+        };
+        assert_eq!(10.0, model.cascade_cost(&[]));
+        assert_eq!(1.0 + 5.0, model.cascade_cost(&[0.5]));
+        assert_eq!(1.0 + 0.5 + 1.0, model.cascade_cost(&[0.5, 0.1]));
+        assert_eq!(
+            4.0 + 0.5 + 1.0,
+            model.cascade_cost_with(&[4.0, 1.0], &[0.5, 0.1])
+        );
+    }
+
+    #[test]
+    fn test_optimizer_prices_search_costs() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 1000);
+        // as selective as "rust", but four times as expensive to search
+        let any_of = RawFilter::any_of(&["rust"]);
+        let plan = Optimizer::default()
+            .optimize(&sample, &[any_of.clone(), RawFilter::substring("rust")])
+            .unwrap();
+        assert_eq!(vec![RawFilter::substring("rust")], plan.cascade.filters());
+        let candidate = |filter: &RawFilter| {
+            plan.explain
+                .candidates
+                .iter()
+                .find(|c| c.filter == *filter)
+                .unwrap()
+                .ns_per_byte
+        };
+        assert_eq!(
+            4.0 * candidate(&RawFilter::substring("rust")),
+            candidate(&any_of)
+        );
+
+        let plan = Optimizer::default()
+            .evaluate(&sample, &Cascade::new(vec![any_of]))
+            .unwrap();
+        assert!((plan.estimated_cost - (0.4 + 2.0 * plan.passed[0])).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_optimizer_picks_selective_filter() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 1000);
+        let candidates = vec![
+            RawFilter::substring("lang"),
+            RawFilter::substring("\"en\""),
+            RawFilter::substring("rust"),
+        ];
+        let plan = Optimizer::default().optimize(&sample, &candidates).unwrap();
+        assert_eq!(RawFilter::substring("rust"), plan.cascade.filters()[0]);
+        assert!(!plan
+            .cascade
+            .filters()
+            .contains(&RawFilter::substring("lang")));
+        assert!(plan.estimated_cost < CostModel::default().cascade_cost(&[]));
+        assert!(plan.passed[0] > 0.15 && plan.passed[0] < 0.25);
+    }
+
+    #[test]
+    fn test_optimizer_skips_useless_filters() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 100);
+        let plan = Optimizer::default()
+            .optimize(&sample, &[RawFilter::substring("lang")])
+            .unwrap();
+        assert!(plan.cascade.is_empty());
+    }
+
+    #[test]
+    fn test_optimizer_max_depth() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 1000);
+        let model = CostModel {
+            search_ns_per_byte: 0.001,
+            verify_ns_per_byte: 100.0,
+        };
+        let candidates = vec![RawFilter::substring("\"en\""), RawFilter::substring("rust")];
+        let plan = Optimizer::new(model)
+            .optimize(&sample, &candidates)
+            .unwrap();
+        assert_eq!(2, plan.cascade.len());
+        let plan = Optimizer::new(model)
+            .with_max_depth(1)
+            .optimize(&sample, &candidates)
+            .unwrap();
+        assert_eq!(1, plan.cascade.len());
+    }
+
+    #[test]
+    fn test_explain() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 1000);
+        let candidates = vec![
+            RawFilter::substring("lang"),
+            RawFilter::substring("\"en\""),
+            RawFilter::substring("rust"),
+            RawFilter::substring("rust"),
+        ];
+        let plan = Optimizer::default()
+            .with_max_candidates(2)
+            .optimize(&sample, &candidates)
+            .unwrap();
+        let explain = &plan.explain;
+        assert_eq!(1000, explain.sample_records);
+        assert_eq!(3, explain.candidates.len());
+        assert_eq!(RawFilter::substring("rust"), explain.candidates[0].filter);
+        assert!(!explain.candidates[2].considered);
+        assert_eq!(1.0, explain.candidates[2].passed);
+        // half of the "rust" records are also "en" records
+        assert_eq!(explain.candidates[0].passed, explain.joint[0][0]);
+        assert!((explain.joint[0][1] - explain.candidates[0].passed / 2.0).abs() < 0.01);
+        assert_eq!(explain.joint[0][1], explain.joint[1][0]);
+        let chosen: Vec<&RawFilter> = explain
+            .chosen
+            .iter()
+            .map(|&i| &explain.candidates[i].filter)
+            .collect();
+        assert_eq!(plan.cascade.filters().iter().collect::<Vec<_>>(), chosen);
+        assert_eq!(plan.passed, explain.passed);
+        assert_eq!(2.0, explain.parse_everything_cost);
+
+        let text = explain.to_string();
+        assert!(text.contains("1000 records"));
+        assert!(text.contains("\"lang\" (not considered)"));
+        assert!(text.contains("1. #0 \"rust\" passes"));
+        let json = explain.to_json();
+        assert_eq!(3, json["candidates"].as_array().unwrap().len());
+        assert_eq!("\"rust\"", json["candidates"][0]["filter"]);
+        assert_eq!(0, json["cascade"][0]["candidate"]);
+        assert_eq!(2.0, json["parse_everything_cost"]);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 1000);
+        let optimizer = Optimizer::default();
+        let cascade = Cascade::new(vec![
+            RawFilter::substring("\"en\""),
+            RawFilter::substring("rust"),
+        ]);
+        let plan = optimizer.evaluate(&sample, &cascade).unwrap();
+        assert_eq!(cascade, plan.cascade);
+        assert_eq!(2, plan.passed.len());
+        assert!((plan.passed[0] - 0.5).abs() < 0.01);
+        assert!((plan.passed[1] - 0.1).abs() < 0.01);
+        assert_eq!(
+            optimizer.cost_model().cascade_cost(&plan.passed),
+            plan.estimated_cost
+        );
+        assert_eq!(vec![0, 1], plan.explain.chosen);
+        assert!((plan.explain.candidates[1].passed - 0.2).abs() < 0.01);
+
+        // the optimizer's own choice evaluates to the same estimate
+        let best = optimizer.optimize(&sample, cascade.filters()).unwrap();
+        let again = optimizer.evaluate(&sample, &best.cascade).unwrap();
+        assert_eq!(best.passed, again.passed);
+        assert!(optimizer.evaluate(&[], &cascade).is_err());
+    }
+
+    #[test]
+    fn test_empty_sample() {
+        assert!(Optimizer::default().optimize(&[], &[]).is_err());
+    }
+
+    #[test]
+    fn test_sample_records() {
+        let data = sample_data();
+        let sample = sample_records(&data, b'\n', 10);
+        assert_eq!(10, sample.len());
+        assert!(sample[0].starts_with(b"{\"text\": \"rust 0\""));
+        assert!(sample[9].starts_with(b"{\"text\": \"rust 900\""));
+    }
+
+    #[test]
+    fn test_sample_csv_records() {
+        let data = b"1,\"a\nb\"\r\n2,c\r\n3,\"d\ne\"\r\n";
+        let sample = sample_csv_records(&CsvFormat::csv(), data, 10);
+        let expected: Vec<&[u8]> = vec![b"1,\"a\nb\"", b"2,c", b"3,\"d\ne\""];
+        assert_eq!(expected, sample);
+        assert_eq!(2, sample_csv_records(&CsvFormat::csv(), data, 2).len());
+    }
+}
@@ -0,0 +1,268 @@
+use std::ops::Range;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::thread;
+
+use cascade::{Cascade, LevelCounts};
+use formats::csv::CsvFormat;
+use sparser_kernels;
+use stats::ScanStats;
+use utils::buffer::Buffer;
+
+/// Number of partitions created per worker thread, so that threads finishing early
+/// can pick up more work.
+const PARTITIONS_PER_THREAD: usize = 4;
+
+/// Partitions smaller than this are not worth handing to another thread.
+const MIN_PARTITION_SIZE: usize = 64 * 1024;
+
+/// ParallelScanner runs a cascade over a buffer on several threads. The buffer is
+/// split at record boundaries, partitions are processed by a fixed pool of worker
+/// threads, and the results are merged in input order, so the output is the same
+/// for any thread count.
+#[derive(Debug, Clone)]
+pub struct ParallelScanner {
+    threads: usize,
+    delimiter: u8,
+    min_partition_size: usize,
+    csv: Option<CsvFormat>,
+}
+
+impl ParallelScanner {
+    /// Creates a scanner using `threads` worker threads. Zero uses one thread per
+    /// available CPU.
+    pub fn new(threads: usize, delimiter: u8) -> Self {
+        let threads = if threads == 0 {
+            thread::available_parallelism().map_or(1, |n| n.get())
+        } else {
+            threads
+        };
+        ParallelScanner {
+            threads,
+            delimiter,
+            min_partition_size: MIN_PARTITION_SIZE,
+            csv: None,
+        }
+    }
+
+    /// Sets the smallest partition size in bytes.
+    pub fn with_min_partition_size(mut self, size: usize) -> Self {
+        self.min_partition_size = size.max(1);
+        self
+    }
+
+    /// Scans records of `format`, which end at newlines outside quoted fields, so
+    /// partitions never split a quoted field. The delimiter must be `\n`.
+    pub fn with_csv_records(mut self, format: CsvFormat) -> Self {
+        self.csv = Some(format);
+        self
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    /// Splits `data` into at most `n` contiguous ranges that start and end at record
+    /// boundaries and together cover all of `data`.
+    pub fn partition(&self, data: &[u8], n: usize) -> Vec<Range<usize>> {
+        let n = n.max(1).min(data.len() / self.min_partition_size).max(1);
+        let target = data.len() / n;
+        let mut partitions = Vec::with_capacity(n);
+        let mut start = 0;
+        while start < data.len() {
+            let mut end = (start + target).max(start + 1);
+            if partitions.len() + 1 == n || end >= data.len() {
+                end = data.len();
+            } else if let Some(ref format) = self.csv {
+                end = start + format.next_record_start(&data[start..], end - start);
+            } else {
+                // extend to just past the next delimiter
+                end = sparser_kernels::find_byte(&data[end - 1..], self.delimiter)
+                    .map_or(data.len(), |i| end + i);
+            }
+            partitions.push(start..end);
+            start = end;
+        }
+        partitions
+    }
+
+    /// Returns the offsets, in input order, of the records in `buffer` that pass
+    /// `cascade` and `verifier`.
+    pub fn search<F>(&self, buffer: &Buffer, cascade: &Cascade, verifier: F) -> Vec<usize>
+    where
+        F: Fn(&[u8]) -> bool + Sync,
+    {
+        self.search_with_stats(buffer, cascade, verifier).0
+    }
+
+    /// Like `search`, and also returns the statistics of the scan. Filter and verify
+    /// times are summed over the worker threads.
+    pub fn search_with_stats<F>(
+        &self,
+        buffer: &Buffer,
+        cascade: &Cascade,
+        verifier: F,
+    ) -> (Vec<usize>, ScanStats)
+    where
+        F: Fn(&[u8]) -> bool + Sync,
+    {
+        let data = buffer.data();
+        let partitions = self.partition(data, self.threads * PARTITIONS_PER_THREAD);
+        if self.threads == 1 || partitions.len() == 1 {
+            return scan_partition(data, 0..data.len(), cascade, self, &verifier);
+        }
+
+        let next = AtomicUsize::new(0);
+        let results = Mutex::new(vec![Default::default(); partitions.len()]);
+        thread::scope(|s| {
+            for _ in 0..self.threads.min(partitions.len()) {
+                s.spawn(|| loop {
+                    let i = next.fetch_add(1, Ordering::Relaxed);
+                    if i >= partitions.len() {
+                        break;
+                    }
+                    let result =
+                        scan_partition(data, partitions[i].clone(), cascade, self, &verifier);
+                    results.lock().unwrap()[i] = result;
+                });
+            }
+        });
+        let mut offsets = Vec::new();
+        let mut stats = ScanStats::new();
+        for (partition_offsets, partition_stats) in results.into_inner().unwrap() {
+            offsets.extend(partition_offsets);
+            stats.merge(&partition_stats);
+        }
+        (offsets, stats)
+    }
+}
+
+fn scan_partition<F: Fn(&[u8]) -> bool>(
+    data: &[u8],
+    range: Range<usize>,
+    cascade: &Cascade,
+    scanner: &ParallelScanner,
+    verifier: &F,
+) -> (Vec<usize>, ScanStats) {
+    let base = range.start;
+    let mut offsets = Vec::new();
+    let mut stats = ScanStats::new();
+    let mut counts = LevelCounts::new(cascade.len());
+    stats.scan(
+        cascade,
+        &data[range],
+        scanner.delimiter,
+        scanner.csv.as_ref(),
+        &mut counts,
+        |offset, record| {
+            let matched = verifier(record);
+            if matched {
+                offsets.push(base + offset);
+            }
+            matched
+        },
+    );
+    (offsets, stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cascade::for_each_record;
+    use raw_filter::RawFilter;
+
+    fn tweets(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..n {
+            let lang = if i % 7 == 0 { "en" } else { "fr" };
+            data.extend_from_slice(
+                format!(
+                    "{{\"id\": {}, \"retweets\": {}, \"lang\": \"{}\"}}\n",
+                    i,
+                    i % 100,
+                    lang
+                )
+                .as_bytes(),
+            );
+        }
+        data
+    }
+
+    #[test]
+    fn test_partition_at_record_boundaries() {
+        let data = tweets(1000);
+        let scanner = ParallelScanner::new(4, b'\n').with_min_partition_size(100);
+        let partitions = scanner.partition(&data, 16);
+        assert_eq!(16, partitions.len());
+        assert_eq!(0, partitions[0].start);
+        assert_eq!(data.len(), partitions.last().unwrap().end);
+        for pair in partitions.windows(2) {
+            assert_eq!(pair[0].end, pair[1].start);
+            assert_eq!(b'\n', data[pair[0].end - 1]);
+        }
+    }
+
+    #[test]
+    fn test_partition_small_input() {
+        let scanner = ParallelScanner::new(4, b'\n');
+        assert_eq!(vec![0..3], scanner.partition(b"a\nb", 16));
+        assert!(scanner.partition(b"", 16).is_empty());
+    }
+
+    #[test]
+    fn test_parallel_search_is_deterministic() {
+        let data = tweets(20000);
+        let buffer = Buffer::from(&data);
+        let cascade = Cascade::new(vec![RawFilter::substring("\"en\"")]);
+        let verifier = |record: &[u8]| !record.ends_with(b"\"retweets\": 0, \"lang\": \"en\"}");
+
+        let mut expected = Vec::new();
+        for_each_record(&data, b'\n', |offset, record| {
+            if cascade.matches(record) && verifier(record) {
+                expected.push(offset);
+            }
+        });
+        assert!(!expected.is_empty());
+
+        for threads in 1..6 {
+            let scanner = ParallelScanner::new(threads, b'\n').with_min_partition_size(1000);
+            assert_eq!(expected, scanner.search(&buffer, &cascade, verifier));
+            let (offsets, stats) = scanner.search_with_stats(&buffer, &cascade, verifier);
+            assert_eq!(expected, offsets);
+            assert_eq!(20000, stats.records);
+            assert_eq!(data.len() as u64, stats.bytes_scanned);
+            assert_eq!(expected.len() as u64, stats.matches());
+            assert_eq!(vec![2858], stats.levels.records);
+        }
+    }
+
+    #[test]
+    fn test_csv_partitions_keep_quoted_newlines() {
+        let format = CsvFormat::csv();
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            let text = if i % 2 == 0 { "\"a\nb\nen\"" } else { "fr" };
+            data.extend_from_slice(format!("{},{}\n", i, text).as_bytes());
+        }
+        let scanner = ParallelScanner::new(4, b'\n')
+            .with_min_partition_size(100)
+            .with_csv_records(format);
+        for pair in scanner.partition(&data, 16).windows(2) {
+            assert_eq!(format.next_record_start(&data, pair[0].end), pair[0].end);
+        }
+
+        let buffer = Buffer::from(&data);
+        let cascade = Cascade::new(vec![RawFilter::substring("en")]);
+        let verifier = |record: &[u8]| format.field(record, 1) == Some(&b"\"a\nb\nen\""[..]);
+        let mut expected = Vec::new();
+        format.for_each_candidate(&data, &cascade, |offset, record| {
+            if verifier(record) {
+                expected.push(offset);
+            }
+        });
+        assert_eq!(1000, expected.len());
+        let (offsets, stats) = scanner.search_with_stats(&buffer, &cascade, verifier);
+        assert_eq!(expected, offsets);
+        assert_eq!(2000, stats.records);
+    }
+}
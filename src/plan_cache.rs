@@ -0,0 +1,501 @@
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde_json::{self, Value};
+
+use cascade::Cascade;
+use formats::csv::CsvFormat;
+use optimizer::{Optimizer, Plan};
+use raw_filter::{DigitRange, RawFilter};
+use utils::error::{Result, ResultExt, SparserError};
+
+/// Relative deviation of a level's pass rate on a fresh sample from the cached one
+/// above which a cached plan is re-optimized.
+pub const DEFAULT_TOLERANCE: f64 = 0.5;
+
+/// Identifies a cached plan: a query, normalized by printing it (see `Query`'s
+/// `Display`), and the data source it was optimized for, e.g. a file path.
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct PlanKey {
+    pub query: String,
+    pub source: String,
+}
+
+impl PlanKey {
+    pub fn new<Q: ToString, S: Into<String>>(query: &Q, source: S) -> Self {
+        PlanKey {
+            query: query.to_string(),
+            source: source.into(),
+        }
+    }
+}
+
+/// A cascade as it was chosen, with its pass rates on the sample it was chosen on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct CachedPlan {
+    pub cascade: Cascade,
+    pub passed: Vec<f64>,
+}
+
+/// PlanCache remembers the cascades chosen for queries on data sources, so that
+/// running a query again costs one pass of the cached cascade over the sample
+/// instead of measuring every candidate and enumerating cascades.
+///
+/// A cached cascade is reused while its pass rates on a fresh sample stay within
+/// `tolerance` of those it was chosen on; the cached rates are not updated on a
+/// hit, so slow drift is noticed too. A cache opened from a file is written back
+/// with `save`.
+#[derive(Debug, Clone)]
+pub struct PlanCache {
+    path: Option<PathBuf>,
+    plans: HashMap<PlanKey, CachedPlan>,
+    tolerance: f64,
+    hits: u64,
+    misses: u64,
+}
+
+impl Default for PlanCache {
+    fn default() -> Self {
+        PlanCache::new()
+    }
+}
+
+impl PlanCache {
+    /// Creates an empty cache that is not backed by a file.
+    pub fn new() -> Self {
+        PlanCache {
+            path: None,
+            plans: HashMap::new(),
+            tolerance: DEFAULT_TOLERANCE,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// Opens the cache stored at `path`, or an empty one if the file does not exist.
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut cache = PlanCache::new();
+        cache.path = Some(path.to_path_buf());
+        let text = match fs::read_to_string(path) {
+            Ok(text) => text,
+            Err(ref e) if e.kind() == ::std::io::ErrorKind::NotFound => return Ok(cache),
+            Err(e) => return Err(e).context(format!("reading plan cache {}", path.display())),
+        };
+        cache
+            .load(&text)
+            .context(format!("reading plan cache {}", path.display()))?;
+        Ok(cache)
+    }
+
+    pub fn with_tolerance(mut self, tolerance: f64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    pub fn len(&self) -> usize {
+        self.plans.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.plans.is_empty()
+    }
+
+    /// Returns how many plans were served from the cache.
+    pub fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    /// Returns how many plans had to be optimized, because none was cached or the
+    /// cached one no longer fit.
+    pub fn misses(&self) -> u64 {
+        self.misses
+    }
+
+    pub fn get(&self, key: &PlanKey) -> Option<&CachedPlan> {
+        self.plans.get(key)
+    }
+
+    pub fn insert(&mut self, key: PlanKey, plan: &Plan) {
+        let cached = CachedPlan {
+            cascade: plan.cascade.clone(),
+            passed: plan.passed.clone(),
+        };
+        self.plans.insert(key, cached);
+    }
+
+    pub fn remove(&mut self, key: &PlanKey) -> Option<CachedPlan> {
+        self.plans.remove(key)
+    }
+
+    /// Returns the plan for `key` on `sample`: the cached cascade if it only uses
+    /// filters from `candidates` and its pass rates still fit, and otherwise a
+    /// newly optimized one, which replaces it in the cache.
+    pub fn plan(
+        &mut self,
+        key: &PlanKey,
+        optimizer: &Optimizer,
+        sample: &[&[u8]],
+        candidates: &[RawFilter],
+    ) -> Result<Plan> {
+        if let Some(cached) = self.plans.get(key) {
+            if cached
+                .cascade
+                .filters()
+                .iter()
+                .all(|f| candidates.contains(f))
+            {
+                let plan = optimizer.evaluate(sample, &cached.cascade)?;
+                if !self.drifted(&cached.passed, &plan.passed) {
+                    self.hits += 1;
+                    return Ok(plan);
+                }
+            }
+        }
+        self.misses += 1;
+        let plan = optimizer.optimize(sample, candidates)?;
+        self.insert(key.clone(), &plan);
+        Ok(plan)
+    }
+
+    fn drifted(&self, cached: &[f64], fresh: &[f64]) -> bool {
+        cached.len() != fresh.len()
+            || cached
+                .iter()
+                .zip(fresh)
+                .any(|(c, f)| (c - f).abs() > self.tolerance * c.max(*f))
+    }
+
+    /// Writes the cache back to the file it was opened from. Does nothing for a
+    /// cache created with `new`.
+    pub fn save(&self) -> Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        let context = format!("writing plan cache {}", path.display());
+        // write a new file and rename it, so that readers never see a partial cache
+        let mut temp = path.clone().into_os_string();
+        temp.push(".tmp");
+        fs::write(&temp, self.to_json().to_string()).context(context.clone())?;
+        fs::rename(&temp, path).context(context)
+    }
+
+    pub fn to_json(&self) -> Value {
+        let mut keys: Vec<&PlanKey> = self.plans.keys().collect();
+        keys.sort();
+        let plans = keys
+            .into_iter()
+            .map(|key| {
+                let plan = &self.plans[key];
+                let cascade: Vec<Value> =
+                    plan.cascade.filters().iter().map(filter_to_json).collect();
+                serde_json::json!({
+                    "query": key.query,
+                    "source": key.source,
+                    "cascade": cascade,
+                    "passed": plan.passed,
+                })
+            })
+            .collect::<Vec<_>>();
+        serde_json::json!({ "plans": plans })
+    }
+
+    fn load(&mut self, text: &str) -> Result<()> {
+        let value: Value = serde_json::from_str(text).map_err(|e| invalid(e.to_string()))?;
+        let plans = value["plans"]
+            .as_array()
+            .ok_or_else(|| invalid("missing plans"))?;
+        for plan in plans {
+            let string = |name: &str| {
+                plan[name]
+                    .as_str()
+                    .map(String::from)
+                    .ok_or_else(|| invalid(format!("missing {}", name)))
+            };
+            let key = PlanKey {
+                query: string("query")?,
+                source: string("source")?,
+            };
+            let filters = plan["cascade"]
+                .as_array()
+                .ok_or_else(|| invalid("missing cascade"))?
+                .iter()
+                .map(filter_from_json)
+                .collect::<Result<Vec<_>>>()?;
+            let passed = plan["passed"]
+                .as_array()
+                .ok_or_else(|| invalid("missing passed"))?
+                .iter()
+                .map(|p| p.as_f64().ok_or_else(|| invalid("invalid pass rate")))
+                .collect::<Result<Vec<_>>>()?;
+            let cached = CachedPlan {
+                cascade: Cascade::new(filters),
+                passed,
+            };
+            self.plans.insert(key, cached);
+        }
+        Ok(())
+    }
+}
+
+fn invalid<M: Into<String>>(message: M) -> SparserError {
+    SparserError::format("plan cache", message)
+}
+
+fn filter_to_json(filter: &RawFilter) -> Value {
+    let digits = |range: Option<DigitRange>| range.map(|r| vec![r.min, r.max]);
+    match *filter {
+        RawFilter::Substring(ref bytes) => serde_json::json!({ "substring": hex(bytes) }),
+        RawFilter::Column {
+            format,
+            column,
+            ref bytes,
+        } => serde_json::json!({
+            "column": column,
+            "separator": format.separator,
+            "quote": format.quote,
+            "bytes": hex(bytes),
+        }),
+        RawFilter::Number {
+            ref key,
+            positive,
+            negative,
+        } => serde_json::json!({
+            "number": hex(key),
+            "positive": digits(positive),
+            "negative": digits(negative),
+        }),
+        RawFilter::KeyValue {
+            ref key,
+            ref value,
+            window,
+        } => serde_json::json!({
+            "key": hex(key),
+            "value": hex(value),
+            "window": window,
+        }),
+        RawFilter::Caseless(ref bytes) => serde_json::json!({ "caseless": hex(bytes) }),
+        RawFilter::AnyOf(ref fingerprint) => serde_json::json!({
+            "any_of": fingerprint.literals().iter().map(|l| hex(l)).collect::<Vec<_>>(),
+        }),
+        RawFilter::Automaton(ref automaton) => serde_json::json!({
+            "automaton": automaton.literals().iter().map(|l| hex(l)).collect::<Vec<_>>(),
+        }),
+        RawFilter::OrEscaped(ref filter) => {
+            serde_json::json!({ "or_escaped": filter_to_json(filter) })
+        }
+    }
+}
+
+fn filter_from_json(value: &Value) -> Result<RawFilter> {
+    let bytes = |name: &str| {
+        value[name]
+            .as_str()
+            .and_then(unhex)
+            .ok_or_else(|| invalid(format!("invalid {}", name)))
+    };
+    let literals = |name: &str| {
+        value[name]
+            .as_array()
+            .and_then(|ls| {
+                ls.iter()
+                    .map(|l| l.as_str().and_then(unhex))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid(format!("invalid {}", name)))
+    };
+    let int = |name: &str| {
+        value[name]
+            .as_u64()
+            .ok_or_else(|| invalid(format!("invalid {}", name)))
+    };
+    let digits = |name: &str| match value[name] {
+        Value::Null => Ok(None),
+        Value::Array(ref range) => match (range.first(), range.get(1)) {
+            (Some(min), Some(max)) if range.len() == 2 => match (min.as_u64(), max.as_u64()) {
+                (Some(min), Some(max)) => Ok(Some(DigitRange {
+                    min: min as usize,
+                    max: max as usize,
+                })),
+                _ => Err(invalid(format!("invalid {}", name))),
+            },
+            _ => Err(invalid(format!("invalid {}", name))),
+        },
+        _ => Err(invalid(format!("invalid {}", name))),
+    };
+    if value.get("substring").is_some() {
+        Ok(RawFilter::Substring(bytes("substring")?))
+    } else if value.get("caseless").is_some() {
+        Ok(RawFilter::caseless(bytes("caseless")?))
+    } else if value.get("column").is_some() {
+        let format = CsvFormat {
+            separator: int("separator")? as u8,
+            quote: int("quote")? as u8,
+        };
+        Ok(RawFilter::Column {
+            format,
+            column: int("column")? as usize,
+            bytes: bytes("bytes")?,
+        })
+    } else if value.get("number").is_some() {
+        Ok(RawFilter::Number {
+            key: bytes("number")?,
+            positive: digits("positive")?,
+            negative: digits("negative")?,
+        })
+    } else if value.get("key").is_some() {
+        Ok(RawFilter::KeyValue {
+            key: bytes("key")?,
+            value: bytes("value")?,
+            window: int("window")? as usize,
+        })
+    } else if value.get("any_of").is_some() {
+        Ok(RawFilter::any_of(&literals("any_of")?))
+    } else if value.get("automaton").is_some() {
+        Ok(RawFilter::automaton(&literals("automaton")?))
+    } else if let Some(filter) = value.get("or_escaped") {
+        Ok(RawFilter::or_escaped(filter_from_json(filter)?))
+    } else {
+        Err(invalid("unknown raw filter"))
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    let mut s = String::with_capacity(bytes.len() * 2);
+    for b in bytes {
+        let _ = write!(s, "{:02x}", b);
+    }
+    s
+}
+
+fn unhex(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !s.is_ascii() {
+        return None;
+    }
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::process;
+
+    use super::*;
+    use optimizer::sample_records;
+    use query::Query;
+
+    fn tweets(en_every: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..1000 {
+            let lang = if i % en_every == 0 { "en" } else { "de" };
+            let text = if i % 5 == 0 { "rust" } else { "golang" };
+            data.extend_from_slice(
+                format!("{{\"text\": \"{} {}\", \"lang\": \"{}\"}}\n", text, i, lang).as_bytes(),
+            );
+        }
+        data
+    }
+
+    fn query() -> Query {
+        "text contains 'rust' and lang = 'en'".parse().unwrap()
+    }
+
+    #[test]
+    fn test_hit_and_invalidation() {
+        let query = query();
+        let key = PlanKey::new(&query, "tweets.json");
+        let candidates = query.raw_filters();
+        let optimizer = Optimizer::default();
+        let mut cache = PlanCache::new();
+
+        let data = tweets(2);
+        let sample = sample_records(&data, b'\n', 1000);
+        let first = cache.plan(&key, &optimizer, &sample, &candidates).unwrap();
+        assert_eq!((0, 1), (cache.hits(), cache.misses()));
+        let second = cache.plan(&key, &optimizer, &sample, &candidates).unwrap();
+        assert_eq!((1, 1), (cache.hits(), cache.misses()));
+        assert_eq!(first.cascade, second.cascade);
+        assert_eq!(first.passed, second.passed);
+
+        // equally spelled queries share the plan, other sources do not
+        let respelled: Query = "text CONTAINS 'rust'  AND lang='en'".parse().unwrap();
+        assert!(cache
+            .get(&PlanKey::new(&respelled, "tweets.json"))
+            .is_some());
+        assert!(cache.get(&PlanKey::new(&query, "other.json")).is_none());
+
+        // "en" becomes rare, so the cached cascade's pass rates are off
+        let data = tweets(50);
+        let sample = sample_records(&data, b'\n', 1000);
+        let third = cache.plan(&key, &optimizer, &sample, &candidates).unwrap();
+        assert_eq!((1, 2), (cache.hits(), cache.misses()));
+        assert_eq!(third.cascade, cache.get(&key).unwrap().cascade);
+        assert_eq!(third.passed, cache.get(&key).unwrap().passed);
+
+        // a cascade using filters that are no longer candidates is not reused
+        cache
+            .plan(&key, &optimizer, &sample, &[RawFilter::substring("lang")])
+            .unwrap();
+        assert_eq!((1, 3), (cache.hits(), cache.misses()));
+    }
+
+    #[test]
+    fn test_save_and_open() {
+        let path = env::temp_dir().join(format!("sparser-plan-cache-{}.json", process::id()));
+        let key = PlanKey::new(&query(), "tweets.json");
+        let format = CsvFormat::csv();
+        let plan = Optimizer::default()
+            .evaluate(
+                &[b"a,rust,1", b"b,go,10"],
+                &Cascade::new(vec![
+                    RawFilter::column(format, 1, "rust"),
+                    RawFilter::substring(b"\"\n\xff"),
+                    RawFilter::key_value("lang", "\"en\"", 1),
+                    RawFilter::number(
+                        "n",
+                        Some(DigitRange::of_magnitudes(10.0, f64::INFINITY)),
+                        None,
+                    ),
+                    RawFilter::any_of(&[&b"rust"[..], b"go\xff"]),
+                    RawFilter::automaton(&["rust", "go"]),
+                    RawFilter::caseless("rust"),
+                    RawFilter::or_escaped(RawFilter::key_value("lang", "\"en\"", 1)),
+                ]),
+            )
+            .unwrap();
+
+        let mut cache = PlanCache::open(&path).unwrap();
+        assert!(cache.is_empty());
+        cache.insert(key.clone(), &plan);
+        cache.save().unwrap();
+        let reopened = PlanCache::open(&path);
+        fs::write(&path, "{\"plans\": [{\"query\": \"a = 'x'\"}]}").unwrap();
+        let corrupt = PlanCache::open(&path);
+        fs::remove_file(&path).unwrap();
+
+        let reopened = reopened.unwrap();
+        assert_eq!(1, reopened.len());
+        let cached = reopened.get(&key).unwrap();
+        assert_eq!(plan.cascade, cached.cascade);
+        for (a, b) in plan.passed.iter().zip(&cached.passed) {
+            assert!((a - b).abs() < 1e-12);
+        }
+        let e = corrupt.unwrap_err();
+        assert!(e.report().contains("missing source"));
+        assert!(PlanCache::new().save().is_ok());
+    }
+
+    #[test]
+    fn test_hex() {
+        assert_eq!("00ff7b", hex(b"\x00\xff{"));
+        assert_eq!(Some(b"\x00\xff{".to_vec()), unhex("00FF7b"));
+        assert_eq!(None, unhex("0"));
+        assert_eq!(None, unhex("zz"));
+    }
+}
@@ -0,0 +1,99 @@
+use unicode_normalization::UnicodeNormalization;
+
+/// ASCII bytes that the folded forms of non-ASCII characters contain: `İ` folds to
+/// `i` and a combining dot, the Kelvin sign to `k`, `ſ`, `ß` and `ẞ` to `s`, and the
+/// Greek question mark and varia are canonically equivalent to `;` and `` ` ``.
+pub const FOLDED_FROM_NON_ASCII: &[u8] = b"iks;`";
+
+/// Returns `s` case folded: lower-cased, with `ß` written `ss` and the final and long
+/// forms of sigma and s replaced by the usual ones, in NFC. Folding is idempotent,
+/// and two strings differing only in case or in the composition of their accents
+/// (`café` and `cafe\u{301}`) fold alike.
+///
+/// Compatibility forms such as fullwidth letters or ligatures are kept apart: NFKC
+/// maps them to ASCII, and then no ASCII run of a value could be required of the
+/// raw bytes by `caseless_pieces`.
+pub fn fold(s: &str) -> String {
+    let mut folded = String::with_capacity(s.len());
+    for c in s.nfd().flat_map(char::to_lowercase) {
+        match c {
+            'ß' => folded.push_str("ss"),
+            'ς' => folded.push('σ'),
+            'ſ' => folded.push('s'),
+            c => folded.push(c),
+        }
+    }
+    folded.nfc().collect()
+}
+
+/// Returns the runs of a folded string that every string folding to a superstring of
+/// it contains up to ASCII case: its ASCII bytes, split where a byte is not ASCII or
+/// may be folded from a non-ASCII character.
+pub fn caseless_pieces(folded: &str) -> Vec<Vec<u8>> {
+    folded
+        .as_bytes()
+        .split(|b| !b.is_ascii() || FOLDED_FROM_NON_ASCII.contains(b))
+        .filter(|piece| !piece.is_empty())
+        .map(|piece| piece.to_vec())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::char;
+
+    use super::*;
+
+    #[test]
+    fn test_fold() {
+        assert_eq!(fold("STRASSE"), fold("Straße"));
+        assert_eq!(fold("ΟΔΟΣ"), fold("οδος"));
+        assert_eq!("café", fold("CAFÉ"));
+        assert_eq!(fold("café"), fold("CAFE\u{301}"));
+        assert_eq!(fold("Ångström"), fold("\u{212b}NGSTRO\u{308}M"));
+        assert!(!fold("cafe\u{301}").contains("cafe"));
+        // compatibility forms stay distinct
+        assert_ne!(fold("file"), fold("\u{fb01}le"));
+        assert_eq!(fold("Kelvin"), fold("\u{212a}elvin"));
+        // the dotless i is a letter of its own
+        assert!(!fold("ISTANBUL").contains(&fold("ıstanbul")));
+    }
+
+    #[test]
+    fn test_folded_from_non_ascii_is_complete() {
+        for c in (0x80..0x11_0000).filter_map(char::from_u32) {
+            let folded = fold(&c.to_string());
+            assert_eq!(folded, fold(&folded));
+            for b in folded.bytes().filter(u8::is_ascii) {
+                assert!(
+                    FOLDED_FROM_NON_ASCII.contains(&b),
+                    "{:?} folds to {:?}",
+                    c,
+                    folded
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_caseless_pieces() {
+        assert_eq!(
+            vec![
+                b"caf".to_vec(),
+                b" ru".to_vec(),
+                b"t ".to_vec(),
+                b"t".to_vec()
+            ],
+            caseless_pieces(&fold("Café RUST SKT"))
+        );
+        assert!(caseless_pieces("éss").is_empty());
+        // a decomposed field holds the pieces of a value folded from composed text
+        let field = "Cafe\u{301} Rust";
+        assert!(fold(field).contains(&fold("CAFÉ R")));
+        for piece in caseless_pieces(&fold("CAFÉ R")) {
+            assert!(field
+                .to_lowercase()
+                .contains(std::str::from_utf8(&piece).unwrap()));
+        }
+    }
+}
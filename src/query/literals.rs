@@ -0,0 +1,79 @@
+use regex_syntax::hir::{Hir, HirKind};
+
+use utils::error::{Result, SparserError};
+
+/// Returns literals that every match of `pattern` contains.
+///
+/// Each literal is a maximal run of bytes the regex matches verbatim and
+/// unconditionally, e.g. `timeout after ` and `ms` for `timeout after \d+ms`. Parts
+/// of the pattern under an alternation or an optional repetition contribute nothing,
+/// nor do case-insensitive literals, which the parser turns into classes.
+pub fn required_literals(pattern: &str) -> Result<Vec<Vec<u8>>> {
+    let hir = regex_syntax::parse(pattern)
+        .map_err(|e| SparserError::InvalidQuery(format!("invalid regex: {}", e)))?;
+    let mut literals = Vec::new();
+    let mut run = Vec::new();
+    collect(&hir, &mut literals, &mut run);
+    flush(&mut literals, &mut run);
+    Ok(literals)
+}
+
+fn collect(hir: &Hir, literals: &mut Vec<Vec<u8>>, run: &mut Vec<u8>) {
+    match *hir.kind() {
+        // zero-width; the bytes on either side are still adjacent
+        HirKind::Empty | HirKind::Look(_) => {}
+        HirKind::Literal(ref literal) => run.extend_from_slice(&literal.0),
+        HirKind::Concat(ref subs) => {
+            for sub in subs {
+                collect(sub, literals, run);
+            }
+        }
+        HirKind::Capture(ref capture) => collect(&capture.sub, literals, run),
+        HirKind::Repetition(ref repetition) if repetition.min > 0 => {
+            flush(literals, run);
+            collect(&repetition.sub, literals, run);
+            flush(literals, run);
+        }
+        _ => flush(literals, run),
+    }
+}
+
+fn flush(literals: &mut Vec<Vec<u8>>, run: &mut Vec<u8>) {
+    if !run.is_empty() && !literals.contains(run) {
+        literals.push(run.clone());
+    }
+    run.clear();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn literals(pattern: &str) -> Vec<String> {
+        required_literals(pattern)
+            .unwrap()
+            .into_iter()
+            .map(|l| String::from_utf8(l).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_required_literals() {
+        assert_eq!(
+            vec!["timeout after ", "ms"],
+            literals(r"timeout after \d+ms")
+        );
+        assert_eq!(vec!["error"], literals(r"^error$"));
+        assert_eq!(vec!["ab", "x"], literals(r"(ab)+c?x"));
+        assert_eq!(vec!["id="], literals(r"id=(foo|bar)"));
+        assert_eq!(vec!["a"], literals(r"a.*a"));
+        assert!(literals(r"(?i)error").is_empty());
+        assert!(literals(r"(ab)*").is_empty());
+        assert_eq!(vec!["caf\u{e9}"], literals("caf\u{e9}"));
+    }
+
+    #[test]
+    fn test_invalid_regex() {
+        assert!(required_literals(r"(unclosed").is_err());
+    }
+}
@@ -0,0 +1,1104 @@
+//! Filter queries over JSON records.
+//!
+//! A query is a tree of predicates on fields of a record. It is evaluated exactly by
+//! parsing the record, and it yields raw filters, cheap tests on the raw bytes that every
+//! matching record passes, which the optimizer assembles into a cascade.
+
+pub mod folding;
+pub mod literals;
+pub mod parser;
+
+use std::collections::BTreeSet;
+use std::fmt;
+use std::ops;
+use std::str::FromStr;
+
+use regex::Regex;
+use serde_json::{self, Value};
+
+use raw_filter::{DigitRange, RawFilter};
+use structural::StructuralIndex;
+use utils::error::{Result, SparserError};
+
+/// A condition on a single field, addressed by a dot-separated path such as
+/// `user.lang`.
+#[derive(Debug, Clone)]
+pub enum Predicate {
+    /// The field is a string equal to `value`.
+    Equals { field: String, value: String },
+    /// The field is a string containing `value`.
+    Contains { field: String, value: String },
+    /// The field is a string containing `value` once both are case folded. `value`
+    /// is folded already, as `contains_folded` does.
+    ContainsFolded { field: String, value: String },
+    /// The field is a string matching `regex`.
+    Matches { field: String, regex: Regex },
+    /// The field is a string equal to one of `values`.
+    In {
+        field: String,
+        values: BTreeSet<String>,
+    },
+    /// The field is a number comparing to `value` as `op` says.
+    Compare {
+        field: String,
+        op: Comparison,
+        value: f64,
+    },
+}
+
+/// A numeric comparison operator.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Comparison {
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    Eq,
+    Ne,
+}
+
+impl Comparison {
+    pub fn evaluate(&self, left: f64, right: f64) -> bool {
+        match *self {
+            Comparison::Lt => left < right,
+            Comparison::Le => left <= right,
+            Comparison::Gt => left > right,
+            Comparison::Ge => left >= right,
+            Comparison::Eq => left == right,
+            Comparison::Ne => left != right,
+        }
+    }
+
+    /// Returns the closed interval containing every `x` with `x op value`.
+    fn bounds(&self, value: f64) -> (f64, f64) {
+        match *self {
+            Comparison::Lt | Comparison::Le => (f64::NEG_INFINITY, value),
+            Comparison::Gt | Comparison::Ge => (value, f64::INFINITY),
+            Comparison::Eq => (value, value),
+            Comparison::Ne => (f64::NEG_INFINITY, f64::INFINITY),
+        }
+    }
+}
+
+impl Predicate {
+    /// Creates a `Matches` predicate, failing with `InvalidQuery` on a bad pattern.
+    pub fn matches(field: &str, pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern)
+            .map_err(|e| SparserError::InvalidQuery(format!("invalid regex: {}", e)))?;
+        Ok(Predicate::Matches {
+            field: field.to_string(),
+            regex,
+        })
+    }
+
+    /// Creates a `ContainsFolded` predicate, folding `value`.
+    pub fn contains_folded(field: &str, value: &str) -> Self {
+        Predicate::ContainsFolded {
+            field: field.to_string(),
+            value: folding::fold(value),
+        }
+    }
+
+    pub fn field(&self) -> &str {
+        match *self {
+            Predicate::Equals { ref field, .. }
+            | Predicate::Contains { ref field, .. }
+            | Predicate::ContainsFolded { ref field, .. }
+            | Predicate::Matches { ref field, .. }
+            | Predicate::In { ref field, .. }
+            | Predicate::Compare { ref field, .. } => field,
+        }
+    }
+
+    /// Evaluates the predicate against a parsed record.
+    pub fn evaluate(&self, record: &Value) -> bool {
+        lookup(record, self.field()).is_some_and(|field| self.evaluate_field(field))
+    }
+
+    /// Evaluates the predicate against the parsed value of its field.
+    pub fn evaluate_field(&self, field: &Value) -> bool {
+        match *self {
+            Predicate::Equals { ref value, .. } => field.as_str() == Some(value.as_str()),
+            Predicate::Contains { ref value, .. } => {
+                field.as_str().is_some_and(|s| s.contains(value.as_str()))
+            }
+            Predicate::ContainsFolded { ref value, .. } => field
+                .as_str()
+                .is_some_and(|s| folding::fold(s).contains(value.as_str())),
+            Predicate::Matches { ref regex, .. } => {
+                field.as_str().is_some_and(|s| regex.is_match(s))
+            }
+            Predicate::In { ref values, .. } => field.as_str().is_some_and(|s| values.contains(s)),
+            Predicate::Compare { op, value, .. } => {
+                field.as_f64().is_some_and(|n| op.evaluate(n, value))
+            }
+        }
+    }
+
+    /// Evaluates the predicate against a field given as text, as in CSV and log
+    /// records. Comparisons parse the text as a number.
+    pub fn evaluate_text(&self, field: &str) -> bool {
+        match *self {
+            Predicate::Equals { ref value, .. } => field == value,
+            Predicate::Contains { ref value, .. } => field.contains(value.as_str()),
+            Predicate::ContainsFolded { ref value, .. } => {
+                folding::fold(field).contains(value.as_str())
+            }
+            Predicate::Matches { ref regex, .. } => regex.is_match(field),
+            Predicate::In { ref values, .. } => values.contains(field),
+            Predicate::Compare { op, value, .. } => field
+                .trim()
+                .parse::<f64>()
+                .is_ok_and(|n| op.evaluate(n, value)),
+        }
+    }
+
+    /// Returns the literals every field value satisfying the predicate contains.
+    pub fn required_literals(&self) -> Vec<Vec<u8>> {
+        match *self {
+            Predicate::Equals { ref value, .. } | Predicate::Contains { ref value, .. } => {
+                if value.is_empty() {
+                    Vec::new()
+                } else {
+                    vec![value.as_bytes().to_vec()]
+                }
+            }
+            // the pattern already compiled, so it also parses here
+            Predicate::Matches { ref regex, .. } => {
+                literals::required_literals(regex.as_str()).unwrap_or_default()
+            }
+            // the field's bytes may differ from the value's in case
+            Predicate::ContainsFolded { .. } => Vec::new(),
+            Predicate::In { .. } | Predicate::Compare { .. } => Vec::new(),
+        }
+    }
+
+    /// Returns raw filters that every record satisfying the predicate passes: the
+    /// quoted keys on the field's path, and the parts of the value that appear
+    /// verbatim in the encoded record. A whole quoted value also yields a filter for
+    /// the value right after the last key. A comparison instead yields a filter on
+    /// the sign and number of digits of the numbers stored under the last key. A
+    /// set of values yields filters passing records with any of them, and a folded
+    /// value filters ignoring ASCII case on the parts no other character folds to.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        let mut filters = key_filters(self.field());
+        match *self {
+            Predicate::Equals { ref value, .. } if is_verbatim(value.as_bytes()) => {
+                let quoted = format!("\"{}\"", value);
+                // only the colon separates a key from its value
+                let key = self.field().rsplit('.').next().unwrap_or_default();
+                if is_verbatim(key.as_bytes()) {
+                    filters.push(RawFilter::key_value(key, &quoted, 1));
+                }
+                filters.push(RawFilter::substring(quoted))
+            }
+            Predicate::Equals { ref value, .. } | Predicate::Contains { ref value, .. } => {
+                filters.extend(verbatim_pieces(value.as_bytes()).map(RawFilter::substring))
+            }
+            Predicate::ContainsFolded { ref value, .. } => {
+                for piece in folding::caseless_pieces(value) {
+                    filters.extend(verbatim_pieces(&piece).map(RawFilter::caseless));
+                }
+            }
+            Predicate::Matches { .. } => {
+                for literal in self.required_literals() {
+                    filters.extend(verbatim_pieces(&literal).map(RawFilter::substring));
+                }
+            }
+            Predicate::In {
+                ref field,
+                ref values,
+            } if values.len() == 1 => {
+                return Predicate::Equals {
+                    field: field.clone(),
+                    value: values.iter().next().unwrap().clone(),
+                }
+                .raw_filters()
+            }
+            Predicate::In { ref values, .. } => filters.extend(any_of_filters(values)),
+            Predicate::Compare { op, value, .. } => return number_filters(self.field(), op, value),
+        }
+        filters
+    }
+}
+
+/// Returns the filters passing records that contain one of `values`: the quoted
+/// values if all are written verbatim, or else the longest verbatim piece of each.
+/// There are none if some value has no verbatim piece, as its records contain
+/// nothing known.
+fn any_of_filters(values: &BTreeSet<String>) -> Vec<RawFilter> {
+    let literals: Option<Vec<Vec<u8>>> = if values.iter().all(|v| is_verbatim(v.as_bytes())) {
+        Some(
+            values
+                .iter()
+                .map(|v| format!("\"{}\"", v).into_bytes())
+                .collect(),
+        )
+    } else {
+        values
+            .iter()
+            .map(|v| {
+                verbatim_pieces(v.as_bytes())
+                    .max_by_key(|piece| piece.len())
+                    .map(|piece| piece.to_vec())
+            })
+            .collect()
+    };
+    literals
+        .map(|literals| RawFilter::any_of_candidates(&literals))
+        .unwrap_or_default()
+}
+
+/// Returns filters for a comparison: the quoted keys leading to the number, and a
+/// filter on the sign and integer digits of the numbers under the last key.
+fn number_filters(field: &str, op: Comparison, value: f64) -> Vec<RawFilter> {
+    let (path, key) = match field.rfind('.') {
+        Some(i) => (&field[..i], &field[i + 1..]),
+        None => ("", field),
+    };
+    let mut filters = key_filters(path);
+    if !is_verbatim(key.as_bytes()) {
+        return filters;
+    }
+    if !value.is_finite() {
+        filters.push(RawFilter::substring(format!("\"{}\"", key)));
+        return filters;
+    }
+    let (lo, hi) = op.bounds(value);
+    // -0 is written with a sign, so zero belongs to both sides
+    let positive = if hi >= 0.0 {
+        Some(DigitRange::of_magnitudes(lo.max(0.0), hi))
+    } else {
+        None
+    };
+    let negative = if lo <= 0.0 {
+        Some(DigitRange::of_magnitudes((-hi).max(0.0), -lo))
+    } else {
+        None
+    };
+    filters.push(RawFilter::number(key, positive, negative));
+    filters
+}
+
+/// Returns filters for the quoted keys on a dot-separated path.
+fn key_filters(path: &str) -> Vec<RawFilter> {
+    path.split('.')
+        .filter(|key| is_verbatim(key.as_bytes()))
+        .map(|key| RawFilter::substring(format!("\"{}\"", key)))
+        .collect()
+}
+
+/// A boolean combination of predicates.
+#[derive(Debug, Clone)]
+pub enum Query {
+    Predicate(Predicate),
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+impl Query {
+    /// Evaluates the query against a parsed record.
+    pub fn evaluate(&self, record: &Value) -> bool {
+        self.evaluate_with(&|p: &Predicate| p.evaluate(record))
+    }
+
+    /// Evaluates the query with `predicate` deciding its predicates.
+    pub fn evaluate_with<F: Fn(&Predicate) -> bool>(&self, predicate: &F) -> bool {
+        match *self {
+            Query::Predicate(ref p) => predicate(p),
+            Query::And(ref qs) => qs.iter().all(|q| q.evaluate_with(predicate)),
+            Query::Or(ref qs) => qs.iter().any(|q| q.evaluate_with(predicate)),
+            Query::Not(ref q) => !q.evaluate_with(predicate),
+        }
+    }
+
+    /// Returns every predicate of the query, in order.
+    pub fn predicates(&self) -> Vec<&Predicate> {
+        match *self {
+            Query::Predicate(ref p) => vec![p],
+            Query::And(ref qs) | Query::Or(ref qs) => {
+                qs.iter().flat_map(|q| q.predicates()).collect()
+            }
+            Query::Not(ref q) => q.predicates(),
+        }
+    }
+
+    /// Returns raw filters that every record satisfying the query passes, as
+    /// candidates for the optimizer. A conjunction needs the filters of all its
+    /// terms; a disjunction only the filters common to all of its branches.
+    ///
+    /// A raw filter can only show that a record lacks some bytes, which never rules
+    /// out a negated predicate, so negated predicates yield no filters. Negations
+    /// are pushed down first, so `NOT (a OR NOT b)` still yields the filters of `b`.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        self.raw_filters_with(&Predicate::raw_filters)
+    }
+
+    /// Like `raw_filters`, with `predicate` returning the filters of a predicate, for
+    /// records in other formats than JSON.
+    pub fn raw_filters_with<F: Fn(&Predicate) -> Vec<RawFilter>>(
+        &self,
+        predicate: &F,
+    ) -> Vec<RawFilter> {
+        let mut filters = self.filters(true, predicate);
+        let mut unique = Vec::with_capacity(filters.len());
+        for filter in filters.drain(..) {
+            if !unique.contains(&filter) {
+                unique.push(filter);
+            }
+        }
+        unique
+    }
+
+    /// Returns the filters of this query if `positive`, or of its negation.
+    fn filters<F: Fn(&Predicate) -> Vec<RawFilter>>(
+        &self,
+        positive: bool,
+        predicate: &F,
+    ) -> Vec<RawFilter> {
+        let all = |qs: &[Query]| {
+            qs.iter()
+                .flat_map(|q| q.filters(positive, predicate))
+                .collect()
+        };
+        let common = |qs: &[Query]| {
+            let mut branches = qs.iter().map(|q| q.filters(positive, predicate));
+            let first = branches.next().unwrap_or_default();
+            branches.fold(first, |common: Vec<RawFilter>, branch| {
+                common.into_iter().filter(|f| branch.contains(f)).collect()
+            })
+        };
+        match (self, positive) {
+            (Query::Predicate(p), true) => predicate(p),
+            (Query::Predicate(_), false) => Vec::new(),
+            // NOT (a OR b) is NOT a AND NOT b
+            (Query::And(qs), true) | (Query::Or(qs), false) => all(qs),
+            (Query::Or(qs), true) | (Query::And(qs), false) => common(qs),
+            (Query::Not(q), _) => q.filters(!positive, predicate),
+        }
+    }
+}
+
+impl ops::Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        Query::Not(Box::new(self))
+    }
+}
+
+impl FromStr for Query {
+    type Err = SparserError;
+
+    /// Parses a filter expression, see `parser`.
+    fn from_str(s: &str) -> Result<Self> {
+        parser::parse(s)
+    }
+}
+
+impl From<Predicate> for Query {
+    fn from(predicate: Predicate) -> Self {
+        Query::Predicate(predicate)
+    }
+}
+
+impl fmt::Display for Comparison {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            Comparison::Lt => "<",
+            Comparison::Le => "<=",
+            Comparison::Gt => ">",
+            Comparison::Ge => ">=",
+            Comparison::Eq => "=",
+            Comparison::Ne => "!=",
+        })
+    }
+}
+
+impl fmt::Display for Predicate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Predicate::Equals {
+                ref field,
+                ref value,
+            } => write!(f, "{} = '{}'", field, value.replace('\'', "''")),
+            Predicate::Contains {
+                ref field,
+                ref value,
+            } => write!(f, "{} CONTAINS '{}'", field, value.replace('\'', "''")),
+            Predicate::ContainsFolded {
+                ref field,
+                ref value,
+            } => write!(f, "{} ICONTAINS '{}'", field, value.replace('\'', "''")),
+            Predicate::Matches {
+                ref field,
+                ref regex,
+            } => write!(f, "{} ~ /{}/", field, regex.as_str().replace('/', "\\/")),
+            Predicate::In {
+                ref field,
+                ref values,
+            } => {
+                write!(f, "{} IN (", field)?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "'{}'", value.replace('\'', "''"))?;
+                }
+                write!(f, ")")
+            }
+            // the parser reads an overflowing literal as infinity
+            Predicate::Compare {
+                ref field,
+                op,
+                value,
+            } if value.is_infinite() => {
+                let sign = if value < 0.0 { "-" } else { "" };
+                write!(f, "{} {} {}1e999", field, op, sign)
+            }
+            Predicate::Compare {
+                ref field,
+                op,
+                value,
+            } => write!(f, "{} {} {}", field, op, value),
+        }
+    }
+}
+
+/// Writes the query in the syntax `parser` reads, with upper-case keywords, single
+/// spaces and only the parentheses needed to keep its structure, so that queries
+/// differing only in spelling print alike.
+impl fmt::Display for Query {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let terms = |f: &mut fmt::Formatter, qs: &[Query], keyword: &str| {
+            for (i, q) in qs.iter().enumerate() {
+                if i > 0 {
+                    write!(f, " {} ", keyword)?;
+                }
+                match (q, keyword) {
+                    (Query::Or(_), _) | (Query::And(_), "AND") => write!(f, "({})", q)?,
+                    _ => write!(f, "{}", q)?,
+                }
+            }
+            Ok(())
+        };
+        match *self {
+            Query::Predicate(ref p) => write!(f, "{}", p),
+            Query::And(ref qs) => terms(f, qs, "AND"),
+            Query::Or(ref qs) => terms(f, qs, "OR"),
+            Query::Not(ref q) => match **q {
+                Query::Predicate(_) | Query::Not(_) => write!(f, "NOT {}", q),
+                _ => write!(f, "NOT ({})", q),
+            },
+        }
+    }
+}
+
+/// JsonVerifier evaluates a query exactly against newline-delimited JSON records.
+/// Records that fail to parse fail verification.
+#[derive(Debug, Clone)]
+pub struct JsonVerifier {
+    query: Query,
+    escape_safe: bool,
+    /// The fields the query reads, if only they are parsed.
+    lazy_fields: Option<Vec<String>>,
+}
+
+impl JsonVerifier {
+    pub fn new(query: Query) -> Self {
+        JsonVerifier {
+            query,
+            escape_safe: false,
+            lazy_fields: None,
+        }
+    }
+
+    /// Makes every raw filter pass records containing a backslash. The filters
+    /// otherwise assume that an encoder escapes only what `is_verbatim_byte` rules
+    /// out, and miss records that spell out other characters as `\uXXXX`.
+    pub fn with_escape_safety(mut self) -> Self {
+        self.escape_safe = true;
+        self
+    }
+
+    /// Makes `verify` parse only the values of the fields the query reads, found
+    /// with a `StructuralIndex`, and the whole record only where the structure on
+    /// the way to a value is not that of JSON. The rest of the record is not
+    /// validated, so a record that is malformed elsewhere may pass.
+    pub fn with_lazy_parsing(mut self) -> Self {
+        let mut fields: Vec<String> = Vec::new();
+        for predicate in self.query.predicates() {
+            if !fields.iter().any(|f| f == predicate.field()) {
+                fields.push(predicate.field().to_string());
+            }
+        }
+        self.lazy_fields = Some(fields);
+        self
+    }
+
+    pub fn query(&self) -> &Query {
+        &self.query
+    }
+
+    /// Returns whether `record` parses and satisfies the query.
+    pub fn verify(&self, record: &[u8]) -> bool {
+        if let Some(ref fields) = self.lazy_fields {
+            if let Some(verified) = self.verify_fields(record, fields) {
+                return verified;
+            }
+        }
+        match serde_json::from_slice::<Value>(record) {
+            Ok(value) => self.query.evaluate(&value),
+            Err(_) => false,
+        }
+    }
+
+    /// Evaluates the query on the parsed values of `fields` alone, or returns `None`
+    /// if one of them cannot be located or parsed.
+    fn verify_fields(&self, record: &[u8], fields: &[String]) -> Option<bool> {
+        let index = StructuralIndex::new(record).ok()?;
+        let mut values = Vec::with_capacity(fields.len());
+        for field in fields {
+            values.push(match index.field(record, field).ok()? {
+                Some(range) => Some(serde_json::from_slice::<Value>(&record[range]).ok()?),
+                None => None,
+            });
+        }
+        Some(self.query.evaluate_with(&|p: &Predicate| {
+            fields
+                .iter()
+                .zip(&values)
+                .find(|&(field, _)| field == p.field())
+                .and_then(|(_, value)| value.as_ref())
+                .is_some_and(|value| p.evaluate_field(value))
+        }))
+    }
+
+    /// Returns the raw filters implied by the query, as optimizer candidates.
+    pub fn raw_filters(&self) -> Vec<RawFilter> {
+        let filters = self.query.raw_filters();
+        if self.escape_safe {
+            filters.into_iter().map(RawFilter::or_escaped).collect()
+        } else {
+            filters
+        }
+    }
+}
+
+/// Returns the value at the dot-separated `path` of nested objects in `record`.
+pub fn lookup<'a>(record: &'a Value, path: &str) -> Option<&'a Value> {
+    path.split('.')
+        .try_fold(record, |value, key| value.get(key))
+}
+
+/// Returns whether a JSON encoder writes `byte` unescaped. Quotes, backslashes and
+/// control characters must be escaped, many encoders escape all non-ASCII, and some
+/// escape slashes as `\/`.
+fn is_verbatim_byte(byte: u8) -> bool {
+    (0x20..0x7f).contains(&byte) && !b"\"\\/".contains(&byte)
+}
+
+fn is_verbatim(bytes: &[u8]) -> bool {
+    !bytes.is_empty() && bytes.iter().all(|&b| is_verbatim_byte(b))
+}
+
+/// Splits `bytes` into the runs that appear unchanged inside an encoded string.
+fn verbatim_pieces(bytes: &[u8]) -> impl Iterator<Item = &[u8]> {
+    bytes
+        .split(|&b| !is_verbatim_byte(b))
+        .filter(|piece| !piece.is_empty())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use cascade::{for_each_record, Cascade};
+    use optimizer::{sample_records, Optimizer};
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    fn logs() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..500 {
+            let message = match i % 5 {
+                0 => format!("timeout after {}ms", i * 3),
+                1 => "timeout after ms".to_string(),
+                2 => format!("connection reset after {}ms", i),
+                3 => "caf\u{e9} \"timeout after 5ms\"".to_string(),
+                _ => format!("ok in {}ms", i),
+            };
+            let record = serde_json::json!({"id": i, "host": {"name": format!("web-{}", i % 3)}, "message": message});
+            data.extend_from_slice(record.to_string().as_bytes());
+            data.push(b'\n');
+        }
+        data
+    }
+
+    /// Returns the ids matched by scanning with the optimized cascade, checking
+    /// that they are exactly the records matched by verifying everything.
+    fn search(data: &[u8], query: Query) -> Vec<u64> {
+        search_with(data, JsonVerifier::new(query))
+    }
+
+    fn search_with(data: &[u8], verifier: JsonVerifier) -> Vec<u64> {
+        let sample = sample_records(data, b'\n', 100);
+        let plan = Optimizer::default()
+            .optimize(&sample, &verifier.raw_filters())
+            .unwrap();
+        let mut expected = Vec::new();
+        for_each_record(data, b'\n', |_, record| {
+            if verifier.verify(record) {
+                expected.push(record.to_vec());
+            }
+        });
+        let mut found = Vec::new();
+        plan.cascade.for_each_candidate(data, b'\n', |_, record| {
+            if verifier.verify(record) {
+                found.push(record.to_vec());
+            }
+        });
+        assert_eq!(expected, found);
+        found
+            .iter()
+            .map(|r| {
+                serde_json::from_slice::<Value>(r).unwrap()["id"]
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_regex_literals_become_filters() {
+        let predicate = Predicate::matches("message", r"timeout after \d+ms").unwrap();
+        assert_eq!(
+            vec![
+                RawFilter::substring("\"message\""),
+                RawFilter::substring("timeout after "),
+                RawFilter::substring("ms"),
+            ],
+            predicate.raw_filters()
+        );
+        // also matched inside the quoted text of every fifth record
+        let ids = search(&logs(), predicate.into());
+        assert_eq!(200, ids.len());
+        assert!(ids.iter().all(|id| id % 5 == 0 || id % 5 == 3));
+        assert!(Predicate::matches("message", "(").is_err());
+    }
+
+    #[test]
+    fn test_escaped_values_are_split() {
+        let predicate = Predicate::Contains {
+            field: "message".to_string(),
+            value: "caf\u{e9} \"timeout".to_string(),
+        };
+        assert_eq!(
+            vec![
+                RawFilter::substring("\"message\""),
+                RawFilter::substring("caf"),
+                RawFilter::substring(" "),
+                RawFilter::substring("timeout"),
+            ],
+            predicate.raw_filters()
+        );
+        assert_eq!(100, search(&logs(), predicate.into()).len());
+    }
+
+    #[test]
+    fn test_nested_fields_and_boolean_queries() {
+        let web0 = Query::from(Predicate::Equals {
+            field: "host.name".to_string(),
+            value: "web-0".to_string(),
+        });
+        assert!(web0
+            .raw_filters()
+            .contains(&RawFilter::substring("\"web-0\"")));
+        assert!(web0
+            .raw_filters()
+            .contains(&RawFilter::key_value("name", "\"web-0\"", 1)));
+        let timeout =
+            Query::from(Predicate::matches("message", "^timeout after [0-9]+ms$").unwrap());
+        let reset = Query::from(Predicate::matches("message", "^connection reset").unwrap());
+
+        let ids = search(&logs(), Query::And(vec![web0.clone(), timeout.clone()]));
+        assert_eq!(34, ids.len());
+        assert!(ids.iter().all(|id| id % 15 == 0));
+
+        let either = Query::Or(vec![timeout, reset]);
+        assert_eq!(
+            vec![RawFilter::substring("\"message\"")],
+            either.raw_filters()
+        );
+        assert_eq!(67, search(&logs(), Query::And(vec![either, web0])).len());
+    }
+
+    #[test]
+    fn test_lazy_parsing_agrees_with_parsing() {
+        let mut records: Vec<Vec<u8>> = Vec::new();
+        for data in [logs(), counts()].iter() {
+            for_each_record(data, b'\n', |_, record| records.push(record.to_vec()));
+        }
+        for record in [
+            &br#"{"host": {"name": "web-0"}, "host": {"name": "web-1"}}"#[..],
+            br#"{"host": ["web-0"], "message": "timeout after 5ms"}"#,
+            br#"  {"id": 3, "host": {"name": "web-0", "x": {"name": "web-2"}}}  "#,
+            br#"{"message": "timeout after 5ms", "stats": {"retweet_count": 1e3}}"#,
+            br#"["web-0"]"#,
+            br#"{"host": {"name": "web-0"}"#,
+            br#"{"host": {"name": "web-0" "id": 1}}"#,
+            b"",
+        ]
+        .iter()
+        {
+            records.push(record.to_vec());
+        }
+
+        let queries = [
+            "host.name = 'web-0' and message ~ /^timeout/",
+            "not host.name in ('web-1', 'web-2') or id < 10",
+            "stats.retweet_count >= 100 and not text contains 'retweet_count: 1'",
+            "message icontains 'CAFÉ' or host = 'web-0'",
+        ];
+        for query in queries.iter() {
+            let query: Query = query.parse().unwrap();
+            let full = JsonVerifier::new(query.clone());
+            let lazy = JsonVerifier::new(query).with_lazy_parsing();
+            for record in &records {
+                assert_eq!(
+                    full.verify(record),
+                    lazy.verify(record),
+                    "{}",
+                    String::from_utf8_lossy(record)
+                );
+            }
+        }
+
+        // only the fields read are validated
+        let lazy = JsonVerifier::new("id = 1".parse().unwrap()).with_lazy_parsing();
+        assert!(lazy.verify(br#"{"id": 1, "x": tru}"#));
+        assert!(!JsonVerifier::new("id = 1".parse().unwrap()).verify(br#"{"id": 1, "x": tru}"#));
+    }
+
+    #[test]
+    fn test_key_value_filter_is_chosen() {
+        // every record has "lang" and "en", but only a tenth has them together
+        let mut data = Vec::new();
+        for i in 0..1000 {
+            let (lang, reply) = if i % 10 == 0 {
+                ("en", "de")
+            } else {
+                ("de", "en")
+            };
+            data.extend_from_slice(
+                format!(
+                    "{{\"id\": {}, \"lang\": \"{}\", \"reply_lang\": \"{}\"}}\n",
+                    i, lang, reply
+                )
+                .as_bytes(),
+            );
+        }
+        let query = Query::from(Predicate::Equals {
+            field: "lang".to_string(),
+            value: "en".to_string(),
+        });
+        let sample = sample_records(&data, b'\n', 1000);
+        let plan = Optimizer::default()
+            .optimize(&sample, &query.raw_filters())
+            .unwrap();
+        assert_eq!(
+            &[RawFilter::key_value("lang", "\"en\"", 1)],
+            plan.cascade.filters()
+        );
+        assert!(plan.passed[0] < 0.11);
+        assert_eq!(100, search(&data, query).len());
+    }
+
+    #[test]
+    fn test_in_predicate_filters() {
+        let mut data = Vec::new();
+        for i in 0..2000 {
+            let record = serde_json::json!({"id": i, "hashtag": format!("tag{}", i * 13 % 5000)});
+            data.extend_from_slice(record.to_string().as_bytes());
+            data.push(b'\n');
+        }
+        let values: BTreeSet<String> = (0..5000).step_by(20).map(|k| format!("tag{}", k)).collect();
+        let query = Query::from(Predicate::In {
+            field: "hashtag".to_string(),
+            values: values.clone(),
+        });
+        let filters = query.raw_filters();
+        assert_eq!(RawFilter::substring("\"hashtag\""), filters[0]);
+        let quoted: Vec<String> = values.iter().map(|v| format!("\"{}\"", v)).collect();
+        assert_eq!(RawFilter::any_of_candidates(&quoted), &filters[1..]);
+
+        let sample = sample_records(&data, b'\n', 1000);
+        let plan = Optimizer::default().optimize(&sample, &filters).unwrap();
+        assert_eq!(&filters[1..2], plan.cascade.filters());
+        assert_eq!(100, search(&data, query).len());
+
+        // with thousands of values, mostly absent, the automaton is cheaper
+        let many: BTreeSet<String> = (0..3000).map(|k| format!("tag{}", k * 20)).collect();
+        let query = Query::from(Predicate::In {
+            field: "hashtag".to_string(),
+            values: many,
+        });
+        let filters = query.raw_filters();
+        let plan = Optimizer::default().optimize(&sample, &filters).unwrap();
+        assert_eq!(&filters[2..], plan.cascade.filters());
+        assert_eq!(100, search(&data, query).len());
+
+        // a single value is an equality, and values with escapes fall back to pieces
+        let single = Predicate::In {
+            field: "hashtag".to_string(),
+            values: vec!["tag0".to_string()].into_iter().collect(),
+        };
+        assert!(single
+            .raw_filters()
+            .contains(&RawFilter::key_value("hashtag", "\"tag0\"", 1)));
+        let escaped = Predicate::In {
+            field: "hashtag".to_string(),
+            values: vec!["a\"bc".to_string(), "x".to_string()]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(
+            RawFilter::any_of_candidates(&["bc", "x"]),
+            &escaped.raw_filters()[1..]
+        );
+        let unknown = Predicate::In {
+            field: "hashtag".to_string(),
+            values: vec!["\u{e9}".to_string(), "x".to_string()]
+                .into_iter()
+                .collect(),
+        };
+        assert_eq!(1, unknown.raw_filters().len());
+    }
+
+    #[test]
+    fn test_escape_safety() {
+        let mut data = Vec::new();
+        for i in 0..400 {
+            // escaped as encoders may, but need not, write them
+            let (lang, url) = match i % 4 {
+                0 => ("\"en\"", "\"https://rust-lang.org\""),
+                1 => ("\"\\u0065n\"", "\"https:\\/\\/rust-lang.org\""),
+                2 => ("\"de\"", "\"https://go.dev\""),
+                _ => ("\"e\\u006e\"", "\"https:\\/\\/rust-lang.org\\/learn\""),
+            };
+            data.extend_from_slice(
+                format!("{{\"id\": {}, \"lang\": {}, \"url\": {}}}\n", i, lang, url).as_bytes(),
+            );
+        }
+
+        let query: Query = "lang = 'en' and url contains '//rust-lang.org/'"
+            .parse()
+            .unwrap();
+        let filters = query.raw_filters();
+        assert!(filters.contains(&RawFilter::substring("\"en\"")));
+        assert!(filters.contains(&RawFilter::substring("rust-lang.org")));
+        let verifier = JsonVerifier::new(query.clone()).with_escape_safety();
+        assert_eq!(
+            filters
+                .into_iter()
+                .map(RawFilter::or_escaped)
+                .collect::<Vec<_>>(),
+            verifier.raw_filters()
+        );
+        assert_eq!(100, search_with(&data, verifier).len());
+
+        // without escape safety, escaped records that match are missed
+        let plain = Cascade::new(query.raw_filters());
+        let verifier = JsonVerifier::new(query);
+        assert_eq!(
+            0,
+            plain.search(&data, b'\n', |record| verifier.verify(record))
+        );
+    }
+
+    #[test]
+    fn test_contains_folded() {
+        let mut data = Vec::new();
+        for i in 0..600 {
+            let city = match i % 6 {
+                0 => "CAFÉ in der STRASSE",
+                1 => "café in der Straße",
+                2 => "Cafe in der strasse",
+                3 => "ΟΔΟΣ ΚΑΦΕ",
+                4 => "Caf\u{e9} on the \u{212a}ing's road",
+                _ => "tea on the road",
+            };
+            let record = serde_json::json!({"id": i, "place": {"name": city}});
+            data.extend_from_slice(record.to_string().as_bytes());
+            data.push(b'\n');
+        }
+
+        let predicate = Predicate::contains_folded("place.name", "Café IN der Straße");
+        assert_eq!(
+            vec![
+                RawFilter::substring("\"place\""),
+                RawFilter::substring("\"name\""),
+                RawFilter::caseless("caf"),
+                RawFilter::caseless(" "),
+                RawFilter::caseless("n der "),
+                RawFilter::caseless("tra"),
+                RawFilter::caseless("e"),
+            ],
+            predicate.raw_filters()
+        );
+        assert_eq!(200, search(&data, Query::from(predicate)).len());
+        let king = Query::from(Predicate::contains_folded("place.name", "KING"));
+        assert_eq!(100, search(&data, king).len());
+        let greek = Query::from(Predicate::contains_folded("place.name", "οδος"));
+        assert_eq!(100, search(&data, greek).len());
+    }
+
+    fn counts() -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..400 {
+            let count = match i % 8 {
+                0 => format!("{}", i * 10),
+                1 => format!("{}.5", i),
+                2 => format!("-{}", i * 7),
+                3 => format!("{}e2", i % 30),
+                4 => "-0".to_string(),
+                5 => format!("\"{}\"", i * 100),
+                6 => format!("{}", i / 20),
+                _ => "null".to_string(),
+            };
+            data.extend_from_slice(
+                format!(
+                    "{{\"id\": {}, \"stats\": {{\"retweet_count\": {}}}, \"text\": \"retweet_count: {}\"}}\n",
+                    i, count, i
+                )
+                .as_bytes(),
+            );
+        }
+        data
+    }
+
+    #[test]
+    fn test_numeric_comparisons() {
+        let data = counts();
+        let comparisons = [
+            (Comparison::Gt, 1000.0),
+            (Comparison::Ge, 0.0),
+            (Comparison::Lt, 0.0),
+            (Comparison::Le, -35.0),
+            (Comparison::Lt, 99.5),
+            (Comparison::Eq, 1200.0),
+            (Comparison::Ne, 0.0),
+            (Comparison::Gt, -1e300),
+        ];
+        for &(op, value) in comparisons.iter() {
+            let predicate = Predicate::Compare {
+                field: "stats.retweet_count".to_string(),
+                op,
+                value,
+            };
+            let expected = (0..400u64)
+                .filter(|&i| {
+                    let record = serde_json::from_slice::<Value>(
+                        data.split(|&b| b == b'\n').nth(i as usize).unwrap(),
+                    )
+                    .unwrap();
+                    predicate.evaluate(&record)
+                })
+                .count();
+            assert_eq!(
+                expected,
+                search(&data, predicate.into()).len(),
+                "{:?} {}",
+                op,
+                value
+            );
+        }
+    }
+
+    #[test]
+    fn test_number_filter_prunes() {
+        let predicate = Predicate::Compare {
+            field: "stats.retweet_count".to_string(),
+            op: Comparison::Gt,
+            value: 1000.0,
+        };
+        let filters = predicate.raw_filters();
+        assert_eq!(RawFilter::substring("\"stats\""), filters[0]);
+        let number = &filters[1];
+        let data = counts();
+        let passed = data
+            .split(|&b| b == b'\n')
+            .filter(|record| number.matches(record))
+            .count();
+        // 37 four-digit counts, and all 50 numbers in exponent notation
+        assert_eq!(37 + 50, passed);
+        assert_eq!(37 + 33, search(&data, predicate.into()).len());
+    }
+
+    fn contains(field: &str, value: &str) -> Query {
+        Query::from(Predicate::Contains {
+            field: field.to_string(),
+            value: value.to_string(),
+        })
+    }
+
+    #[test]
+    fn test_negated_predicates_yield_no_filters() {
+        let timeout = contains("message", "timeout");
+        let web0 = contains("host.name", "web-0");
+        assert!((!timeout.clone()).raw_filters().is_empty());
+        assert_eq!(timeout.raw_filters(), (!!timeout.clone()).raw_filters());
+        // NOT (NOT timeout OR NOT web-0) is timeout AND web-0
+        let both = !Query::Or(vec![!timeout.clone(), !web0.clone()]);
+        assert_eq!(
+            Query::And(vec![timeout.clone(), web0.clone()]).raw_filters(),
+            both.raw_filters()
+        );
+        // NOT (NOT timeout AND NOT web-0) is timeout OR web-0
+        let either = !Query::And(vec![!timeout.clone(), !web0.clone()]);
+        assert!(either.raw_filters().is_empty());
+
+        // the positive term still prunes
+        let query = Query::And(vec![timeout.clone(), !web0]);
+        assert_eq!(timeout.raw_filters(), query.raw_filters());
+        let ids = search(&logs(), query);
+        assert!(!ids.is_empty());
+        assert!(ids.iter().all(|id| id % 3 != 0));
+    }
+
+    fn random_query<R: Rng>(rng: &mut R, depth: usize) -> Query {
+        let leaves = [
+            contains("message", "timeout"),
+            contains("message", "after"),
+            contains("message", "caf\u{e9}"),
+            contains("host.name", "web-1"),
+            Query::from(Predicate::matches("message", r"\d+ms$").unwrap()),
+            Query::from(Predicate::Compare {
+                field: "id".to_string(),
+                op: Comparison::Lt,
+                value: 250.0,
+            }),
+        ];
+        if depth == 0 || rng.gen_bool(0.3) {
+            return rng.choose(&leaves).unwrap().clone();
+        }
+        let children = (0..rng.gen_range(1, 4))
+            .map(|_| random_query(rng, depth - 1))
+            .collect();
+        match rng.gen_range(0, 3) {
+            0 => Query::And(children),
+            1 => Query::Or(children),
+            _ => !Query::And(children),
+        }
+    }
+
+    #[test]
+    fn test_negated_queries_have_no_false_negatives() {
+        let data = logs();
+        let mut rng = StdRng::seed_from_u64(38);
+        for _ in 0..100 {
+            // search asserts the cascade finds every record the verifier accepts
+            search(&data, random_query(&mut rng, 3));
+        }
+    }
+}
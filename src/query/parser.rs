@@ -0,0 +1,562 @@
+//! Parser for filter expressions such as
+//! `text CONTAINS 'rust' AND user.lang = 'en' OR retweets > 10`.
+//!
+//! ```text
+//! expr      := and ("OR" and)*
+//! and       := unary ("AND" unary)*
+//! unary     := "NOT" unary | "(" expr ")" | predicate
+//! predicate := field op value | field "IN" "(" string ("," string)* ")"
+//! field     := name ("." name)*
+//! op        := "=" | "!=" | "<>" | "<" | "<=" | ">" | ">=" | "CONTAINS" | "ICONTAINS"
+//!            | "MATCHES" | "~"
+//! value     := 'string' | number | /regex/
+//! ```
+//!
+//! Keywords are case-insensitive. Strings are single-quoted with `''` for a quote;
+//! in regex literals `\/` stands for a slash. `=` and `!=` compare strings or
+//! numbers depending on the value, the other comparisons need a number. `ICONTAINS`
+//! is `CONTAINS` on case-folded text.
+
+use std::collections::BTreeSet;
+
+use super::{Comparison, Predicate, Query};
+use utils::error::{Result, SparserError};
+
+/// Parses a filter expression.
+pub fn parse(input: &str) -> Result<Query> {
+    let tokens = tokenize(input)?;
+    let mut parser = Parser {
+        tokens,
+        pos: 0,
+        end: input.chars().count() + 1,
+    };
+    let query = parser.or()?;
+    match parser.peek() {
+        None => Ok(query),
+        Some(token) => Err(error(
+            format!("unexpected {}", token.kind.describe()),
+            token.column,
+        )),
+    }
+}
+
+fn error<M: Into<String>>(message: M, column: usize) -> SparserError {
+    SparserError::ParseError(format!("{} at column {}", message.into(), column))
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum TokenKind {
+    Name(String),
+    Str(String),
+    Number(f64),
+    Regex(String),
+    Op(&'static str),
+    LParen,
+    RParen,
+    Comma,
+}
+
+impl TokenKind {
+    fn describe(&self) -> String {
+        match *self {
+            TokenKind::Name(ref name) => format!("'{}'", name),
+            TokenKind::Str(_) => "string".to_string(),
+            TokenKind::Number(_) => "number".to_string(),
+            TokenKind::Regex(_) => "regex".to_string(),
+            TokenKind::Op(op) => format!("'{}'", op),
+            TokenKind::LParen => "'('".to_string(),
+            TokenKind::RParen => "')'".to_string(),
+            TokenKind::Comma => "','".to_string(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        match *self {
+            TokenKind::Name(ref name) => name.eq_ignore_ascii_case(keyword),
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Token {
+    kind: TokenKind,
+    /// One-based column of the first character.
+    column: usize,
+}
+
+const OPERATORS: [&str; 8] = ["<=", ">=", "!=", "<>", "=", "<", ">", "~"];
+
+fn tokenize(input: &str) -> Result<Vec<Token>> {
+    let chars: Vec<char> = input.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let column = i + 1;
+        if c.is_whitespace() {
+            i += 1;
+            continue;
+        }
+        let kind = if c == '(' {
+            i += 1;
+            TokenKind::LParen
+        } else if c == ')' {
+            i += 1;
+            TokenKind::RParen
+        } else if c == ',' {
+            i += 1;
+            TokenKind::Comma
+        } else if c == '\'' {
+            let mut value = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(error("unterminated string", column)),
+                    Some(&'\'') if chars.get(i + 1) == Some(&'\'') => {
+                        value.push('\'');
+                        i += 2;
+                    }
+                    Some(&'\'') => break,
+                    Some(&c) => {
+                        value.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            TokenKind::Str(value)
+        } else if c == '/' {
+            let mut pattern = String::new();
+            i += 1;
+            loop {
+                match chars.get(i) {
+                    None => return Err(error("unterminated regex", column)),
+                    Some(&'\\') if chars.get(i + 1) == Some(&'/') => {
+                        pattern.push('/');
+                        i += 2;
+                    }
+                    Some(&'/') => break,
+                    Some(&c) => {
+                        pattern.push(c);
+                        i += 1;
+                    }
+                }
+            }
+            i += 1;
+            TokenKind::Regex(pattern)
+        } else if c.is_ascii_digit()
+            || (c == '-' || c == '.') && chars.get(i + 1).is_some_and(|c| c.is_ascii_digit())
+        {
+            let start = i;
+            i += 1;
+            while i < chars.len() {
+                let c = chars[i];
+                let exponent_sign =
+                    (c == '-' || c == '+') && (chars[i - 1] == 'e' || chars[i - 1] == 'E');
+                if c.is_ascii_alphanumeric() || c == '.' || exponent_sign {
+                    i += 1;
+                } else {
+                    break;
+                }
+            }
+            let text: String = chars[start..i].iter().collect();
+            match text.parse() {
+                Ok(n) => TokenKind::Number(n),
+                Err(_) => return Err(error(format!("invalid number '{}'", text), column)),
+            }
+        } else if c.is_alphabetic() || c == '_' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_alphanumeric() || chars[i] == '_') {
+                i += 1;
+            }
+            TokenKind::Name(chars[start..i].iter().collect())
+        } else if c == '.' {
+            i += 1;
+            TokenKind::Op(".")
+        } else {
+            let rest: String = chars[i..chars.len().min(i + 2)].iter().collect();
+            match OPERATORS.iter().find(|op| rest.starts_with(*op)) {
+                Some(op) => {
+                    i += op.len();
+                    TokenKind::Op(op)
+                }
+                None => return Err(error(format!("unexpected character '{}'", c), column)),
+            }
+        };
+        tokens.push(Token { kind, column });
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+    /// Column just past the input, reported for a premature end.
+    end: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn next(&mut self, expected: &str) -> Result<Token> {
+        match self.tokens.get(self.pos) {
+            Some(token) => {
+                self.pos += 1;
+                Ok(token.clone())
+            }
+            None => Err(error(
+                format!("expected {}, found end of input", expected),
+                self.end,
+            )),
+        }
+    }
+
+    fn keyword(&mut self, keyword: &str) -> bool {
+        if self.peek().is_some_and(|t| t.kind.is_keyword(keyword)) {
+            self.pos += 1;
+            true
+        } else {
+            false
+        }
+    }
+
+    fn or(&mut self) -> Result<Query> {
+        let mut terms = vec![self.and()?];
+        while self.keyword("or") {
+            terms.push(self.and()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.pop().unwrap()
+        } else {
+            Query::Or(terms)
+        })
+    }
+
+    fn and(&mut self) -> Result<Query> {
+        let mut terms = vec![self.unary()?];
+        while self.keyword("and") {
+            terms.push(self.unary()?);
+        }
+        Ok(if terms.len() == 1 {
+            terms.pop().unwrap()
+        } else {
+            Query::And(terms)
+        })
+    }
+
+    fn unary(&mut self) -> Result<Query> {
+        if self.keyword("not") {
+            return Ok(!self.unary()?);
+        }
+        if self.peek().is_some_and(|t| t.kind == TokenKind::LParen) {
+            let open = self.next("'('")?;
+            let query = self.or()?;
+            return match self.peek() {
+                Some(token) if token.kind == TokenKind::RParen => {
+                    self.pos += 1;
+                    Ok(query)
+                }
+                Some(token) => Err(error(
+                    format!("expected ')', found {}", token.kind.describe()),
+                    token.column,
+                )),
+                None => Err(error(
+                    format!("unclosed '(' from column {}", open.column),
+                    self.end,
+                )),
+            };
+        }
+        self.predicate()
+    }
+
+    fn field(&mut self) -> Result<String> {
+        let mut field = String::new();
+        loop {
+            let token = self.next("a field name")?;
+            match token.kind {
+                TokenKind::Name(ref name) if !is_reserved(name) => field.push_str(name),
+                ref kind => {
+                    return Err(error(
+                        format!("expected a field name, found {}", kind.describe()),
+                        token.column,
+                    ))
+                }
+            }
+            if self.peek().is_some_and(|t| t.kind == TokenKind::Op(".")) {
+                self.pos += 1;
+                field.push('.');
+            } else {
+                return Ok(field);
+            }
+        }
+    }
+
+    fn predicate(&mut self) -> Result<Query> {
+        let field = self.field()?;
+        if self.keyword("in") {
+            return self
+                .values()
+                .map(|values| Predicate::In { field, values }.into());
+        }
+        let op = self.next("an operator")?;
+        let op_name = match op.kind {
+            TokenKind::Op(op) if op != "." => op.to_string(),
+            TokenKind::Name(ref name) if name.eq_ignore_ascii_case("contains") => {
+                "contains".to_string()
+            }
+            TokenKind::Name(ref name) if name.eq_ignore_ascii_case("icontains") => {
+                "icontains".to_string()
+            }
+            TokenKind::Name(ref name) if name.eq_ignore_ascii_case("matches") => "~".to_string(),
+            ref kind => {
+                return Err(error(
+                    format!("expected an operator, found {}", kind.describe()),
+                    op.column,
+                ))
+            }
+        };
+        let value = self.next("a value")?;
+        let mismatch = |what: &str| {
+            Err(error(
+                format!(
+                    "'{}' needs {}, found {}",
+                    op_name,
+                    what,
+                    value.kind.describe()
+                ),
+                value.column,
+            ))
+        };
+        let predicate = match (op_name.as_str(), &value.kind) {
+            ("=", TokenKind::Str(s)) => Predicate::Equals {
+                field,
+                value: s.clone(),
+            },
+            ("!=", TokenKind::Str(s)) | ("<>", TokenKind::Str(s)) => {
+                return Ok(!Query::from(Predicate::Equals {
+                    field,
+                    value: s.clone(),
+                }))
+            }
+            ("contains", TokenKind::Str(s)) => Predicate::Contains {
+                field,
+                value: s.clone(),
+            },
+            ("icontains", TokenKind::Str(s)) => Predicate::contains_folded(&field, s),
+            ("contains", _) | ("icontains", _) => return mismatch("a string"),
+            ("~", TokenKind::Str(pattern)) | ("~", TokenKind::Regex(pattern)) => {
+                Predicate::matches(&field, pattern)
+                    .map_err(|e| error(e.to_string(), value.column))?
+            }
+            ("~", _) => return mismatch("a regex"),
+            (op, TokenKind::Number(n)) => Predicate::Compare {
+                field,
+                op: comparison(op),
+                value: *n,
+            },
+            ("=", _) | ("!=", _) | ("<>", _) => return mismatch("a string or number"),
+            _ => return mismatch("a number"),
+        };
+        Ok(predicate.into())
+    }
+
+    /// Parses the parenthesized list of strings after `IN`.
+    fn values(&mut self) -> Result<BTreeSet<String>> {
+        let expect = |token: Token, kind: TokenKind| {
+            if token.kind == kind {
+                Ok(())
+            } else {
+                Err(error(
+                    format!(
+                        "expected {}, found {}",
+                        kind.describe(),
+                        token.kind.describe()
+                    ),
+                    token.column,
+                ))
+            }
+        };
+        expect(self.next("'('")?, TokenKind::LParen)?;
+        let mut values = BTreeSet::new();
+        loop {
+            let token = self.next("a string")?;
+            match token.kind {
+                TokenKind::Str(ref s) => values.insert(s.clone()),
+                ref kind => {
+                    return Err(error(
+                        format!("'IN' needs strings, found {}", kind.describe()),
+                        token.column,
+                    ))
+                }
+            };
+            let token = self.next("')'")?;
+            if token.kind != TokenKind::Comma {
+                expect(token, TokenKind::RParen)?;
+                return Ok(values);
+            }
+        }
+    }
+}
+
+fn comparison(op: &str) -> Comparison {
+    match op {
+        "<" => Comparison::Lt,
+        "<=" => Comparison::Le,
+        ">" => Comparison::Gt,
+        ">=" => Comparison::Ge,
+        "=" => Comparison::Eq,
+        _ => Comparison::Ne,
+    }
+}
+
+fn is_reserved(name: &str) -> bool {
+    ["and", "or", "not", "contains", "icontains", "matches", "in"]
+        .iter()
+        .any(|k| name.eq_ignore_ascii_case(k))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+
+    use super::*;
+
+    fn record(json: &str) -> Value {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn error_message(input: &str) -> String {
+        match parse(input) {
+            Err(SparserError::ParseError(message)) => message,
+            other => panic!("expected a parse error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_display_normalizes() {
+        let cases = [
+            (
+                "text contains 'rust'  and user.lang='en' or retweets>10",
+                "text CONTAINS 'rust' AND user.lang = 'en' OR retweets > 10",
+            ),
+            (
+                "not (a = 'it''s' or b ~ /x\\/y/) and not not c <> -2.5",
+                "NOT (a = 'it''s' OR b ~ /x\\/y/) AND NOT NOT c != -2.5",
+            ),
+            (
+                "a matches 'x' and (b = 1 and (c < 1e999 or d > 0))",
+                "a ~ /x/ AND (b = 1 AND (c < 1e999 OR d > 0))",
+            ),
+            ("(a != 'x')", "NOT a = 'x'"),
+            ("tag in ('b', 'a','b')", "tag IN ('a', 'b')"),
+            ("text icontains 'Straße'", "text ICONTAINS 'strasse'"),
+        ];
+        for &(input, normalized) in cases.iter() {
+            let query = parse(input).unwrap();
+            assert_eq!(normalized, query.to_string());
+            assert_eq!(normalized, parse(normalized).unwrap().to_string());
+        }
+    }
+
+    #[test]
+    fn test_parse_precedence() {
+        let query = parse("text CONTAINS 'rust' AND user.lang = 'en' OR retweets > 10").unwrap();
+        match query {
+            Query::Or(ref terms) => {
+                assert_eq!(2, terms.len());
+                match terms[0] {
+                    Query::And(ref terms) => assert_eq!(2, terms.len()),
+                    ref other => panic!("expected AND, got {:?}", other),
+                }
+            }
+            ref other => panic!("expected OR, got {:?}", other),
+        }
+        assert!(query.evaluate(&record(
+            r#"{"text": "rust 1.0", "user": {"lang": "en"}, "retweets": 0}"#
+        )));
+        assert!(query.evaluate(&record(
+            r#"{"text": "go", "user": {"lang": "de"}, "retweets": 11}"#
+        )));
+        assert!(!query.evaluate(&record(
+            r#"{"text": "rust", "user": {"lang": "de"}, "retweets": 10}"#
+        )));
+    }
+
+    #[test]
+    fn test_parse_operators() {
+        let r =
+            record(r#"{"msg": "timeout after 30ms", "path": "/a/b", "n": -2.5, "lang": "it's"}"#);
+        let matching = [
+            r"msg ~ /timeout after \d+ms/",
+            r"msg matches 'after \d+'",
+            r"path ~ /^\/a\//",
+            "n < 0 and n >= -2.5 and n <> 3 and n != -2.4e0",
+            "lang = 'it''s' AND NOT lang != 'it''s'",
+            "not (msg contains 'x' or n = 1)",
+            "((n <= -2.5))",
+            "lang IN ('en', 'it''s') and not msg in ('timeout')",
+            "msg ICONTAINS 'TIMEOUT' and lang icontains 'IT''S'",
+        ];
+        for input in matching.iter() {
+            assert!(parse(input).unwrap().evaluate(&r), "{}", input);
+        }
+        assert!(!parse("n > -1").unwrap().evaluate(&r));
+        assert!(!parse("n = 'x'").unwrap().evaluate(&r));
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(
+            "expected a value, found end of input at column 11",
+            error_message("retweets >")
+        );
+        assert_eq!(
+            "'>' needs a number, found string at column 12",
+            error_message("retweets > 'many'")
+        );
+        assert_eq!(
+            "unexpected 'lang' at column 12",
+            error_message("text = 'a' lang = 'en'")
+        );
+        assert_eq!(
+            "expected an operator, found string at column 6",
+            error_message("text 'a'")
+        );
+        assert_eq!(
+            "unterminated string at column 8",
+            error_message("text = 'rust")
+        );
+        assert_eq!(
+            "unclosed '(' from column 1 at column 15",
+            error_message("(text = 'rust'")
+        );
+        assert_eq!(
+            "unexpected character '#' at column 6",
+            error_message("text # 'a'")
+        );
+        assert_eq!(
+            "expected a field name, found 'AND' at column 1",
+            error_message("AND x = 1")
+        );
+        assert!(error_message("msg ~ /(/").ends_with("at column 7"));
+        assert_eq!(
+            "'IN' needs strings, found number at column 14",
+            error_message("tag IN ('a', 1)")
+        );
+        assert_eq!(
+            "expected ')', found string at column 13",
+            error_message("tag IN ('a' 'b')")
+        );
+        assert_eq!(
+            "expected '(', found string at column 8",
+            error_message("tag IN 'a'")
+        );
+        assert_eq!(
+            "expected a string, found end of input at column 13",
+            error_message("tag IN ('a',")
+        );
+    }
+}
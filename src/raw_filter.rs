@@ -0,0 +1,592 @@
+use std::fmt;
+use std::ops::Range;
+
+use aho_corasick::Automaton;
+use fingerprint::Fingerprint;
+use formats::csv::CsvFormat;
+use sparser_kernels;
+
+/// Cost of a fingerprint search over a byte relative to a substring search: each
+/// position is hashed and looked up in a table instead of compared with one byte.
+pub const FINGERPRINT_SEARCH_COST: f64 = 4.0;
+
+/// Number of literals up to which a fingerprint search costs `FINGERPRINT_SEARCH_COST`.
+/// Beyond it the table outgrows the L1 cache and more positions share a prefix with
+/// some literal, so the cost grows in proportion to the number of literals.
+pub const FINGERPRINT_FLAT_LITERALS: usize = 256;
+
+/// Cost of a case-insensitive substring search over a byte relative to a
+/// case-sensitive one, which compares each position with one case only.
+pub const CASELESS_SEARCH_COST: f64 = 2.0;
+
+/// Cost of an automaton search over a byte relative to a substring search: one
+/// dependent table lookup per byte, regardless of the number of literals.
+pub const AUTOMATON_SEARCH_COST: f64 = 8.0;
+
+/// A raw filter is a cheap test on the raw bytes of a record. A record that fails a
+/// raw filter cannot satisfy the query it was derived from, while a record that
+/// passes may still be a false positive and has to be verified.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum RawFilter {
+    /// The record contains the given bytes.
+    Substring(Vec<u8>),
+    /// The record contains the given bytes, ignoring ASCII case.
+    Caseless(Vec<u8>),
+    /// Field `column` of a CSV record contains the given bytes.
+    Column {
+        format: CsvFormat,
+        column: usize,
+        bytes: Vec<u8>,
+    },
+    /// The record contains the JSON key `key` with a number whose sign and number of
+    /// integer digits are allowed by `positive` (written without a minus sign) or
+    /// `negative` (written with one). Numbers in exponent notation pass if their
+    /// sign is allowed.
+    Number {
+        key: Vec<u8>,
+        positive: Option<DigitRange>,
+        negative: Option<DigitRange>,
+    },
+    /// The record contains `key` followed by `value`, with at most `window` bytes
+    /// other than whitespace in between, as in `"lang": "en"` for a window of one.
+    KeyValue {
+        key: Vec<u8>,
+        value: Vec<u8>,
+        window: usize,
+    },
+    /// The record contains any of a set of literals, found with a hashed fingerprint
+    /// of their prefixes. Suits disjunctions of more literals than substring
+    /// filters can search for one at a time.
+    AnyOf(Fingerprint),
+    /// The record contains any of a set of literals, found with an Aho-Corasick
+    /// automaton. Cheaper than `AnyOf` for many literals.
+    Automaton(Automaton),
+    /// The record passes the inner filter or contains a backslash. A JSON record
+    /// without backslashes holds its strings as they decode, while escapes such as
+    /// `\u00e9` or `\/` may hide the bytes the inner filter looks for.
+    OrEscaped(Box<RawFilter>),
+}
+
+/// An inclusive range of integer digit counts of a written number.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct DigitRange {
+    pub min: usize,
+    pub max: usize,
+}
+
+impl DigitRange {
+    /// Returns the digit counts of the integer parts of numbers with a magnitude in
+    /// `lo..=hi`, where `hi` may be infinite.
+    pub fn of_magnitudes(lo: f64, hi: f64) -> Self {
+        DigitRange {
+            min: integer_digits(lo),
+            max: if hi.is_finite() {
+                integer_digits(hi)
+            } else {
+                usize::MAX
+            },
+        }
+    }
+
+    pub fn contains(&self, digits: usize) -> bool {
+        self.min <= digits && digits <= self.max
+    }
+}
+
+/// Returns the number of digits JSON writes for the integer part of `x >= 0`.
+fn integer_digits(x: f64) -> usize {
+    format!("{:.0}", x.max(0.0).floor()).len()
+}
+
+impl RawFilter {
+    /// Creates a filter passing records that contain `bytes`.
+    pub fn substring<T: AsRef<[u8]>>(bytes: T) -> Self {
+        RawFilter::Substring(bytes.as_ref().to_vec())
+    }
+
+    /// Creates a filter passing records that contain `bytes`, ignoring ASCII case.
+    pub fn caseless<T: AsRef<[u8]>>(bytes: T) -> Self {
+        RawFilter::Caseless(bytes.as_ref().to_ascii_lowercase())
+    }
+
+    /// Creates a filter passing CSV records whose field `column` contains `bytes`.
+    pub fn column<T: AsRef<[u8]>>(format: CsvFormat, column: usize, bytes: T) -> Self {
+        RawFilter::Column {
+            format,
+            column,
+            bytes: bytes.as_ref().to_vec(),
+        }
+    }
+
+    /// Creates a filter passing records with a number under the JSON key `key`.
+    pub fn number<T: AsRef<[u8]>>(
+        key: T,
+        positive: Option<DigitRange>,
+        negative: Option<DigitRange>,
+    ) -> Self {
+        RawFilter::Number {
+            key: bytes_of_key(key.as_ref()),
+            positive,
+            negative,
+        }
+    }
+
+    /// Creates a filter passing records in which the JSON key `key` is followed by
+    /// `value` within `window` bytes, not counting whitespace.
+    pub fn key_value<K: AsRef<[u8]>, V: AsRef<[u8]>>(key: K, value: V, window: usize) -> Self {
+        RawFilter::KeyValue {
+            key: bytes_of_key(key.as_ref()),
+            value: value.as_ref().to_vec(),
+            window,
+        }
+    }
+
+    /// Creates a filter passing records that contain any of `literals`. None of them
+    /// may contain the record delimiter.
+    pub fn any_of<T: AsRef<[u8]>>(literals: &[T]) -> Self {
+        RawFilter::AnyOf(Fingerprint::new(literals))
+    }
+
+    /// Creates a filter passing records that contain any of `literals`, found with
+    /// an automaton. None of them may contain the record delimiter.
+    pub fn automaton<T: AsRef<[u8]>>(literals: &[T]) -> Self {
+        RawFilter::Automaton(Automaton::new(literals))
+    }
+
+    /// Creates a filter passing records that pass `filter` or contain a backslash.
+    pub fn or_escaped(filter: RawFilter) -> Self {
+        RawFilter::OrEscaped(Box::new(filter))
+    }
+
+    /// Returns the filters passing records that contain any of `literals`: one
+    /// searching with a fingerprint and one with an automaton, for the optimizer to
+    /// choose from by their search costs. A single literal is a substring filter.
+    pub fn any_of_candidates<T: AsRef<[u8]>>(literals: &[T]) -> Vec<RawFilter> {
+        match literals {
+            [literal] => vec![RawFilter::substring(literal)],
+            _ => vec![RawFilter::any_of(literals), RawFilter::automaton(literals)],
+        }
+    }
+
+    /// Returns whether `record` passes this filter.
+    pub fn matches(&self, record: &[u8]) -> bool {
+        match *self {
+            RawFilter::Substring(ref s) => sparser_kernels::find(record, s).is_some(),
+            RawFilter::Caseless(ref s) => {
+                sparser_kernels::find_ignore_ascii_case(record, s).is_some()
+            }
+            RawFilter::Column {
+                ref format,
+                column,
+                ref bytes,
+            } => {
+                // reject on the plain substring before splitting the record
+                sparser_kernels::find(record, bytes).is_some()
+                    && format
+                        .field(record, column)
+                        .is_some_and(|field| sparser_kernels::find(field, bytes).is_some())
+            }
+            RawFilter::Number {
+                ref key,
+                positive,
+                negative,
+            } => sparser_kernels::find_iter(record, key)
+                .any(|i| number_passes(&record[i + key.len()..], positive, negative)),
+            RawFilter::KeyValue {
+                ref key,
+                ref value,
+                window,
+            } => sparser_kernels::find_iter(record, key)
+                .any(|i| value_follows(&record[i + key.len()..], value, window)),
+            RawFilter::AnyOf(ref fingerprint) => fingerprint.find(record).is_some(),
+            RawFilter::Automaton(ref automaton) => automaton.find(record).is_some(),
+            RawFilter::OrEscaped(ref filter) => {
+                sparser_kernels::find_byte(record, b'\\').is_some() || filter.matches(record)
+            }
+        }
+    }
+
+    /// Returns the byte range of the first position in `data` where this filter may
+    /// match. Used to drive a scan over many records: only the record around the
+    /// match needs to be looked at. Unless `find_is_exact` is true, that record must
+    /// still be checked with `matches`.
+    pub fn find(&self, data: &[u8]) -> Option<Range<usize>> {
+        match *self {
+            RawFilter::Substring(ref s)
+            | RawFilter::Column { bytes: ref s, .. }
+            | RawFilter::Number { key: ref s, .. }
+            | RawFilter::KeyValue { key: ref s, .. } => {
+                sparser_kernels::find(data, s).map(|start| start..start + s.len())
+            }
+            RawFilter::Caseless(ref s) => {
+                sparser_kernels::find_ignore_ascii_case(data, s).map(|start| start..start + s.len())
+            }
+            RawFilter::AnyOf(ref fingerprint) => fingerprint.find(data),
+            RawFilter::Automaton(ref automaton) => automaton.find(data),
+            RawFilter::OrEscaped(ref filter) => {
+                // only a backslash before the inner match comes first
+                let m = filter.find(data);
+                let limit = m.as_ref().map_or(data.len(), |m| m.start);
+                match sparser_kernels::find_byte(&data[..limit], b'\\') {
+                    Some(i) => Some(i..i + 1),
+                    None => m,
+                }
+            }
+        }
+    }
+
+    /// Returns whether a match reported by `find` implies that the record around it
+    /// passes the filter.
+    pub fn find_is_exact(&self) -> bool {
+        match *self {
+            RawFilter::Substring(_)
+            | RawFilter::Caseless(_)
+            | RawFilter::AnyOf(_)
+            | RawFilter::Automaton(_) => true,
+            RawFilter::Column { .. } | RawFilter::Number { .. } | RawFilter::KeyValue { .. } => {
+                false
+            }
+            // a backslash passes the record whatever the inner filter says
+            RawFilter::OrEscaped(ref filter) => filter.find_is_exact(),
+        }
+    }
+
+    /// Returns the cost of searching a byte with this filter relative to a substring
+    /// search, which the optimizer scales its per-byte search cost by.
+    pub fn search_cost(&self) -> f64 {
+        match *self {
+            RawFilter::AnyOf(ref fingerprint) => {
+                let literals = fingerprint.literals().len();
+                FINGERPRINT_SEARCH_COST
+                    * (literals as f64 / FINGERPRINT_FLAT_LITERALS as f64).max(1.0)
+            }
+            RawFilter::Automaton(_) => AUTOMATON_SEARCH_COST,
+            RawFilter::Caseless(_) => CASELESS_SEARCH_COST,
+            // the search for a backslash is one more byte search
+            RawFilter::OrEscaped(ref filter) => filter.search_cost() + 1.0,
+            _ => 1.0,
+        }
+    }
+}
+
+impl fmt::Display for RawFilter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            RawFilter::Substring(ref s) => write!(f, "\"{}\"", escape(s)),
+            RawFilter::Caseless(ref s) => write!(f, "i\"{}\"", escape(s)),
+            RawFilter::Column {
+                column, ref bytes, ..
+            } => write!(f, "${}:\"{}\"", column, escape(bytes)),
+            RawFilter::Number {
+                ref key,
+                positive,
+                negative,
+            } => write!(
+                f,
+                "\"{}\":number(+{}, -{})",
+                escape(&key[1..key.len() - 1]),
+                display_digits(positive),
+                display_digits(negative)
+            ),
+            RawFilter::KeyValue {
+                ref key,
+                ref value,
+                window,
+            } => write!(
+                f,
+                "\"{}\"..{}..\"{}\"",
+                escape(&key[1..key.len() - 1]),
+                window,
+                escape(value)
+            ),
+            RawFilter::AnyOf(ref fingerprint) => {
+                write!(f, "any of ")?;
+                display_literals(f, fingerprint.literals())
+            }
+            RawFilter::Automaton(ref automaton) => {
+                write!(f, "automaton of ")?;
+                display_literals(f, automaton.literals())
+            }
+            RawFilter::OrEscaped(ref filter) => write!(f, "{} or escaped", filter),
+        }
+    }
+}
+
+/// Number of literals a filter on a set of literals lists when displayed.
+const DISPLAYED_LITERALS: usize = 3;
+
+fn display_literals(f: &mut fmt::Formatter, literals: &[Vec<u8>]) -> fmt::Result {
+    write!(f, "{} literals (", literals.len())?;
+    for (i, literal) in literals.iter().take(DISPLAYED_LITERALS).enumerate() {
+        if i > 0 {
+            write!(f, ", ")?;
+        }
+        write!(f, "\"{}\"", escape(literal))?;
+    }
+    if literals.len() > DISPLAYED_LITERALS {
+        write!(f, ", ...")?;
+    }
+    write!(f, ")")
+}
+
+/// Returns the quoted key as it is searched for.
+fn bytes_of_key(key: &[u8]) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(key.len() + 2);
+    bytes.push(b'"');
+    bytes.extend_from_slice(key);
+    bytes.push(b'"');
+    bytes
+}
+
+/// Returns whether the value following a key, `": 123..."`, is a number allowed by
+/// the digit ranges. Anything unexpected fails, as it is not a number.
+fn number_passes(rest: &[u8], positive: Option<DigitRange>, negative: Option<DigitRange>) -> bool {
+    let mut i = 0;
+    let skip_space = |i: &mut usize| {
+        while rest.get(*i).is_some_and(|b| b" \t\r\n".contains(b)) {
+            *i += 1;
+        }
+    };
+    skip_space(&mut i);
+    if rest.get(i) != Some(&b':') {
+        return false;
+    }
+    i += 1;
+    skip_space(&mut i);
+    let range = if rest.get(i) == Some(&b'-') {
+        i += 1;
+        negative
+    } else {
+        positive
+    };
+    let range = match range {
+        Some(range) => range,
+        None => return false,
+    };
+    let digits = rest[i.min(rest.len())..]
+        .iter()
+        .take_while(|b| b.is_ascii_digit())
+        .count();
+    if digits == 0 {
+        return false;
+    }
+    match rest.get(i + digits) {
+        Some(&b'e') | Some(&b'E') => true,
+        Some(&b'.') => {
+            let fraction = rest[i + digits + 1..]
+                .iter()
+                .take_while(|b| b.is_ascii_digit())
+                .count();
+            let exponent = rest.get(i + digits + 1 + fraction);
+            exponent == Some(&b'e') || exponent == Some(&b'E') || range.contains(digits)
+        }
+        _ => range.contains(digits),
+    }
+}
+
+/// Returns whether `rest`, the bytes after a key, starts with `value` after at most
+/// `window` bytes other than whitespace.
+fn value_follows(rest: &[u8], value: &[u8], window: usize) -> bool {
+    let mut skipped = 0;
+    for (i, b) in rest.iter().enumerate() {
+        if rest[i..].starts_with(value) {
+            return true;
+        }
+        if !b" \t\r\n".contains(b) {
+            skipped += 1;
+            if skipped > window {
+                return false;
+            }
+        }
+    }
+    value.is_empty()
+}
+
+fn display_digits(range: Option<DigitRange>) -> String {
+    match range {
+        None => "none".to_string(),
+        Some(DigitRange { min, max }) if max == usize::MAX => format!("{}..", min),
+        Some(DigitRange { min, max }) => format!("{}..{}", min, max),
+    }
+}
+
+/// Renders `bytes` as text, escaping quotes, backslashes and non-printable bytes.
+pub fn escape(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len());
+    for &b in bytes {
+        match b {
+            b'"' => out.push_str("\\\""),
+            b'\\' => out.push_str("\\\\"),
+            b'\n' => out.push_str("\\n"),
+            b'\t' => out.push_str("\\t"),
+            0x20..=0x7e => out.push(b as char),
+            _ => out.push_str(&format!("\\x{:02x}", b)),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_substring_filter() {
+        let filter = RawFilter::substring("lang");
+        assert!(filter.matches(b"{\"lang\": \"en\"}"));
+        assert!(!filter.matches(b"{\"text\": \"en\"}"));
+        assert_eq!(Some(2..6), filter.find(b"{\"lang\": \"en\"}"));
+    }
+
+    #[test]
+    fn test_caseless_filter() {
+        let filter = RawFilter::caseless("RuSt");
+        assert_eq!(RawFilter::Caseless(b"rust".to_vec()), filter);
+        assert!(filter.matches(b"{\"text\": \"RUST 1.0\"}"));
+        assert!(!filter.matches(b"{\"text\": \"r\xc3\xbcst\"}"));
+        assert!(filter.find_is_exact());
+        assert_eq!(Some(10..14), filter.find(b"{\"text\": \"Rust\"}"));
+        assert_eq!(CASELESS_SEARCH_COST, filter.search_cost());
+        assert_eq!("i\"rust\"", filter.to_string());
+    }
+
+    #[test]
+    fn test_or_escaped_filter() {
+        let filter = RawFilter::or_escaped(RawFilter::substring("café"));
+        assert!(filter.matches(b"{\"text\": \"caf\\u00e9\"}"));
+        assert!(filter.matches("{\"text\": \"café\"}".as_bytes()));
+        assert!(!filter.matches(b"{\"text\": \"cafe\"}"));
+        let data = b"{\"a\": \"caf\\u00e9\"}\n{\"b\": \"cafe\"}";
+        assert_eq!(Some(10..11), filter.find(data));
+        assert_eq!(Some(2..7), filter.find("{\"café\\\"}".as_bytes()));
+        assert!(filter.find_is_exact());
+        assert!(!RawFilter::or_escaped(RawFilter::key_value("a", "\"x\"", 1)).find_is_exact());
+        assert_eq!(2.0, filter.search_cost());
+        assert_eq!("\"caf\\xc3\\xa9\" or escaped", filter.to_string());
+    }
+
+    #[test]
+    fn test_column_filter() {
+        let filter = RawFilter::column(CsvFormat::csv(), 1, "en");
+        assert!(filter.matches(b"1,en,hello"));
+        assert!(filter.matches(b"1,\"en, fr\",hello"));
+        assert!(!filter.matches(b"1,de,often"));
+        assert!(!filter.matches(b"\"en,\",de,x"));
+        assert!(!filter.find_is_exact());
+        assert_eq!(Some(8..10), filter.find(b"1,de,often"));
+    }
+
+    #[test]
+    fn test_number_filter() {
+        // integer parts of 1000 and more
+        let filter = RawFilter::number(
+            "retweets",
+            Some(DigitRange::of_magnitudes(1000.0, f64::INFINITY)),
+            None,
+        );
+        assert!(filter.matches(b"{\"retweets\": 1500}"));
+        assert!(filter.matches(b"{\"retweets\" :1000.5, \"x\": 1}"));
+        assert!(filter.matches(b"{\"retweets\": 1e2}"));
+        assert!(filter.matches(b"{\"retweets\": 1.5E9}"));
+        assert!(filter.matches(b"{\"a\": {\"retweets\": 7}, \"retweets\": 12345}"));
+        assert!(!filter.matches(b"{\"retweets\": 999.99}"));
+        assert!(!filter.matches(b"{\"retweets\": -5000}"));
+        assert!(!filter.matches(b"{\"retweets\": \"5000\"}"));
+        assert!(!filter.matches(b"{\"text\": \"\\\"retweets\\\": 5000\"}"));
+        assert!(!filter.find_is_exact());
+        assert_eq!("\"retweets\":number(+4.., -none)", filter.to_string());
+
+        let filter = RawFilter::number(
+            "t",
+            Some(DigitRange::of_magnitudes(0.0, 99.5)),
+            Some(DigitRange::of_magnitudes(0.0, 10.0)),
+        );
+        assert!(filter.matches(b"{\"t\":-10}"));
+        assert!(filter.matches(b"{\"t\":99}"));
+        assert!(!filter.matches(b"{\"t\":-100}"));
+        assert!(!filter.matches(b"{\"t\":100}"));
+    }
+
+    #[test]
+    fn test_key_value_filter() {
+        let filter = RawFilter::key_value("lang", "\"en\"", 1);
+        assert!(filter.matches(b"{\"lang\":\"en\"}"));
+        assert!(filter.matches(b"{\"lang\" :\n\t \"en\"}"));
+        assert!(filter.matches(b"{\"lang\": \"de\", \"x\": {\"lang\": \"en\"}}"));
+        assert!(!filter.matches(b"{\"lang\": \"de\", \"text\": \"en\"}"));
+        assert!(!filter.matches(b"{\"lang\": [\"en\"]}"));
+        assert!(!filter.matches(b"{\"text\": \"en\", \"lang\""));
+        assert!(RawFilter::key_value("lang", "\"en\"", 2).matches(b"{\"lang\": [\"en\"]}"));
+        assert!(!filter.find_is_exact());
+        assert_eq!(Some(1..7), filter.find(b"{\"lang\": \"de\"}"));
+        assert_eq!("\"lang\"..1..\"\\\"en\\\"\"", filter.to_string());
+    }
+
+    #[test]
+    fn test_any_of_filter() {
+        let filter = RawFilter::any_of(&["\"#rust\"", "\"#go\"", "\"#c\"", "\"#zig\""]);
+        assert!(filter.matches(b"{\"tags\": [\"#java\", \"#go\"]}"));
+        assert!(!filter.matches(b"{\"tags\": [\"#golang\"]}"));
+        assert!(filter.find_is_exact());
+        assert_eq!(Some(9..14), filter.find(b"{\"tags\": \"#go\", \"#rust\"}"));
+        assert_eq!(FINGERPRINT_SEARCH_COST, filter.search_cost());
+        assert_eq!(1.0, RawFilter::substring("go").search_cost());
+        assert_eq!(
+            "any of 4 literals (\"\\\"#c\\\"\", \"\\\"#go\\\"\", \"\\\"#rust\\\"\", ...)",
+            filter.to_string()
+        );
+        assert_eq!(
+            filter,
+            RawFilter::any_of(&["\"#zig\"", "\"#c\"", "\"#go\"", "\"#rust\""])
+        );
+    }
+
+    #[test]
+    fn test_automaton_filter() {
+        let literals = ["\"#rust\"", "\"#go\"", "\"#c\"", "\"#zig\""];
+        let filter = RawFilter::automaton(&literals);
+        assert!(filter.matches(b"{\"tags\": [\"#java\", \"#go\"]}"));
+        assert!(!filter.matches(b"{\"tags\": [\"#golang\"]}"));
+        assert!(filter.find_is_exact());
+        assert_eq!(Some(9..14), filter.find(b"{\"tags\": \"#go\", \"#rust\"}"));
+        assert_eq!(
+            "automaton of 4 literals (\"\\\"#c\\\"\", \"\\\"#go\\\"\", \"\\\"#rust\\\"\", ...)",
+            filter.to_string()
+        );
+        assert_eq!(
+            vec![RawFilter::any_of(&literals), filter],
+            RawFilter::any_of_candidates(&literals)
+        );
+        assert_eq!(
+            vec![RawFilter::substring("go")],
+            RawFilter::any_of_candidates(&["go"])
+        );
+
+        // the fingerprint is cheaper for few literals, the automaton for many
+        let many: Vec<String> = (0..4 * FINGERPRINT_FLAT_LITERALS)
+            .map(|i| format!("\"#{}\"", i))
+            .collect();
+        let costs = |literals: &[String]| {
+            RawFilter::any_of_candidates(literals)
+                .iter()
+                .map(RawFilter::search_cost)
+                .collect::<Vec<_>>()
+        };
+        let few = costs(&many[..FINGERPRINT_FLAT_LITERALS]);
+        assert!(few[0] < few[1]);
+        let all = costs(&many);
+        assert!(all[0] > all[1]);
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(
+            "\"\\\"en\\\"\\x01\"",
+            RawFilter::substring(b"\"en\"\x01").to_string()
+        );
+        assert_eq!(
+            "$2:\"en\"",
+            RawFilter::column(CsvFormat::tsv(), 2, "en").to_string()
+        );
+    }
+}
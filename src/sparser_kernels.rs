@@ -27,12 +27,502 @@ fn ffs(x: i32) -> i32 {
         r += 2;
     }
     if (val & 1) == 0 {
-        val >>= 1;
         r += 1;
     }
     r
 }
 
+/** Returns the match mask of an 8-bit search string.
+ *
+ * @param reg the register filled with the search value
+ * @param base the data to search. Should be at least 32 bytes long.
+ *
+ * @return a mask where bit `i` is set if a match starts at byte `i`.
+ */
+#[inline]
+pub fn mask_epi8(reg: __m256i, base: __m256i) -> u32 {
+    unsafe { _mm256_movemask_epi8(_mm256_cmpeq_epi8(reg, base)) as u32 }
+}
+
+/** Returns the match mask of a 16-bit search string.
+ *
+ * @param reg the register filled with the search value
+ * @param base the data to search. Should be at least 32 bytes long.
+ *
+ * @return a mask where bit `i` is set if a match starts at byte `i`. Only even
+ * bytes can be set.
+ */
+#[inline]
+pub fn mask_epi16(reg: __m256i, base: __m256i) -> u32 {
+    unsafe { _mm256_movemask_epi8(_mm256_cmpeq_epi16(reg, base)) as u32 & 0x55555555 }
+}
+
+/** Returns the match mask of a 32-bit search string.
+ *
+ * @param reg the register filled with the search value
+ * @param base the data to search. Should be at least 32 bytes long.
+ *
+ * @return a mask where bit `i` is set if a match starts at byte `i`. Only bytes
+ * that are a multiple of four can be set.
+ */
+#[inline]
+pub fn mask_epi32(reg: __m256i, base: __m256i) -> u32 {
+    unsafe { _mm256_movemask_epi8(_mm256_cmpeq_epi32(reg, base)) as u32 & 0x11111111 }
+}
+
+/** Finds the first occurrence of `needle` in `haystack`.
+ *
+ * Compares the first and last byte of `needle` against 32 positions at a time
+ * and only runs a full comparison where both match. Falls back to a scalar
+ * search on CPUs without AVX2.
+ *
+ * @return the byte offset of the match.
+ */
+pub fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    if is_x86_feature_detected!("avx2") {
+        unsafe { find_avx2(haystack, needle) }
+    } else {
+        find_scalar(haystack, needle, 0)
+    }
+}
+
+/** Iterates over the starting offsets of all (possibly overlapping) occurrences
+ * of `needle` in `haystack`.
+ */
+pub fn find_iter<'a>(haystack: &'a [u8], needle: &'a [u8]) -> FindIter<'a> {
+    FindIter {
+        haystack,
+        needle,
+        pos: 0,
+    }
+}
+
+pub struct FindIter<'a> {
+    haystack: &'a [u8],
+    needle: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Iterator for FindIter<'a> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.needle.is_empty() || self.pos > self.haystack.len() {
+            return None;
+        }
+        match find(&self.haystack[self.pos..], self.needle) {
+            Some(i) => {
+                let found = self.pos + i;
+                self.pos = found + 1;
+                Some(found)
+            }
+            None => {
+                self.pos = self.haystack.len() + 1;
+                None
+            }
+        }
+    }
+}
+
+/** Finds the first occurrence of the byte `b` in `haystack`. */
+#[inline]
+pub fn find_byte(haystack: &[u8], b: u8) -> Option<usize> {
+    find(haystack, &[b])
+}
+
+/** Finds the last occurrence of the byte `b` in `haystack`. */
+#[inline]
+pub fn rfind_byte(haystack: &[u8], b: u8) -> Option<usize> {
+    haystack.iter().rposition(|&c| c == b)
+}
+
+/** Counts the occurrences of the byte `b` in `haystack`. */
+pub fn count_byte(haystack: &[u8], b: u8) -> usize {
+    if is_x86_feature_detected!("avx2") {
+        unsafe { count_byte_avx2(haystack, b) }
+    } else {
+        count_byte_scalar(haystack, b)
+    }
+}
+
+fn count_byte_scalar(haystack: &[u8], b: u8) -> usize {
+    haystack.iter().filter(|&&c| c == b).count()
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn count_byte_avx2(haystack: &[u8], b: u8) -> usize {
+    let reg = _mm256_set1_epi8(b as i8);
+    let ptr = haystack.as_ptr();
+    let mut count = 0;
+    let mut i = 0;
+    while i + 32 <= haystack.len() {
+        let block = _mm256_loadu_si256(ptr.add(i) as *const __m256i);
+        count += mask_epi8(reg, block).count_ones() as usize;
+        i += 32;
+    }
+    count + count_byte_scalar(&haystack[i..], b)
+}
+
+/** Finds the first occurrence of `needle` in `haystack`, ignoring ASCII case.
+ *
+ * Works like `find`, comparing the first and last byte of `needle` in both cases.
+ *
+ * @return the byte offset of the match.
+ */
+pub fn find_ignore_ascii_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    if needle.is_empty() {
+        return Some(0);
+    }
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    if is_x86_feature_detected!("avx2") {
+        unsafe { find_ignore_ascii_case_avx2(haystack, needle) }
+    } else {
+        find_ignore_ascii_case_scalar(haystack, needle, 0)
+    }
+}
+
+fn find_ignore_ascii_case_scalar(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (from..haystack.len() - needle.len() + 1)
+        .find(|&i| haystack[i..i + needle.len()].eq_ignore_ascii_case(needle))
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn find_ignore_ascii_case_avx2(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let n = needle.len();
+    let cases = |b: u8| {
+        (
+            _mm256_set1_epi8(b.to_ascii_lowercase() as i8),
+            _mm256_set1_epi8(b.to_ascii_uppercase() as i8),
+        )
+    };
+    let (first_lower, first_upper) = cases(needle[0]);
+    let (last_lower, last_upper) = cases(needle[n - 1]);
+    let ptr = haystack.as_ptr();
+    let mut i = 0;
+    while i + n - 1 + 32 <= haystack.len() {
+        let block_first = _mm256_loadu_si256(ptr.add(i) as *const __m256i);
+        let block_last = _mm256_loadu_si256(ptr.add(i + n - 1) as *const __m256i);
+        let mut mask = (mask_epi8(first_lower, block_first) | mask_epi8(first_upper, block_first))
+            & (mask_epi8(last_lower, block_last) | mask_epi8(last_upper, block_last));
+        while mask != 0 {
+            let bit = mask.trailing_zeros() as usize;
+            if n <= 2
+                || haystack[i + bit + 1..i + bit + n - 1].eq_ignore_ascii_case(&needle[1..n - 1])
+            {
+                return Some(i + bit);
+            }
+            mask &= mask - 1;
+        }
+        i += 32;
+    }
+    find_ignore_ascii_case_scalar(haystack, needle, i)
+}
+
+/** Returns whether `data` is valid UTF-8.
+ *
+ * Checks 32 bytes at a time with the lookup algorithm of Keiser and Lemire,
+ * "Validating UTF-8 In Less Than One Instruction Per Byte": three table lookups
+ * on the nibbles of each byte and the byte before it classify every two-byte
+ * window, and saturating subtractions check that three- and four-byte sequences
+ * are continued. Blocks of ASCII are skipped. Falls back to the standard library
+ * on CPUs without AVX2.
+ */
+pub fn is_utf8(data: &[u8]) -> bool {
+    if is_x86_feature_detected!("avx2") {
+        unsafe { is_utf8_avx2(data) }
+    } else {
+        std::str::from_utf8(data).is_ok()
+    }
+}
+
+// Error classes of two-byte windows; a window is invalid if all three lookups
+// agree on some class.
+const TOO_SHORT: u8 = 1 << 0;
+const TOO_LONG: u8 = 1 << 1;
+const OVERLONG_3: u8 = 1 << 2;
+const TOO_LARGE: u8 = 1 << 3;
+const SURROGATE: u8 = 1 << 4;
+const OVERLONG_2: u8 = 1 << 5;
+const TOO_LARGE_1000: u8 = 1 << 6;
+const OVERLONG_4: u8 = 1 << 6;
+const TWO_CONTS: u8 = 1 << 7;
+const CARRY: u8 = TOO_SHORT | TOO_LONG | TWO_CONTS;
+
+/// Error classes by the high nibble of the first byte of a window.
+const BYTE_1_HIGH: [u8; 16] = [
+    // 0xxx: ASCII
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    TOO_LONG,
+    // 10xx: continuation
+    TWO_CONTS,
+    TWO_CONTS,
+    TWO_CONTS,
+    TWO_CONTS,
+    // 1100, 1101: two-byte lead
+    TOO_SHORT | OVERLONG_2,
+    TOO_SHORT,
+    // 1110: three-byte lead
+    TOO_SHORT | OVERLONG_3 | SURROGATE,
+    // 1111: four-byte lead
+    TOO_SHORT | TOO_LARGE | TOO_LARGE_1000 | OVERLONG_4,
+];
+
+/// Error classes by the low nibble of the first byte of a window.
+const BYTE_1_LOW: [u8; 16] = [
+    CARRY | OVERLONG_3 | OVERLONG_2 | OVERLONG_4,
+    CARRY | OVERLONG_2,
+    CARRY,
+    CARRY,
+    CARRY | TOO_LARGE,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000 | SURROGATE,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+    CARRY | TOO_LARGE | TOO_LARGE_1000,
+];
+
+/// Error classes by the high nibble of the second byte of a window.
+const BYTE_2_HIGH: [u8; 16] = [
+    // 0xxx: ASCII
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    // 1000, 1001, 101x: continuation
+    TOO_LONG | OVERLONG_2 | TWO_CONTS | OVERLONG_3 | TOO_LARGE_1000 | OVERLONG_4,
+    TOO_LONG | OVERLONG_2 | TWO_CONTS | OVERLONG_3 | TOO_LARGE,
+    TOO_LONG | OVERLONG_2 | TWO_CONTS | SURROGATE | TOO_LARGE,
+    TOO_LONG | OVERLONG_2 | TWO_CONTS | SURROGATE | TOO_LARGE,
+    // 11xx: lead
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+    TOO_SHORT,
+];
+
+#[target_feature(enable = "avx2")]
+unsafe fn is_utf8_avx2(data: &[u8]) -> bool {
+    let table =
+        |t: &[u8; 16]| _mm256_broadcastsi128_si256(_mm_loadu_si128(t.as_ptr() as *const __m128i));
+    let tables = (table(&BYTE_1_HIGH), table(&BYTE_1_LOW), table(&BYTE_2_HIGH));
+    // bytes at the end of a block that need a continuation in the next one
+    let incomplete_max = _mm256_setr_epi8(
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1, //
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        -1,
+        0b1111_0000u8 as i8 - 1,
+        0b1110_0000u8 as i8 - 1,
+        0b1100_0000u8 as i8 - 1,
+    );
+
+    let mut error = _mm256_setzero_si256();
+    let mut prev = _mm256_setzero_si256();
+    let mut prev_incomplete = _mm256_setzero_si256();
+    let mut i = 0;
+    while i + 32 <= data.len() {
+        let block = _mm256_loadu_si256(data.as_ptr().add(i) as *const __m256i);
+        if _mm256_movemask_epi8(block) == 0 {
+            error = _mm256_or_si256(error, prev_incomplete);
+        } else {
+            error = _mm256_or_si256(error, utf8_errors(block, prev, tables));
+            prev_incomplete = _mm256_subs_epu8(block, incomplete_max);
+        }
+        prev = block;
+        i += 32;
+    }
+    if i < data.len() {
+        // zeros after the tail are ASCII, so a sequence cut off by the end is too short
+        let mut tail = [0u8; 32];
+        tail[..data.len() - i].copy_from_slice(&data[i..]);
+        let block = _mm256_loadu_si256(tail.as_ptr() as *const __m256i);
+        error = _mm256_or_si256(error, utf8_errors(block, prev, tables));
+    } else {
+        error = _mm256_or_si256(error, prev_incomplete);
+    }
+    _mm256_testz_si256(error, error) == 1
+}
+
+/// Returns a non-zero byte for every invalid position of `block`, which follows
+/// `prev` in the input.
+#[target_feature(enable = "avx2")]
+unsafe fn utf8_errors(
+    block: __m256i,
+    prev: __m256i,
+    tables: (__m256i, __m256i, __m256i),
+) -> __m256i {
+    let low_nibbles = _mm256_set1_epi8(0x0f);
+    let high_nibble = |x: __m256i| _mm256_and_si256(_mm256_srli_epi16(x, 4), low_nibbles);
+    // the 16 bytes before each lane of the block
+    let before = _mm256_permute2x128_si256(prev, block, 0x21);
+    let prev1 = _mm256_alignr_epi8(block, before, 15);
+    let prev2 = _mm256_alignr_epi8(block, before, 14);
+    let prev3 = _mm256_alignr_epi8(block, before, 13);
+
+    let special_cases = _mm256_and_si256(
+        _mm256_and_si256(
+            _mm256_shuffle_epi8(tables.0, high_nibble(prev1)),
+            _mm256_shuffle_epi8(tables.1, _mm256_and_si256(prev1, low_nibbles)),
+        ),
+        _mm256_shuffle_epi8(tables.2, high_nibble(block)),
+    );
+    // the high bit is set where the third or fourth byte of a sequence is due
+    let third = _mm256_subs_epu8(prev2, _mm256_set1_epi8((0b1110_0000u8 - 0x80) as i8));
+    let fourth = _mm256_subs_epu8(prev3, _mm256_set1_epi8((0b1111_0000u8 - 0x80) as i8));
+    let must_be_continuation = _mm256_and_si256(
+        _mm256_or_si256(third, fourth),
+        _mm256_set1_epi8(0x80u8 as i8),
+    );
+    _mm256_xor_si256(must_be_continuation, special_cases)
+}
+
+fn find_scalar(haystack: &[u8], needle: &[u8], from: usize) -> Option<usize> {
+    if needle.len() > haystack.len() {
+        return None;
+    }
+    (from..haystack.len() - needle.len() + 1).find(|&i| &haystack[i..i + needle.len()] == needle)
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn find_avx2(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    let n = needle.len();
+    let first = _mm256_set1_epi8(needle[0] as i8);
+    let last = _mm256_set1_epi8(needle[n - 1] as i8);
+    let ptr = haystack.as_ptr();
+    let mut i = 0;
+    while i + n - 1 + 32 <= haystack.len() {
+        let block_first = _mm256_loadu_si256(ptr.add(i) as *const __m256i);
+        let block_last = _mm256_loadu_si256(ptr.add(i + n - 1) as *const __m256i);
+        let mut mask = mask_epi8(first, block_first) & mask_epi8(last, block_last);
+        while mask != 0 {
+            let bit = mask.trailing_zeros() as usize;
+            if n <= 2 || haystack[i + bit + 1..i + bit + n - 1] == needle[1..n - 1] {
+                return Some(i + bit);
+            }
+            mask &= mask - 1;
+        }
+        i += 32;
+    }
+    find_scalar(haystack, needle, i)
+}
+
+/** Bitmaps of the bytes of a 64-byte block of JSON that its structure depends on.
+ *
+ * Bit `i` of a mask is set if byte `i` of the block is one of its characters,
+ * whether it is inside a string or not.
+ */
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct JsonBitmaps {
+    pub quotes: u64,
+    pub backslashes: u64,
+    /// Braces, brackets, colons and commas.
+    pub structurals: u64,
+}
+
+/** Classifies the bytes of a 64-byte block of JSON.
+ *
+ * Compares both 32-byte halves of the block with each character and joins the
+ * movemasks. Brackets are compared as braces by setting bit 5, which maps `[`
+ * and `]` to `{` and `}` and no other byte to either. Falls back to a scalar
+ * loop on CPUs without AVX2.
+ */
+pub fn json_bitmaps(block: &[u8; 64]) -> JsonBitmaps {
+    if is_x86_feature_detected!("avx2") {
+        unsafe { json_bitmaps_avx2(block) }
+    } else {
+        json_bitmaps_scalar(block)
+    }
+}
+
+fn json_bitmaps_scalar(block: &[u8; 64]) -> JsonBitmaps {
+    let mut bitmaps = JsonBitmaps {
+        quotes: 0,
+        backslashes: 0,
+        structurals: 0,
+    };
+    for (i, &b) in block.iter().enumerate() {
+        match b {
+            b'"' => bitmaps.quotes |= 1 << i,
+            b'\\' => bitmaps.backslashes |= 1 << i,
+            b'{' | b'}' | b'[' | b']' | b':' | b',' => bitmaps.structurals |= 1 << i,
+            _ => {}
+        }
+    }
+    bitmaps
+}
+
+#[target_feature(enable = "avx2")]
+unsafe fn json_bitmaps_avx2(block: &[u8; 64]) -> JsonBitmaps {
+    let low = _mm256_loadu_si256(block.as_ptr() as *const __m256i);
+    let high = _mm256_loadu_si256(block.as_ptr().add(32) as *const __m256i);
+    let case = _mm256_set1_epi8(0x20);
+    let (low_folded, high_folded) = (_mm256_or_si256(low, case), _mm256_or_si256(high, case));
+    let mask = |c: u8, low: __m256i, high: __m256i| {
+        let reg = _mm256_set1_epi8(c as i8);
+        mask_epi8(reg, low) as u64 | (mask_epi8(reg, high) as u64) << 32
+    };
+    JsonBitmaps {
+        quotes: mask(b'"', low, high),
+        backslashes: mask(b'\\', low, high),
+        structurals: mask(b'{', low_folded, high_folded)
+            | mask(b'}', low_folded, high_folded)
+            | mask(b':', low, high)
+            | mask(b',', low, high),
+    }
+}
+
 #[test]
 fn test_ffs() {
     assert!(ffs(1) == 1);
@@ -95,7 +585,6 @@ pub fn search_epi32(reg: __m256i, base: __m256i) -> u32 {
     let mut count = 0;
     unsafe {
         let mut mask = _mm256_movemask_epi8(_mm256_cmpeq_epi32(reg, base));
-        println!("{:?}", mask);
         mask &= 0x11111111;
         while mask != 0 {
             let index = ffs(mask) - 1;
@@ -108,11 +597,17 @@ pub fn search_epi32(reg: __m256i, base: __m256i) -> u32 {
 
 #[cfg(test)]
 mod test {
+    use rand::{thread_rng, Rng};
     use sparser_kernels::search_epi16;
     use sparser_kernels::search_epi32;
     use sparser_kernels::search_epi8;
+    use sparser_kernels::{
+        count_byte, count_byte_scalar, find, find_ignore_ascii_case, find_ignore_ascii_case_scalar,
+        find_iter, find_scalar, is_utf8, json_bitmaps, json_bitmaps_scalar, mask_epi32,
+    };
     use std::arch::x86_64::__m256i;
     use std::arch::x86_64::_mm256_loadu_si256;
+    use std::arch::x86_64::_mm256_set1_epi32;
 
     #[test]
     fn test_search_epi32() {
@@ -155,4 +650,182 @@ mod test {
             assert_eq!(result, 1);
         }
     }
+
+    #[test]
+    fn test_mask_epi32() {
+        unsafe {
+            let reg = _mm256_set1_epi32(i32::from_le_bytes(*b"an i"));
+            let base: &[u8] = "an ian ian ian ian ian ian ian i".as_bytes();
+            let base_req: __m256i = _mm256_loadu_si256(base.as_ptr() as *const __m256i);
+            assert_eq!(0x11111111, mask_epi32(reg, base_req));
+        }
+    }
+
+    #[test]
+    fn test_find() {
+        let haystack = b"{\"text\": \"hello\"}\n{\"text\": \"sparser is fast\"}\n{\"id\": 3}\n";
+        assert_eq!(Some(0), find(haystack, b"{"));
+        assert_eq!(Some(39), find(haystack, b"fast"));
+        assert_eq!(Some(2), find(haystack, b"te"));
+        assert_eq!(None, find(haystack, b"slow"));
+        assert_eq!(None, find(b"ab", b"abc"));
+        assert_eq!(Some(0), find(b"abc", b""));
+        assert_eq!(
+            vec![1, 19, 47],
+            find_iter(haystack, b"\"").step_by(4).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn test_find_matches_scalar() {
+        let mut haystack = Vec::new();
+        for i in 0..500u32 {
+            haystack.extend_from_slice(format!("record {} value {}|", i, i * 7).as_bytes());
+        }
+        for needle in [
+            &b"7"[..],
+            b"|r",
+            b"value 34",
+            b"d 49",
+            b"record 499 value 3493|",
+        ]
+        .iter()
+        {
+            let expected: Vec<usize> = (0..haystack.len())
+                .filter(|&i| haystack[i..].starts_with(needle))
+                .collect();
+            let found: Vec<usize> = find_iter(&haystack, needle).collect();
+            assert_eq!(expected, found);
+            assert_eq!(expected.first().cloned(), find_scalar(&haystack, needle, 0));
+        }
+    }
+
+    #[test]
+    fn test_count_byte() {
+        let mut haystack = Vec::new();
+        for i in 0..100u32 {
+            haystack.extend_from_slice(format!("record {}\n", i).as_bytes());
+        }
+        for len in [0, 5, 31, 32, 33, 100, haystack.len()].iter() {
+            let data = &haystack[..*len];
+            assert_eq!(count_byte_scalar(data, b'\n'), count_byte(data, b'\n'));
+        }
+        assert_eq!(100, count_byte(&haystack, b'\n'));
+        assert_eq!(0, count_byte(&haystack, b'x'));
+    }
+
+    #[test]
+    fn test_find_ignore_ascii_case() {
+        let mut haystack = Vec::new();
+        for i in 0..100u32 {
+            haystack.extend_from_slice(format!("Record {} is {}|", i, "RuSt").as_bytes());
+        }
+        assert_eq!(Some(0), find_ignore_ascii_case(&haystack, b"rEcOrD"));
+        assert_eq!(Some(12), find_ignore_ascii_case(&haystack, b"rust|"));
+        assert_eq!(None, find_ignore_ascii_case(&haystack, b"rust 1"));
+        assert_eq!(None, find_ignore_ascii_case(b"\xc3\xa9", b"\xc3\x89"));
+        for needle in [&b"d 9"[..], b"S 9", b"|r", b"99 IS rust|"].iter() {
+            let expected = (0..haystack.len()).find(|&i| {
+                haystack[i..].len() >= needle.len()
+                    && haystack[i..i + needle.len()].eq_ignore_ascii_case(needle)
+            });
+            assert_eq!(expected, find_ignore_ascii_case(&haystack, needle));
+            assert_eq!(
+                expected,
+                find_ignore_ascii_case_scalar(&haystack, needle, 0)
+            );
+        }
+    }
+
+    #[test]
+    fn test_is_utf8() {
+        let cases: [&[u8]; 14] = [
+            b"",
+            b"plain ASCII",
+            "caf\u{e9} \u{4e2d}\u{6587} \u{1f980}".as_bytes(),
+            b"\x80",
+            b"\xc3",
+            b"\xc0\x80",
+            b"\xe0\x80\x80",
+            b"\xed\xa0\x80",
+            b"\xef\xbf\xbf",
+            b"\xf0\x80\x80\x80",
+            b"\xf4\x8f\xbf\xbf",
+            b"\xf4\x90\x80\x80",
+            b"\xf5\x80\x80\x80",
+            b"\xe2\x82",
+        ];
+        // at every alignment, and after and before a full block of ASCII
+        for case in cases.iter() {
+            for pad in 0..34 {
+                let mut data = vec![b'a'; pad];
+                data.extend_from_slice(case);
+                let expected = std::str::from_utf8(&data).is_ok();
+                assert_eq!(expected, is_utf8(&data), "{:?}", data);
+                data.extend_from_slice(&[b'z'; 40]);
+                assert_eq!(expected, is_utf8(&data), "{:?}", data);
+            }
+        }
+
+        let mut rng = thread_rng();
+        let pieces: [&[u8]; 10] = [
+            b"a",
+            b"\n",
+            "\u{e9}".as_bytes(),
+            "\u{20ac}".as_bytes(),
+            "\u{1f980}".as_bytes(),
+            b"\x80",
+            b"\xc3",
+            b"\xe2\x82",
+            b"\xf0\x9f\xa6",
+            b"\xff",
+        ];
+        for _ in 0..2000 {
+            let mut data = Vec::new();
+            let invalid = rng.gen_range(0, 4) == 0;
+            for _ in 0..rng.gen_range(0, 60) {
+                let piece = if invalid {
+                    *rng.choose(&pieces).unwrap()
+                } else {
+                    *rng.choose(&pieces[..5]).unwrap()
+                };
+                data.extend_from_slice(piece);
+            }
+            assert_eq!(
+                std::str::from_utf8(&data).is_ok(),
+                is_utf8(&data),
+                "{:?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn test_json_bitmaps() {
+        let mut block = [b' '; 64];
+        block[..24].copy_from_slice(b"{\"a\\\"\": [1, {}], \"b\": 2}");
+        block[63] = b']';
+        let bitmaps = json_bitmaps(&block);
+        let bits = |positions: &[u32]| positions.iter().fold(0u64, |m, &i| m | 1 << i);
+        assert_eq!(bits(&[1, 4, 5, 17, 19]), bitmaps.quotes);
+        assert_eq!(bits(&[3]), bitmaps.backslashes);
+        assert_eq!(
+            bits(&[0, 6, 8, 10, 12, 13, 14, 15, 20, 23, 63]),
+            bitmaps.structurals
+        );
+
+        let mut rng = thread_rng();
+        let alphabet = b"{}[]:,\"\\ a;=[]{}";
+        for _ in 0..200 {
+            let mut block = [0u8; 64];
+            for b in block.iter_mut() {
+                *b = if rng.gen() {
+                    *rng.choose(alphabet).unwrap()
+                } else {
+                    rng.gen()
+                };
+            }
+            assert_eq!(json_bitmaps_scalar(&block), json_bitmaps(&block));
+        }
+    }
 }
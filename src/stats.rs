@@ -0,0 +1,207 @@
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use cascade::{Cascade, LevelCounts};
+use formats::csv::CsvFormat;
+use sparser_kernels;
+
+/// Counters and timings of a scan, filled in by the scanners as they go.
+///
+/// Collecting them costs one pass counting record delimiters per chunk and two
+/// clock reads per verifier call, which is small next to the verifier itself.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ScanStats {
+    pub bytes_scanned: u64,
+    pub records: u64,
+    /// Records and bytes passing each cascade level. When the cascade changes
+    /// during a scan, level `i` sums the `i`-th levels of all cascades used.
+    pub levels: LevelCounts,
+    pub verifier_calls: u64,
+    /// Verifier calls that returned false, i.e. records the cascade let through
+    /// although they do not match.
+    pub verifier_rejects: u64,
+    /// Time spent reading (and decompressing) input.
+    pub read_time: Duration,
+    /// Time spent sampling and optimizing cascades.
+    pub calibration_time: Duration,
+    /// Time spent in the cascade, excluding the verifier.
+    pub filter_time: Duration,
+    pub verify_time: Duration,
+}
+
+impl ScanStats {
+    pub fn new() -> Self {
+        ScanStats::default()
+    }
+
+    /// Returns the number of records that passed the verifier.
+    pub fn matches(&self) -> u64 {
+        self.verifier_calls - self.verifier_rejects
+    }
+
+    /// Returns the fraction of records passing the cascade that the verifier
+    /// rejected, or zero if it was not called.
+    pub fn false_positive_rate(&self) -> f64 {
+        if self.verifier_calls == 0 {
+            0.0
+        } else {
+            self.verifier_rejects as f64 / self.verifier_calls as f64
+        }
+    }
+
+    /// Returns the total time of all phases. For a parallel scan, this is the time
+    /// summed over threads.
+    pub fn total_time(&self) -> Duration {
+        self.read_time + self.calibration_time + self.filter_time + self.verify_time
+    }
+
+    /// Adds the counters and timings of `other`, e.g. from another partition.
+    pub fn merge(&mut self, other: &ScanStats) {
+        self.bytes_scanned += other.bytes_scanned;
+        self.records += other.records;
+        self.levels.merge(&other.levels);
+        self.verifier_calls += other.verifier_calls;
+        self.verifier_rejects += other.verifier_rejects;
+        self.read_time += other.read_time;
+        self.calibration_time += other.calibration_time;
+        self.filter_time += other.filter_time;
+        self.verify_time += other.verify_time;
+    }
+
+    /// Runs `cascade` over the records of `data` and `verifier` over its candidates,
+    /// as `Cascade::for_each_candidate_counted` does, and records the scan. `counts`
+    /// receives this scan's level counts alone. Returns the number of records
+    /// `verifier` accepted.
+    ///
+    /// With `csv`, records end at newlines outside quoted fields; data where quoted
+    /// fields contain newlines is scanned with `for_each_csv_candidate_counted`.
+    pub(crate) fn scan<'a, F: FnMut(usize, &'a [u8]) -> bool>(
+        &mut self,
+        cascade: &Cascade,
+        data: &'a [u8],
+        delimiter: u8,
+        csv: Option<&CsvFormat>,
+        counts: &mut LevelCounts,
+        mut verifier: F,
+    ) -> u64 {
+        let start = Instant::now();
+        let mut verify_time = Duration::default();
+        let mut calls = 0;
+        let mut passed = 0;
+        let mut visit = |offset, record| {
+            let verify_start = Instant::now();
+            if verifier(offset, record) {
+                passed += 1;
+            }
+            verify_time += verify_start.elapsed();
+            calls += 1;
+        };
+        self.bytes_scanned += data.len() as u64;
+        match csv {
+            Some(format) if format.has_quoted_newline(data) => {
+                cascade.for_each_csv_candidate_counted(format, data, counts, &mut visit);
+                format.for_each_record(data, |_, _| self.records += 1);
+            }
+            _ => {
+                cascade.for_each_candidate_counted(data, delimiter, counts, &mut visit);
+                self.records += sparser_kernels::count_byte(data, delimiter) as u64;
+                if data.last().is_some_and(|&b| b != delimiter) {
+                    self.records += 1;
+                }
+            }
+        }
+        self.levels.merge(counts);
+        self.verifier_calls += calls;
+        self.verifier_rejects += calls - passed;
+        self.filter_time += start.elapsed().saturating_sub(verify_time);
+        self.verify_time += verify_time;
+        passed
+    }
+}
+
+impl fmt::Display for ScanStats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "scanned {} bytes, {} records",
+            self.bytes_scanned, self.records
+        )?;
+        for (i, (records, bytes)) in self
+            .levels
+            .records
+            .iter()
+            .zip(&self.levels.bytes)
+            .enumerate()
+        {
+            writeln!(
+                f,
+                "  level {}: {} records ({} bytes) passed",
+                i + 1,
+                records,
+                bytes
+            )?;
+        }
+        writeln!(
+            f,
+            "verified {} records, rejected {} ({:.2}% false positives)",
+            self.verifier_calls,
+            self.verifier_rejects,
+            self.false_positive_rate() * 100.0
+        )?;
+        write!(
+            f,
+            "time: read {:?}, calibrate {:?}, filter {:?}, verify {:?}",
+            self.read_time, self.calibration_time, self.filter_time, self.verify_time
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use raw_filter::RawFilter;
+
+    #[test]
+    fn test_scan_counts() {
+        let data = b"a en 1\nb de 2\nc en 3\nd en 4\ne fr";
+        let cascade = Cascade::new(vec![
+            RawFilter::substring(" en "),
+            RawFilter::substring("3"),
+        ]);
+        let mut stats = ScanStats::new();
+        let mut counts = LevelCounts::new(cascade.len());
+        let passed = stats.scan(&cascade, data, b'\n', None, &mut counts, |_, record| {
+            record.starts_with(b"d")
+        });
+        assert_eq!(0, passed);
+        assert_eq!(data.len() as u64, stats.bytes_scanned);
+        assert_eq!(5, stats.records);
+        assert_eq!(vec![3, 1], stats.levels.records);
+        assert_eq!(1, stats.verifier_calls);
+        assert_eq!(1, stats.verifier_rejects);
+        assert_eq!(1.0, stats.false_positive_rate());
+
+        let mut counts = LevelCounts::new(0);
+        let passed = stats.scan(
+            &Cascade::default(),
+            b"x\ny\n",
+            b'\n',
+            None,
+            &mut counts,
+            |_, _| true,
+        );
+        assert_eq!(2, passed);
+        assert_eq!(7, stats.records);
+        assert_eq!(vec![3, 1], stats.levels.records);
+        assert_eq!(3, stats.verifier_calls);
+        assert_eq!(2, stats.matches());
+
+        let mut total = ScanStats::new();
+        total.merge(&stats);
+        total.merge(&stats);
+        assert_eq!(14, total.records);
+        assert_eq!(vec![6, 2], total.levels.records);
+        assert_eq!(2 * stats.total_time(), total.total_time());
+        assert!(total.to_string().contains("level 2: 2 records"));
+    }
+}
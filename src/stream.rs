@@ -0,0 +1,424 @@
+use std::io::{ErrorKind, Read};
+use std::time::Instant;
+
+use cascade::{Cascade, LevelCounts};
+use formats::csv::CsvFormat;
+use sparser_kernels;
+use stats::ScanStats;
+use utils::buffer::MutableBuffer;
+use utils::error::{Result, SparserError};
+use utils::memory::AllocationMode;
+
+/// Default number of bytes read from the underlying stream per chunk.
+pub const DEFAULT_CHUNK_SIZE: usize = 1 << 20;
+
+/// A run of whole records read from a stream.
+#[derive(Debug)]
+pub struct Chunk<'a> {
+    /// Offset of the first byte of `data` in the stream.
+    pub offset: u64,
+    /// One or more complete records, including their delimiters.
+    pub data: &'a [u8],
+}
+
+/// ChunkReader reads any `Read` into a reusable aligned buffer and hands it out one
+/// chunk of whole records at a time. The partial record at the end of each read is
+/// carried over to the front of the next chunk, so memory stays bounded by the
+/// chunk size (or the longest record, if that is larger).
+pub struct ChunkReader<R> {
+    reader: R,
+    buffer: MutableBuffer,
+    delimiter: u8,
+    /// Length of the chunk handed out last; dropped from `buffer` on the next call.
+    chunk_len: usize,
+    /// Stream offset of the first byte in `buffer`.
+    offset: u64,
+    eof: bool,
+    validate_utf8: bool,
+    csv: Option<CsvFormat>,
+}
+
+impl<R: Read> ChunkReader<R> {
+    pub fn new(reader: R, delimiter: u8) -> Self {
+        Self::with_chunk_size(
+            reader,
+            delimiter,
+            DEFAULT_CHUNK_SIZE,
+            AllocationMode::Default,
+        )
+        .unwrap()
+    }
+
+    /// Creates a reader whose chunk buffer holds `chunk_size` bytes allocated with `mode`.
+    pub fn with_chunk_size(
+        reader: R,
+        delimiter: u8,
+        chunk_size: usize,
+        mode: AllocationMode,
+    ) -> Result<Self> {
+        Ok(ChunkReader {
+            reader,
+            buffer: MutableBuffer::with_mode(chunk_size, mode)?,
+            delimiter,
+            chunk_len: 0,
+            offset: 0,
+            eof: false,
+            validate_utf8: false,
+            csv: None,
+        })
+    }
+
+    /// Makes `next_chunk` fail with a `FormatError` at the first byte that is not
+    /// valid UTF-8.
+    pub fn with_utf8_validation(mut self) -> Self {
+        self.validate_utf8 = true;
+        self
+    }
+
+    /// Reads records of `format`, which end at newlines outside quoted fields. Chunks
+    /// never split a quoted field, and scanners reading from this reader locate
+    /// records the same way. The delimiter must be `\n`.
+    pub fn with_csv_records(mut self, format: CsvFormat) -> Self {
+        self.csv = Some(format);
+        self
+    }
+
+    pub fn delimiter(&self) -> u8 {
+        self.delimiter
+    }
+
+    /// Returns the CSV format set with `with_csv_records`.
+    pub fn csv_format(&self) -> Option<CsvFormat> {
+        self.csv
+    }
+
+    /// Returns the next chunk of whole records, or `None` at the end of the stream.
+    /// The last record of the stream need not be terminated by the delimiter.
+    pub fn next_chunk(&mut self) -> Result<Option<Chunk<'_>>> {
+        self.buffer.consume(self.chunk_len);
+        self.offset += self.chunk_len as u64;
+        self.chunk_len = 0;
+
+        loop {
+            self.fill()?;
+            if self.buffer.is_empty() {
+                return Ok(None);
+            }
+            if self.eof {
+                self.chunk_len = self.buffer.len();
+                break;
+            }
+            let complete = match self.csv {
+                Some(ref format) => format.complete_len(self.buffer.data()),
+                None => sparser_kernels::rfind_byte(self.buffer.data(), self.delimiter)
+                    .map_or(0, |i| i + 1),
+            };
+            match complete {
+                // a single record is longer than the buffer
+                0 => {
+                    let capacity = self.buffer.capacity() * 2;
+                    self.buffer.reserve(capacity)?
+                }
+                len => {
+                    self.chunk_len = len;
+                    break;
+                }
+            }
+        }
+        let data = &self.buffer.data()[..self.chunk_len];
+        if self.validate_utf8 {
+            check_utf8(data, self.offset)?;
+        }
+        Ok(Some(Chunk {
+            offset: self.offset,
+            data,
+        }))
+    }
+
+    /// Reads until the buffer is full or the stream ends.
+    fn fill(&mut self) -> Result<()> {
+        while !self.eof && self.buffer.len() < self.buffer.capacity() {
+            match self.reader.read(self.buffer.spare_capacity_mut()) {
+                Ok(0) => self.eof = true,
+                Ok(n) => {
+                    let len = self.buffer.len() + n;
+                    self.buffer.set_len(len);
+                }
+                Err(ref e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => return Err(e.into()),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fails with a `FormatError` at the first byte of `data` that is not valid UTF-8,
+/// where `offset` is the stream offset of `data`.
+pub fn check_utf8(data: &[u8], offset: u64) -> Result<()> {
+    if sparser_kernels::is_utf8(data) {
+        return Ok(());
+    }
+    let valid = match std::str::from_utf8(data) {
+        Ok(_) => data.len(),
+        Err(e) => e.valid_up_to(),
+    };
+    Err(SparserError::format("utf-8", "invalid UTF-8 sequence").at_offset(offset + valid as u64))
+}
+
+/// StreamScanner filters the records of a stream through a raw-filter cascade,
+/// chunk by chunk.
+pub struct StreamScanner<R> {
+    chunks: ChunkReader<R>,
+    cascade: Cascade,
+    stats: ScanStats,
+}
+
+impl<R: Read> StreamScanner<R> {
+    pub fn new(reader: R, cascade: Cascade, delimiter: u8) -> Self {
+        Self::with_chunk_reader(ChunkReader::new(reader, delimiter), cascade)
+    }
+
+    pub fn with_chunk_reader(chunks: ChunkReader<R>, cascade: Cascade) -> Self {
+        StreamScanner {
+            chunks,
+            cascade,
+            stats: ScanStats::new(),
+        }
+    }
+
+    pub fn cascade(&self) -> &Cascade {
+        &self.cascade
+    }
+
+    /// Returns the statistics of all searches so far.
+    pub fn stats(&self) -> &ScanStats {
+        &self.stats
+    }
+
+    /// Reads the stream to its end and calls `verifier` with the stream offset and
+    /// bytes of every record passing the cascade. Returns the number of records for
+    /// which `verifier` returned true.
+    pub fn search<F: FnMut(u64, &[u8]) -> bool>(&mut self, mut verifier: F) -> Result<u64> {
+        let delimiter = self.chunks.delimiter();
+        let csv = self.chunks.csv_format();
+        let mut passed = 0;
+        loop {
+            let read_start = Instant::now();
+            let chunk = self.chunks.next_chunk()?;
+            self.stats.read_time += read_start.elapsed();
+            let chunk = match chunk {
+                Some(chunk) => chunk,
+                None => break,
+            };
+            let base = chunk.offset;
+            let mut counts = LevelCounts::new(self.cascade.len());
+            passed += self.stats.scan(
+                &self.cascade,
+                chunk.data,
+                delimiter,
+                csv.as_ref(),
+                &mut counts,
+                |offset, record| verifier(base + offset as u64, record),
+            );
+        }
+        Ok(passed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, BufReader, Cursor};
+
+    use super::*;
+    use cascade::for_each_record;
+    use raw_filter::RawFilter;
+
+    fn tweets(n: usize) -> Vec<u8> {
+        let mut data = Vec::new();
+        for i in 0..n {
+            let lang = if i % 3 == 0 { "en" } else { "de" };
+            data.extend_from_slice(
+                format!(
+                    "{{\"id\": {}, \"text\": \"tweet {}\", \"lang\": \"{}\"}}\n",
+                    i, i, lang
+                )
+                .as_bytes(),
+            );
+        }
+        data
+    }
+
+    /// Returns data one byte per read call to exercise partial reads.
+    struct Trickle<'a>(&'a [u8]);
+
+    impl<'a> Read for Trickle<'a> {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            if self.0.is_empty() || buf.is_empty() {
+                return Ok(0);
+            }
+            buf[0] = self.0[0];
+            self.0 = &self.0[1..];
+            Ok(1)
+        }
+    }
+
+    fn expected(data: &[u8], cascade: &Cascade) -> Vec<(u64, Vec<u8>)> {
+        let mut records = Vec::new();
+        for_each_record(data, b'\n', |offset, record| {
+            if cascade.matches(record) {
+                records.push((offset as u64, record.to_vec()));
+            }
+        });
+        records
+    }
+
+    fn scan<R: Read>(reader: R, cascade: &Cascade, chunk_size: usize) -> Vec<(u64, Vec<u8>)> {
+        let chunks =
+            ChunkReader::with_chunk_size(reader, b'\n', chunk_size, AllocationMode::Default)
+                .unwrap();
+        let mut scanner = StreamScanner::with_chunk_reader(chunks, cascade.clone());
+        let mut records = Vec::new();
+        let passed = scanner
+            .search(|offset, record| {
+                records.push((offset, record.to_vec()));
+                true
+            })
+            .unwrap();
+        assert_eq!(passed as usize, records.len());
+        let stats = scanner.stats();
+        assert_eq!(passed, stats.matches());
+        assert_eq!(cascade.len(), stats.levels.records.len());
+        records
+    }
+
+    #[test]
+    fn test_records_across_chunk_boundaries() {
+        let data = tweets(1000);
+        let cascade = Cascade::new(vec![RawFilter::substring("\"en\"")]);
+        let expected = expected(&data, &cascade);
+        assert_eq!(334, expected.len());
+        for chunk_size in [64, 100, 4096, DEFAULT_CHUNK_SIZE].iter() {
+            assert_eq!(expected, scan(Cursor::new(&data), &cascade, *chunk_size));
+        }
+        assert_eq!(
+            expected,
+            scan(BufReader::new(Trickle(&data)), &cascade, 128)
+        );
+    }
+
+    #[test]
+    fn test_csv_records_with_quoted_newlines() {
+        let format = CsvFormat::csv();
+        let mut data = Vec::new();
+        for i in 0..200 {
+            let text = if i % 3 == 0 {
+                "\"a\nmulti\r\nline en\""
+            } else {
+                "de"
+            };
+            data.extend_from_slice(format!("{},{}\r\n", i, text).as_bytes());
+        }
+        let cascade = Cascade::new(vec![RawFilter::substring("en")]);
+        let mut expected = Vec::new();
+        format.for_each_candidate(&data, &cascade, |offset, record| {
+            expected.push((offset as u64, record.to_vec()))
+        });
+        assert_eq!(67, expected.len());
+
+        for &chunk_size in [16, 100, 4096].iter() {
+            let chunks = ChunkReader::with_chunk_size(
+                BufReader::new(Trickle(&data)),
+                b'\n',
+                chunk_size,
+                AllocationMode::Default,
+            )
+            .unwrap()
+            .with_csv_records(format);
+            let mut scanner = StreamScanner::with_chunk_reader(chunks, cascade.clone());
+            let mut records = Vec::new();
+            scanner
+                .search(|offset, record| {
+                    records.push((offset, record.to_vec()));
+                    true
+                })
+                .unwrap();
+            assert_eq!(expected, records);
+            assert_eq!(200, scanner.stats().records);
+        }
+    }
+
+    #[test]
+    fn test_record_longer_than_chunk() {
+        let mut data = b"short en\n".to_vec();
+        data.extend_from_slice(&[b'x'; 1000]);
+        data.extend_from_slice(b" en\nlast en");
+        let cascade = Cascade::new(vec![RawFilter::substring("en")]);
+        let records = scan(Cursor::new(&data), &cascade, 64);
+        assert_eq!(expected(&data, &cascade), records);
+        assert_eq!(3, records.len());
+        assert_eq!(b"last en".to_vec(), records[2].1);
+
+        let mut scanner = StreamScanner::new(Cursor::new(&data), cascade, b'\n');
+        scanner.search(|_, _| false).unwrap();
+        assert_eq!(3, scanner.stats().records);
+        assert_eq!(data.len() as u64, scanner.stats().bytes_scanned);
+        assert_eq!(3, scanner.stats().verifier_rejects);
+    }
+
+    #[test]
+    fn test_chunks_hold_whole_records() {
+        let data = tweets(50);
+        let mut reader =
+            ChunkReader::with_chunk_size(Cursor::new(&data), b'\n', 256, AllocationMode::Default)
+                .unwrap();
+        let mut offset = 0;
+        while let Some(chunk) = reader.next_chunk().unwrap() {
+            assert_eq!(offset, chunk.offset);
+            assert_eq!(Some(&b'\n'), chunk.data.last());
+            assert!(chunk.data.len() <= 256);
+            offset += chunk.data.len() as u64;
+        }
+        assert_eq!(data.len() as u64, offset);
+    }
+
+    #[test]
+    fn test_utf8_validation() {
+        let mut data = "caf\u{e9} en\n".repeat(20).into_bytes();
+        let valid = data.len();
+        data.extend_from_slice(b"bad \xe9 en\nok en\n");
+        let reader = |data: &[u8]| {
+            ChunkReader::with_chunk_size(
+                Cursor::new(data.to_vec()),
+                b'\n',
+                64,
+                AllocationMode::Default,
+            )
+            .unwrap()
+            .with_utf8_validation()
+        };
+
+        let mut chunks = reader(&data[..valid]);
+        while chunks.next_chunk().unwrap().is_some() {}
+
+        let mut scanner = StreamScanner::with_chunk_reader(
+            reader(&data),
+            Cascade::new(vec![RawFilter::substring("en")]),
+        );
+        let mut seen = 0;
+        let e = scanner.search(|_, _| {
+            seen += 1;
+            true
+        });
+        let e = e.unwrap_err();
+        assert_eq!(
+            format!(
+                "invalid utf-8 input: invalid UTF-8 sequence (byte offset {})",
+                valid + 4
+            ),
+            e.to_string()
+        );
+        // the records before the chunk with the invalid byte were scanned
+        assert!(seen > 0 && seen < 20);
+        assert!(check_utf8(&data[..valid], 0).is_ok());
+    }
+}
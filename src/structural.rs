@@ -0,0 +1,350 @@
+//! A structural index of JSON records, after stage 1 of simdjson.
+//!
+//! Every 64-byte block of a record is classified into bitmaps of quotes, backslashes
+//! and structural characters with `sparser_kernels::json_bitmaps`. Quotes escaped by
+//! an odd run of backslashes are dropped, a prefix XOR of the remaining quotes masks
+//! the insides of strings, and what is left outside strings is indexed together with
+//! the quotes. Walking the index finds the bytes of a field's value without looking
+//! at the bytes in between, so that only the value has to be parsed.
+
+use std::convert::TryFrom;
+use std::ops::Range;
+
+use serde_json;
+
+use sparser_kernels::{self, JsonBitmaps};
+use utils::error::{Result, SparserError};
+
+/// Bits of the even positions of a block.
+const EVEN_BITS: u64 = 0x5555_5555_5555_5555;
+
+/// The offsets of the structural characters of a JSON record: braces, brackets,
+/// colons and commas outside strings, and the quotes opening and closing strings.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StructuralIndex {
+    positions: Vec<u32>,
+}
+
+impl StructuralIndex {
+    /// Indexes `record`, failing if it ends inside a string.
+    pub fn new(record: &[u8]) -> Result<Self> {
+        if record.len() > u32::MAX as usize {
+            return Err(SparserError::format("json", "record too long to index"));
+        }
+        let mut index = StructuralIndex {
+            positions: Vec::with_capacity(record.len() / 8),
+        };
+        let mut prev_escaped = 0;
+        let mut prev_in_string = 0;
+        let mut blocks = record.chunks_exact(64);
+        let mut base = 0;
+        for block in &mut blocks {
+            // the chunk is 64 bytes long
+            let block = <&[u8; 64]>::try_from(block).unwrap();
+            let bitmaps = sparser_kernels::json_bitmaps(block);
+            index.add(base, bitmaps, &mut prev_escaped, &mut prev_in_string);
+            base += 64;
+        }
+        let tail = blocks.remainder();
+        if !tail.is_empty() {
+            let mut block = [0u8; 64];
+            block[..tail.len()].copy_from_slice(tail);
+            let bitmaps = sparser_kernels::json_bitmaps(&block);
+            index.add(base, bitmaps, &mut prev_escaped, &mut prev_in_string);
+        }
+        if prev_in_string != 0 {
+            let start = index.positions.last().map_or(0, |&p| p as u64);
+            return Err(SparserError::format("json", "unterminated string").at_offset(start));
+        }
+        Ok(index)
+    }
+
+    /// Adds the structural characters of the block at `base`. `prev_escaped` is 1 if
+    /// the previous block ended in an odd run of backslashes, `prev_in_string` all
+    /// ones if it ended inside a string; both are updated for the next block.
+    fn add(
+        &mut self,
+        base: u32,
+        bitmaps: JsonBitmaps,
+        prev_escaped: &mut u64,
+        prev_in_string: &mut u64,
+    ) {
+        let quotes = bitmaps.quotes & !escaped(bitmaps.backslashes, prev_escaped);
+        let in_string = prefix_xor(quotes) ^ *prev_in_string;
+        *prev_in_string = ((in_string as i64) >> 63) as u64;
+        let mut structurals = (bitmaps.structurals & !in_string) | quotes;
+        while structurals != 0 {
+            self.positions.push(base + structurals.trailing_zeros());
+            structurals &= structurals - 1;
+        }
+    }
+
+    /// Returns the offsets of the structural characters, in order.
+    pub fn positions(&self) -> &[u32] {
+        &self.positions
+    }
+
+    /// Returns the byte range of the value at the dot-separated `path` of nested
+    /// objects in `record`, the record the index was built from, or `None` if there
+    /// is none. Of repeated keys the last counts, as when parsing the record.
+    ///
+    /// Only the structure on the way to the value is checked, failing where it is
+    /// not that of JSON or the record is not an object.
+    pub fn field(&self, record: &[u8], path: &str) -> Result<Option<Range<usize>>> {
+        let start = skip_whitespace(record, 0);
+        if self.positions.first().map(|&p| p as usize) != Some(start) || record[start] != b'{' {
+            return Err(malformed(start));
+        }
+        let mut object = 0;
+        let mut keys = path.split('.').peekable();
+        while let Some(key) = keys.next() {
+            let value = match self.member(record, object, key)? {
+                Some(value) => value,
+                None => return Ok(None),
+            };
+            let (range, _) = self.value(record, value)?;
+            if keys.peek().is_none() {
+                return Ok(Some(range));
+            }
+            if record[range.start] != b'{' {
+                return Ok(None);
+            }
+            object = value;
+        }
+        Ok(None)
+    }
+
+    /// Returns the offset of the `i`th structural character.
+    fn position(&self, i: usize, record: &[u8]) -> Result<usize> {
+        self.positions
+            .get(i)
+            .map(|&p| p as usize)
+            .ok_or_else(|| malformed(record.len()))
+    }
+
+    /// Returns the index of the structural character starting or following the
+    /// value of the last `key` in the object starting at the `object`th one.
+    fn member(&self, record: &[u8], object: usize, key: &str) -> Result<Option<usize>> {
+        let mut i = object + 1;
+        if record[self.position(i, record)?] == b'}' {
+            return Ok(None);
+        }
+        let mut found = None;
+        loop {
+            let open = self.position(i, record)?;
+            let close = self.position(i + 1, record)?;
+            let colon = self.position(i + 2, record)?;
+            if record[open] != b'"' || record[colon] != b':' {
+                return Err(malformed(open));
+            }
+            if key_equals(&record[open..close + 1], key) {
+                found = Some(i + 3);
+            }
+            let (_, next) = self.value(record, i + 3)?;
+            let separator = self.position(next, record)?;
+            match record[separator] {
+                b',' => i = next + 1,
+                b'}' => return Ok(found),
+                _ => return Err(malformed(separator)),
+            }
+        }
+    }
+
+    /// Returns the byte range of the value after the colon that is the structural
+    /// character before the `i`th, and the index of the structural character after
+    /// the value.
+    fn value(&self, record: &[u8], i: usize) -> Result<(Range<usize>, usize)> {
+        let start = skip_whitespace(record, self.position(i - 1, record)? + 1);
+        let p = self.position(i, record)?;
+        if p != start {
+            // a number, boolean or null runs up to the next structural character
+            let end = start
+                + record[start..p]
+                    .iter()
+                    .rposition(|b| !b" \t\r\n".contains(b))
+                    .map_or(0, |last| last + 1);
+            return if end > start {
+                Ok((start..end, i))
+            } else {
+                Err(malformed(start))
+            };
+        }
+        match record[p] {
+            b'"' => Ok((p..self.position(i + 1, record)? + 1, i + 2)),
+            b'{' | b'[' => {
+                let mut depth = 0;
+                for j in i..self.positions.len() {
+                    let q = self.positions[j] as usize;
+                    match record[q] {
+                        b'{' | b'[' => depth += 1,
+                        b'}' | b']' => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return Ok((p..q + 1, j + 1));
+                            }
+                        }
+                        _ => {}
+                    }
+                }
+                Err(malformed(record.len()))
+            }
+            _ => Err(malformed(p)),
+        }
+    }
+}
+
+/// Returns the positions of the bytes escaped by an odd run of backslashes, the
+/// run carried over from the previous block included. Runs starting at even and at
+/// odd positions are added to themselves separately, so that the carry out of each
+/// run lands right after it exactly when the run's length is odd.
+fn escaped(backslashes: u64, prev_escaped: &mut u64) -> u64 {
+    let odd_bits = !EVEN_BITS;
+    let starts = backslashes & !(backslashes << 1);
+    let even_start_mask = EVEN_BITS ^ *prev_escaped;
+    let even_starts = starts & even_start_mask;
+    let odd_starts = starts & !even_start_mask;
+    let even_carries = backslashes.wrapping_add(even_starts);
+    let (mut odd_carries, overflow) = backslashes.overflowing_add(odd_starts);
+    odd_carries |= *prev_escaped;
+    *prev_escaped = overflow as u64;
+    let even_carry_ends = even_carries & !backslashes;
+    let odd_carry_ends = odd_carries & !backslashes;
+    (even_carry_ends & odd_bits) | (odd_carry_ends & EVEN_BITS)
+}
+
+/// Returns the mask whose bit `i` is the XOR of bits `0..=i` of `x`, so that bits
+/// from an opening quote up to the closing one are set.
+fn prefix_xor(mut x: u64) -> u64 {
+    x ^= x << 1;
+    x ^= x << 2;
+    x ^= x << 4;
+    x ^= x << 8;
+    x ^= x << 16;
+    x ^= x << 32;
+    x
+}
+
+/// Returns whether the quoted string `quoted` decodes to `key`.
+fn key_equals(quoted: &[u8], key: &str) -> bool {
+    let raw = &quoted[1..quoted.len() - 1];
+    if sparser_kernels::find_byte(raw, b'\\').is_none() {
+        raw == key.as_bytes()
+    } else {
+        serde_json::from_slice::<String>(quoted).is_ok_and(|decoded| decoded == key)
+    }
+}
+
+fn skip_whitespace(record: &[u8], from: usize) -> usize {
+    from + record[from.min(record.len())..]
+        .iter()
+        .take_while(|b| b" \t\r\n".contains(b))
+        .count()
+}
+
+fn malformed(at: usize) -> SparserError {
+    SparserError::format("json", "unexpected structure").at_offset(at as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use rand::{thread_rng, Rng};
+
+    use super::*;
+
+    /// Indexes `record` byte by byte.
+    fn naive(record: &[u8]) -> Vec<u32> {
+        let mut positions = Vec::new();
+        let (mut in_string, mut escaped) = (false, false);
+        for (i, &b) in record.iter().enumerate() {
+            if in_string {
+                if escaped {
+                    escaped = false;
+                } else if b == b'\\' {
+                    escaped = true;
+                } else if b == b'"' {
+                    in_string = false;
+                    positions.push(i as u32);
+                }
+            } else if b == b'"' {
+                in_string = true;
+                positions.push(i as u32);
+            } else if b"{}[]:,".contains(&b) {
+                positions.push(i as u32);
+            }
+        }
+        positions
+    }
+
+    #[test]
+    fn test_index() {
+        let record = br#"{"a\"": [1, "}"], "b\\": {"c": "\\\""}}"#;
+        let index = StructuralIndex::new(record).unwrap();
+        assert_eq!(
+            vec![0, 1, 5, 6, 8, 10, 12, 14, 15, 16, 18, 22, 23, 25, 26, 28, 29, 31, 36, 37, 38],
+            index.positions()
+        );
+        assert_eq!(naive(record), index.positions());
+        assert!(StructuralIndex::new(br#"{"a": "b\"}"#).is_err());
+    }
+
+    #[test]
+    fn test_matches_naive_index() {
+        let mut rng = thread_rng();
+        let pieces: [&[u8]; 8] = [b"{", b"}", b"[", b"]", b":", b",", b"ab", b" "];
+        let inside: [&[u8]; 6] = [b"x", b"\\\\", b"\\\"", b"{:", b"\\\\\\\"", b"\\n"];
+        for _ in 0..500 {
+            let mut record = Vec::new();
+            for _ in 0..rng.gen_range(0, 80) {
+                if rng.gen_range(0, 4) == 0 {
+                    record.push(b'"');
+                    for _ in 0..rng.gen_range(0, 40) {
+                        record.extend_from_slice(rng.choose(&inside).unwrap());
+                    }
+                    record.push(b'"');
+                } else {
+                    record.extend_from_slice(rng.choose(&pieces).unwrap());
+                }
+            }
+            let index = StructuralIndex::new(&record).unwrap();
+            assert_eq!(naive(&record), index.positions(), "{:?}", record);
+        }
+    }
+
+    #[test]
+    fn test_field() {
+        let record =
+            br#" {"id": 7 , "user": {"name": "a\"b", "lang":"en"}, "tags": ["x", {"y": 1}],
+            "name": null, "user": {"lang": "de", "ids": [1, [2]]}, "e": {}} "#;
+        let index = StructuralIndex::new(record).unwrap();
+        let field = |path: &str| {
+            index
+                .field(record, path)
+                .unwrap()
+                .map(|range| String::from_utf8_lossy(&record[range]).into_owned())
+        };
+        assert_eq!(Some("7".to_string()), field("id"));
+        assert_eq!(Some("[\"x\", {\"y\": 1}]".to_string()), field("tags"));
+        assert_eq!(Some("null".to_string()), field("name"));
+        // the last of repeated keys counts
+        assert_eq!(Some("\"de\"".to_string()), field("user.lang"));
+        assert_eq!(Some("[1, [2]]".to_string()), field("user.ids"));
+        assert_eq!(None, field("user.name"));
+        assert_eq!(Some("{}".to_string()), field("e"));
+        assert_eq!(None, field("e.x"));
+        assert_eq!(None, field("id.x"));
+        assert_eq!(None, field("tags.y"));
+        assert_eq!(None, field("missing"));
+
+        for malformed in [
+            &br#"[{"a": 1}]"#[..],
+            br#"{"a" 1}"#,
+            br#"{"a": }"#,
+            br#"{"a": [1}"#,
+        ]
+        .iter()
+        {
+            let index = StructuralIndex::new(malformed).unwrap();
+            assert!(index.field(malformed, "a").is_err(), "{:?}", malformed);
+        }
+    }
+}
@@ -1,7 +1,10 @@
 use std::mem;
 use std::sync::Arc;
 
+use utils::bit_util;
+use utils::error::Result;
 use utils::memory;
+use utils::memory::AllocationMode;
 
 /// Buffer is a contiguous memory region of fixed size and is aligned at a 64-byte
 /// boundary. Buffer is immutable.
@@ -28,7 +31,7 @@ impl PartialEq for BufferData {
         if self.len != other.len {
             return false;
         }
-        unsafe { memory::memcmp(self.ptr, other.ptr, self.len as usize) == 0 }
+        unsafe { memory::memcmp(self.ptr, other.ptr, self.len) == 0 }
     }
 }
 
@@ -54,7 +57,7 @@ impl Buffer {
 
     /// Returns the number of bytes in the buffer
     pub fn len(&self) -> usize {
-        self.data.len - self.offset as usize
+        self.data.len - self.offset
     }
 
     /// Returns whether the buffer is empty.
@@ -64,6 +67,9 @@ impl Buffer {
 
     /// Returns the byte slice stored in this buffer
     pub fn data(&self) -> &[u8] {
+        if self.data.ptr.is_null() {
+            return &[];
+        }
         unsafe { ::std::slice::from_raw_parts(self.raw_data(), self.len()) }
     }
 
@@ -87,6 +93,16 @@ impl Buffer {
         unsafe { self.data.ptr.add(self.offset) }
     }
 
+    /// Creates a buffer by copying `slice` into memory obtained with `mode`.
+    pub fn copy_from_slice(slice: &[u8], mode: AllocationMode) -> Result<Self> {
+        let len = mem::size_of_val(slice);
+        let buffer = memory::allocate_aligned_with(len as i64, mode)?;
+        unsafe {
+            memory::memcpy(buffer, slice.as_ptr(), len);
+        }
+        Ok(Buffer::from_raw_parts(buffer, len))
+    }
+
     /// Returns an empty buffer.
     pub fn empty() -> Self {
         Self::from_raw_parts(::std::ptr::null(), 0)
@@ -106,26 +122,140 @@ impl Clone for Buffer {
 /// allocated memory region.
 impl<T: AsRef<[u8]>> From<T> for Buffer {
     fn from(p: T) -> Self {
-        // allocate aligned memory buffer
-        let slice = p.as_ref();
-        let len = slice.len() * mem::size_of::<u8>();
-        let buffer = memory::allocate_aligned((len) as i64).unwrap();
+        Buffer::copy_from_slice(p.as_ref(), AllocationMode::Default).unwrap()
+    }
+}
+
+unsafe impl Sync for BufferData {}
+unsafe impl Send for BufferData {}
+
+/// MutableBuffer is a growable, 64-byte aligned memory region. It is meant to be
+/// filled and reused many times, e.g. as the chunk buffer of a stream reader, and
+/// can be turned into an immutable `Buffer` with `freeze`.
+#[derive(Debug)]
+pub struct MutableBuffer {
+    data: *mut u8,
+    len: usize,
+    capacity: usize,
+    mode: AllocationMode,
+}
+
+impl MutableBuffer {
+    /// Creates an empty buffer with room for at least `capacity` bytes.
+    pub fn new(capacity: usize) -> Self {
+        Self::with_mode(capacity, AllocationMode::Default).unwrap()
+    }
+
+    /// Creates an empty buffer with room for at least `capacity` bytes, allocated
+    /// with `mode`. The memory is zero-initialized.
+    pub fn with_mode(capacity: usize, mode: AllocationMode) -> Result<Self> {
+        let capacity = bit_util::round_upto_multiple_of_64(capacity.max(1) as i64) as usize;
+        let data = memory::allocate_aligned_with(capacity as i64, mode)?;
+        unsafe { ::std::ptr::write_bytes(data, 0, capacity) };
+        Ok(MutableBuffer {
+            data,
+            len: 0,
+            capacity,
+            mode,
+        })
+    }
+
+    /// Returns the number of initialized bytes.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the buffer holds no bytes.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bytes the buffer can hold without reallocating.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Returns the first `len()` bytes.
+    pub fn data(&self) -> &[u8] {
+        unsafe { ::std::slice::from_raw_parts(self.data, self.len) }
+    }
+
+    /// Returns the first `len()` bytes for writing.
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        unsafe { ::std::slice::from_raw_parts_mut(self.data, self.len) }
+    }
+
+    /// Returns the bytes between `len()` and `capacity()`, e.g. to read into.
+    pub fn spare_capacity_mut(&mut self) -> &mut [u8] {
         unsafe {
-            memory::memcpy(buffer, slice.as_ptr(), len);
+            ::std::slice::from_raw_parts_mut(self.data.add(self.len), self.capacity - self.len)
+        }
+    }
+
+    /// Sets the number of initialized bytes.
+    pub fn set_len(&mut self, len: usize) {
+        assert!(len <= self.capacity, "length cannot exceed the capacity");
+        self.len = len;
+    }
+
+    /// Appends `bytes`, growing the buffer if needed.
+    pub fn extend_from_slice(&mut self, bytes: &[u8]) -> Result<()> {
+        let len = self.len + bytes.len();
+        self.reserve(len)?;
+        self.spare_capacity_mut()[..bytes.len()].copy_from_slice(bytes);
+        self.len = len;
+        Ok(())
+    }
+
+    /// Ensures the buffer can hold at least `capacity` bytes, preserving its contents.
+    pub fn reserve(&mut self, capacity: usize) -> Result<()> {
+        if capacity <= self.capacity {
+            return Ok(());
+        }
+        let capacity = capacity.max(self.capacity * 2);
+        let mut grown = MutableBuffer::with_mode(capacity, self.mode)?;
+        grown.extend_from_slice(self.data())?;
+        ::std::mem::swap(self, &mut grown);
+        Ok(())
+    }
+
+    /// Removes the first `n` bytes, moving the remaining ones to the front.
+    pub fn consume(&mut self, n: usize) {
+        assert!(
+            n <= self.len,
+            "cannot consume more bytes than the buffer holds"
+        );
+        let len = self.len;
+        self.data_mut().copy_within(n..len, 0);
+        self.len -= n;
+    }
+
+    /// Converts this buffer into an immutable `Buffer` without copying.
+    pub fn freeze(self) -> Buffer {
+        let buffer = Buffer::from_raw_parts(self.data, self.len);
+        mem::forget(self);
+        buffer
+    }
+}
+
+impl Drop for MutableBuffer {
+    fn drop(&mut self) {
+        unsafe {
+            memory::free_aligned(self.data);
         }
-        Buffer::from_raw_parts(buffer, len)
     }
 }
 
-unsafe impl Sync for Buffer {}
-unsafe impl Send for Buffer {}
+unsafe impl Sync for MutableBuffer {}
+unsafe impl Send for MutableBuffer {}
 
 #[cfg(test)]
 mod tests {
     use std::ptr::null_mut;
     use std::thread;
 
-    use super::Buffer;
+    use super::{Buffer, MutableBuffer};
+    use utils::memory::{is_aligned, AllocationMode, HUGE_PAGE_SIZE};
 
     #[test]
     fn test_buffer_data_equality() {
@@ -169,6 +299,16 @@ mod tests {
         assert_eq!(&[0, 1, 2, 3, 4], buf.data());
     }
 
+    #[test]
+    fn test_copy_from_slice_huge_page() {
+        let data = vec![7u8; HUGE_PAGE_SIZE + 10];
+        let mode = AllocationMode::HugePage { numa_local: true };
+        let buf = Buffer::copy_from_slice(&data, mode).unwrap();
+        assert_eq!(HUGE_PAGE_SIZE + 10, buf.len());
+        assert!(is_aligned(buf.raw_data(), HUGE_PAGE_SIZE));
+        assert_eq!(&data[..], buf.data());
+    }
+
     #[test]
     fn test_copy() {
         let buf = Buffer::from(&[0, 1, 2, 3, 4]);
@@ -221,4 +361,28 @@ mod tests {
         assert!(buffer_copy.is_ok());
         assert_eq!(buffer2, buffer_copy.ok().unwrap());
     }
+
+    #[test]
+    fn test_mutable_buffer() {
+        let mut buf = MutableBuffer::new(10);
+        assert_eq!(64, buf.capacity());
+        assert!(buf.is_empty());
+        assert!(is_aligned(buf.data().as_ptr(), 64));
+
+        buf.extend_from_slice(b"hello ").unwrap();
+        buf.extend_from_slice(&[b'x'; 100]).unwrap();
+        assert_eq!(106, buf.len());
+        assert!(buf.capacity() >= 106);
+        assert_eq!(b"hello x", &buf.data()[..7]);
+
+        buf.consume(6);
+        assert_eq!(100, buf.len());
+        assert_eq!(&[b'x'; 100][..], buf.data());
+
+        buf.spare_capacity_mut()[0] = b'!';
+        buf.set_len(101);
+        let frozen = buf.freeze();
+        assert_eq!(101, frozen.len());
+        assert_eq!(b'!', frozen.data()[100]);
+    }
 }
@@ -1,7 +1,279 @@
-#[derive(Debug, Clone, PartialEq)]
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::num::{ParseFloatError, ParseIntError};
+use std::str::Utf8Error;
+use std::string::FromUtf8Error;
+use std::sync::Arc;
+
+#[derive(Debug, Clone)]
 pub enum SparserError {
     MemoryError(String),
     ParseError(String),
+    /// An I/O operation on the input failed. Shared so that the error stays `Clone`.
+    IoError(Arc<io::Error>),
+    /// The input could not be decoded as `format`. `offset` is the byte offset into
+    /// the input and `record` the record number, when known.
+    FormatError {
+        format: &'static str,
+        message: String,
+        offset: Option<u64>,
+        record: Option<u64>,
+    },
+    /// The query cannot be evaluated, e.g. it references an unknown column.
+    InvalidQuery(String),
+    /// Calibration or plan optimization failed.
+    OptimizerError(String),
+    /// Another error annotated with what was being done when it occurred.
+    Context {
+        context: String,
+        source: Box<SparserError>,
+    },
+}
+
+impl SparserError {
+    /// Creates a `FormatError` without position information.
+    pub fn format<M: Into<String>>(format: &'static str, message: M) -> Self {
+        SparserError::FormatError {
+            format,
+            message: message.into(),
+            offset: None,
+            record: None,
+        }
+    }
+
+    /// Sets the byte offset of a `FormatError`. Other variants are returned unchanged.
+    pub fn at_offset(mut self, at: u64) -> Self {
+        if let SparserError::FormatError { ref mut offset, .. } = self {
+            *offset = Some(at);
+        }
+        self
+    }
+
+    /// Sets the record number of a `FormatError`. Other variants are returned unchanged.
+    pub fn at_record(mut self, at: u64) -> Self {
+        if let SparserError::FormatError { ref mut record, .. } = self {
+            *record = Some(at);
+        }
+        self
+    }
+
+    /// Wraps this error with a description of the failed operation.
+    pub fn context<C: Into<String>>(self, context: C) -> Self {
+        SparserError::Context {
+            context: context.into(),
+            source: Box::new(self),
+        }
+    }
+
+    /// Returns this error followed by each of its causes, separated by `: `. `Display`
+    /// only prints the outermost level, as the causes are available from `source()`.
+    pub fn report(&self) -> String {
+        let mut report = self.to_string();
+        let mut cause = self.source();
+        while let Some(e) = cause {
+            report.push_str(": ");
+            report.push_str(&e.to_string());
+            cause = e.source();
+        }
+        report
+    }
+
+    /// Returns the innermost error, skipping any `Context` wrappers.
+    pub fn root(&self) -> &SparserError {
+        match *self {
+            SparserError::Context { ref source, .. } => source.root(),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for SparserError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            SparserError::MemoryError(ref msg) => write!(f, "memory error: {}", msg),
+            SparserError::ParseError(ref msg) => write!(f, "parse error: {}", msg),
+            SparserError::IoError(_) => write!(f, "i/o error"),
+            SparserError::FormatError {
+                format,
+                ref message,
+                offset,
+                record,
+            } => {
+                write!(f, "invalid {} input: {}", format, message)?;
+                if let Some(record) = record {
+                    write!(f, " (record {})", record)?;
+                }
+                if let Some(offset) = offset {
+                    write!(f, " (byte offset {})", offset)?;
+                }
+                Ok(())
+            }
+            SparserError::InvalidQuery(ref msg) => write!(f, "invalid query: {}", msg),
+            SparserError::OptimizerError(ref msg) => write!(f, "optimizer error: {}", msg),
+            SparserError::Context { ref context, .. } => write!(f, "{}", context),
+        }
+    }
+}
+
+/// I/O errors are equal when their kind and message are.
+impl PartialEq for SparserError {
+    fn eq(&self, other: &Self) -> bool {
+        use self::SparserError::*;
+        match (self, other) {
+            (MemoryError(a), MemoryError(b)) => a == b,
+            (ParseError(a), ParseError(b)) => a == b,
+            (IoError(a), IoError(b)) => a.kind() == b.kind() && a.to_string() == b.to_string(),
+            (
+                FormatError {
+                    format,
+                    message,
+                    offset,
+                    record,
+                },
+                FormatError {
+                    format: other_format,
+                    message: other_message,
+                    offset: other_offset,
+                    record: other_record,
+                },
+            ) => {
+                format == other_format
+                    && message == other_message
+                    && offset == other_offset
+                    && record == other_record
+            }
+            (InvalidQuery(a), InvalidQuery(b)) => a == b,
+            (OptimizerError(a), OptimizerError(b)) => a == b,
+            (
+                Context { context, source },
+                Context {
+                    context: other_context,
+                    source: other_source,
+                },
+            ) => context == other_context && source == other_source,
+            _ => false,
+        }
+    }
+}
+
+impl Error for SparserError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match *self {
+            SparserError::IoError(ref e) => Some(e.as_ref()),
+            SparserError::Context { ref source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for SparserError {
+    fn from(e: io::Error) -> Self {
+        SparserError::IoError(Arc::new(e))
+    }
+}
+
+impl From<Utf8Error> for SparserError {
+    fn from(e: Utf8Error) -> Self {
+        SparserError::format("utf-8", e.to_string()).at_offset(e.valid_up_to() as u64)
+    }
+}
+
+impl From<FromUtf8Error> for SparserError {
+    fn from(e: FromUtf8Error) -> Self {
+        SparserError::from(e.utf8_error())
+    }
+}
+
+impl From<ParseIntError> for SparserError {
+    fn from(e: ParseIntError) -> Self {
+        SparserError::ParseError(e.to_string())
+    }
+}
+
+impl From<ParseFloatError> for SparserError {
+    fn from(e: ParseFloatError) -> Self {
+        SparserError::ParseError(e.to_string())
+    }
 }
 
 pub type Result<T> = ::std::result::Result<T, SparserError>;
+
+/// Adds `context` to the error of any result convertible into a `SparserError`.
+pub trait ResultExt<T> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T>;
+}
+
+impl<T, E: Into<SparserError>> ResultExt<T> for ::std::result::Result<T, E> {
+    fn context<C: Into<String>>(self, context: C) -> Result<T> {
+        self.map_err(|e| e.into().context(context))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_missing() -> Result<()> {
+        let e = io::Error::new(io::ErrorKind::NotFound, "no such file");
+        Err(e).context("opening tweets.json")
+    }
+
+    #[test]
+    fn test_format_error_display() {
+        let e = SparserError::format("avro", "bad sync marker")
+            .at_record(12)
+            .at_offset(4096);
+        assert_eq!(
+            "invalid avro input: bad sync marker (record 12) (byte offset 4096)",
+            e.to_string()
+        );
+    }
+
+    #[test]
+    fn test_io_error_source() {
+        let e = read_missing().unwrap_err();
+        assert_eq!("opening tweets.json", e.to_string());
+        assert_eq!("opening tweets.json: i/o error: no such file", e.report());
+        let source = e.source().unwrap();
+        assert!(source.source().unwrap().is::<io::Error>());
+        match *e.root() {
+            SparserError::IoError(ref io) => assert_eq!(io::ErrorKind::NotFound, io.kind()),
+            ref other => panic!("unexpected {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_clone_and_eq() {
+        let e = read_missing().unwrap_err();
+        assert_eq!(e, e.clone());
+        assert_ne!(e, read_missing().unwrap_err().context("loading"));
+        let other = io::Error::new(io::ErrorKind::NotFound, "gone");
+        assert_ne!(
+            e,
+            Err::<(), _>(other)
+                .context("opening tweets.json")
+                .unwrap_err()
+        );
+        let format = SparserError::format("csv", "unterminated quote").at_offset(3);
+        assert_eq!(format.clone(), format);
+        assert_ne!(format, SparserError::format("csv", "unterminated quote"));
+    }
+
+    #[test]
+    fn test_from_conversions() {
+        fn parse(s: &str) -> Result<i64> {
+            Ok(s.parse::<i64>()?)
+        }
+        match parse("12x") {
+            Err(SparserError::ParseError(_)) => {}
+            other => panic!("unexpected {:?}", other),
+        }
+
+        let e = SparserError::from(String::from_utf8(vec![b'a', 0xff]).unwrap_err());
+        match e {
+            SparserError::FormatError { offset, .. } => assert_eq!(Some(1), offset),
+            other => panic!("unexpected {:?}", other),
+        }
+    }
+}
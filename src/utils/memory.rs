@@ -1,4 +1,5 @@
 use std::mem;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use libc;
 
@@ -7,6 +8,34 @@ use std::mem::MaybeUninit;
 
 const ALIGNMENT: usize = 64;
 
+/// Size (and alignment) of a transparent huge page on x86-64 Linux.
+pub const HUGE_PAGE_SIZE: usize = 2 * 1024 * 1024;
+
+/// How memory for a buffer is obtained.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum AllocationMode {
+    /// 64-byte aligned memory from the system allocator.
+    #[default]
+    Default,
+    /// `HUGE_PAGE_SIZE` aligned memory, rounded up to whole huge pages and advised
+    /// with `MADV_HUGEPAGE` so the kernel backs it with transparent huge pages. When
+    /// `numa_local` is set the pages are also bound to the NUMA node of the calling
+    /// CPU. Requests smaller than a huge page fall back to `Default`.
+    HugePage { numa_local: bool },
+}
+
+/// Which of the advice given for a huge page allocation the kernel accepted.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Advice {
+    /// `madvise(MADV_HUGEPAGE)` succeeded.
+    pub huge_pages: bool,
+    /// The memory was bound to the NUMA node of the calling CPU.
+    pub numa_local: bool,
+}
+
+/// Number of huge page allocations whose advice the kernel refused in part.
+static REFUSED_ADVICE: AtomicU64 = AtomicU64::new(0);
+
 #[cfg(windows)]
 #[link(name = "msvcrt")]
 extern "C" {
@@ -14,9 +43,54 @@ extern "C" {
     fn _aligned_free(prt: *const u8);
 }
 
-#[cfg(windows)]
 pub fn allocate_aligned(size: i64) -> Result<*mut u8> {
-    let page = unsafe { _aligned_malloc(size as libc::size_t, ALIGNMENT as libc::size_t) };
+    allocate_with_alignment(size as usize, ALIGNMENT)
+}
+
+/// Allocates `size` bytes using `mode`. The result is always at least 64-byte
+/// aligned and is released with `free_aligned`.
+///
+/// Huge page advice and NUMA binding are best effort: if the kernel refuses them
+/// (THP disabled, no NUMA support, restricted syscalls) the memory is still returned,
+/// and the refusal is counted in `refused_advice`.
+pub fn allocate_aligned_with(size: i64, mode: AllocationMode) -> Result<*mut u8> {
+    allocate_advised(size, mode).map(|(ptr, _)| ptr)
+}
+
+/// Like `allocate_aligned_with`, and also returns the advice the kernel accepted.
+/// Nothing is advised for `Default` allocations and requests that fall back to it.
+pub fn allocate_advised(size: i64, mode: AllocationMode) -> Result<(*mut u8, Advice)> {
+    match mode {
+        AllocationMode::HugePage { numa_local } if size as usize >= HUGE_PAGE_SIZE => {
+            let len = huge_page_len(size as usize);
+            let ptr = allocate_with_alignment(len, HUGE_PAGE_SIZE)?;
+            let advice = Advice {
+                huge_pages: advise_huge_pages(ptr, len),
+                numa_local: numa_local && bind_to_local_node(ptr, len),
+            };
+            if !advice.huge_pages || advice.numa_local != numa_local {
+                REFUSED_ADVICE.fetch_add(1, Ordering::Relaxed);
+            }
+            Ok((ptr, advice))
+        }
+        _ => allocate_aligned(size).map(|ptr| (ptr, Advice::default())),
+    }
+}
+
+/// Returns the number of huge page allocations in this process so far for which
+/// the kernel refused the huge page advice or the NUMA binding.
+pub fn refused_advice() -> u64 {
+    REFUSED_ADVICE.load(Ordering::Relaxed)
+}
+
+/// Returns the number of bytes actually reserved for a huge page allocation of `size`.
+pub fn huge_page_len(size: usize) -> usize {
+    size.div_ceil(HUGE_PAGE_SIZE) * HUGE_PAGE_SIZE
+}
+
+#[cfg(windows)]
+fn allocate_with_alignment(size: usize, alignment: usize) -> Result<*mut u8> {
+    let page = unsafe { _aligned_malloc(size as libc::size_t, alignment as libc::size_t) };
     match page {
         0 => Err(SparserError::MemoryError(
             "Failed to allocate memory".to_string(),
@@ -26,14 +100,12 @@ pub fn allocate_aligned(size: i64) -> Result<*mut u8> {
 }
 
 #[cfg(not(windows))]
-pub fn allocate_aligned(size: i64) -> Result<*mut u8> {
+fn allocate_with_alignment(size: usize, alignment: usize) -> Result<*mut u8> {
     unsafe {
         let mut page: MaybeUninit<*mut libc::c_void> = MaybeUninit::uninit();
-        let result = libc::posix_memalign(page.as_mut_ptr(), ALIGNMENT, size as usize);
+        let result = libc::posix_memalign(page.as_mut_ptr(), alignment, size);
         match result {
-            0 => Ok(mem::transmute::<*mut libc::c_void, *mut u8>(
-                *page.as_mut_ptr(),
-            )),
+            0 => Ok(*page.as_mut_ptr() as *mut u8),
             _ => Err(SparserError::MemoryError(
                 "Failed to allocate memory".to_string(),
             )),
@@ -41,6 +113,70 @@ pub fn allocate_aligned(size: i64) -> Result<*mut u8> {
     }
 }
 
+/// Returns whether the kernel accepted the advice.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn advise_huge_pages(ptr: *mut u8, len: usize) -> bool {
+    unsafe { libc::madvise(ptr as *mut libc::c_void, len, libc::MADV_HUGEPAGE) == 0 }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn advise_huge_pages(_ptr: *mut u8, _len: usize) -> bool {
+    false
+}
+
+/// Returns the NUMA node of the CPU the calling thread is running on, if the
+/// platform reports one.
+#[cfg(target_os = "linux")]
+pub fn current_numa_node() -> Option<u32> {
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_getcpu,
+            &mut cpu as *mut libc::c_uint,
+            &mut node as *mut libc::c_uint,
+            ::std::ptr::null_mut::<libc::c_void>(),
+        )
+    };
+    match result {
+        0 => Some(node as u32),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn current_numa_node() -> Option<u32> {
+    None
+}
+
+/// Returns whether the memory was bound.
+#[cfg(target_os = "linux")]
+fn bind_to_local_node(ptr: *mut u8, len: usize) -> bool {
+    const MPOL_PREFERRED: libc::c_int = 1;
+    let node = match current_numa_node() {
+        Some(node) if node < 64 => node,
+        _ => return false,
+    };
+    let nodemask: libc::c_ulong = 1 << node;
+    let result = unsafe {
+        libc::syscall(
+            libc::SYS_mbind,
+            ptr as *mut libc::c_void,
+            len,
+            MPOL_PREFERRED,
+            &nodemask as *const libc::c_ulong,
+            64 as libc::c_ulong,
+            0 as libc::c_uint,
+        )
+    };
+    result == 0
+}
+
+#[cfg(not(target_os = "linux"))]
+fn bind_to_local_node(_ptr: *mut u8, _len: usize) -> bool {
+    false
+}
+
 #[cfg(windows)]
 pub fn free_aligned(p: *const u8) {
     unsafe {
@@ -48,11 +184,21 @@ pub fn free_aligned(p: *const u8) {
     }
 }
 
+/// Frees memory returned by `allocate_aligned`.
+///
+/// # Safety
+///
+/// `p` must come from `allocate_aligned` (or be null) and must not be freed twice.
 #[cfg(not(windows))]
 pub unsafe fn free_aligned(p: *const u8) {
     libc::free(mem::transmute::<*const u8, *mut libc::c_void>(p));
 }
 
+/// Copies `len` bytes from `src` to `dst`.
+///
+/// # Safety
+///
+/// Both pointers must be valid for `len` bytes and the regions must not overlap.
 pub unsafe fn memcpy(dst: *mut u8, src: *const u8, len: usize) {
     let src = mem::transmute::<*const u8, *const libc::c_void>(src);
     let dst = mem::transmute::<*mut u8, *mut libc::c_void>(dst);
@@ -60,7 +206,6 @@ pub unsafe fn memcpy(dst: *mut u8, src: *const u8, len: usize) {
 }
 
 extern "C" {
-    #[inline]
     pub fn memcmp(p1: *const u8, p2: *const u8, len: usize) -> i32;
 }
 
@@ -70,3 +215,114 @@ pub fn is_aligned<T>(p: *const T, a: usize) -> bool {
     let pmoda = p as usize & a_minus_one;
     pmoda == 0
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allocate_default_mode() {
+        let p = allocate_aligned_with(100, AllocationMode::Default).unwrap();
+        assert!(is_aligned(p, ALIGNMENT));
+        unsafe { free_aligned(p) };
+    }
+
+    #[test]
+    fn test_allocate_huge_page_mode() {
+        let size = HUGE_PAGE_SIZE as i64 + 1;
+        for numa_local in [false, true].iter() {
+            let mode = AllocationMode::HugePage {
+                numa_local: *numa_local,
+            };
+            let p = allocate_aligned_with(size, mode).unwrap();
+            assert!(is_aligned(p, HUGE_PAGE_SIZE));
+            unsafe {
+                // the whole rounded-up region must be usable
+                *p.add(huge_page_len(size as usize) - 1) = 1;
+                free_aligned(p);
+            }
+        }
+    }
+
+    /// Returns the lines of `/proc/self/smaps` describing the mapping that
+    /// contains `p`, header first.
+    #[cfg(target_os = "linux")]
+    fn smaps_of(smaps: &str, p: *const u8) -> Vec<String> {
+        let p = p as usize;
+        let mut lines = Vec::new();
+        let mut inside = false;
+        for line in smaps.lines() {
+            let range = line.split_whitespace().next().unwrap_or_default();
+            let bounds: Vec<_> = range
+                .split('-')
+                .map(|b| usize::from_str_radix(b, 16))
+                .collect();
+            if let [Ok(start), Ok(end)] = bounds[..] {
+                inside = start <= p && p < end;
+            }
+            if inside {
+                lines.push(line.to_string());
+            }
+        }
+        lines
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn test_advice_reaches_the_mapping() {
+        use std::fs;
+
+        let len = 2 * HUGE_PAGE_SIZE;
+        let mode = AllocationMode::HugePage { numa_local: true };
+        let refused = refused_advice();
+        let (p, advice) = allocate_advised(len as i64, mode).unwrap();
+        unsafe { ::std::ptr::write_bytes(p, 1, len) };
+        if !advice.huge_pages || !advice.numa_local {
+            assert!(refused_advice() > refused);
+        }
+
+        let smaps = fs::read_to_string("/proc/self/smaps").unwrap();
+        let mapping = smaps_of(&smaps, p);
+        assert!(!mapping.is_empty());
+        let value = |name: &str| {
+            mapping
+                .iter()
+                .find(|l| l.starts_with(name))
+                .map(|l| l[name.len()..].trim().to_string())
+        };
+        if advice.huge_pages {
+            // the advice is recorded as the mapping's `hg` flag
+            let flags = value("VmFlags:").unwrap();
+            assert!(flags.split_whitespace().any(|f| f == "hg"), "{}", flags);
+            let thp = fs::read_to_string("/sys/kernel/mm/transparent_hugepage/enabled")
+                .unwrap_or_default();
+            if !thp.contains("[never]") {
+                let huge = value("AnonHugePages:").unwrap();
+                assert!(huge != "0 kB", "no huge pages in {:?}", mapping);
+            }
+        }
+        if advice.numa_local {
+            // binding the range splits it into a mapping of its own
+            let numa_maps = fs::read_to_string("/proc/self/numa_maps").unwrap();
+            let start = format!("{:x} ", p as usize);
+            let policy = numa_maps.lines().find(|l| l.starts_with(&start)).unwrap();
+            assert!(policy[start.len()..].starts_with("prefer:"), "{}", policy);
+        }
+        unsafe { free_aligned(p) };
+    }
+
+    #[test]
+    fn test_small_huge_page_request_falls_back() {
+        let mode = AllocationMode::HugePage { numa_local: true };
+        let p = allocate_aligned_with(128, mode).unwrap();
+        assert!(is_aligned(p, ALIGNMENT));
+        unsafe { free_aligned(p) };
+    }
+
+    #[test]
+    fn test_huge_page_len() {
+        assert_eq!(HUGE_PAGE_SIZE, huge_page_len(1));
+        assert_eq!(HUGE_PAGE_SIZE, huge_page_len(HUGE_PAGE_SIZE));
+        assert_eq!(2 * HUGE_PAGE_SIZE, huge_page_len(HUGE_PAGE_SIZE + 1));
+    }
+}